@@ -27,7 +27,8 @@ impl FromStr for ShellKind {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SystemdKind {
     Timer,
     NSpawn,
@@ -62,7 +63,8 @@ impl FromStr for SystemdKind {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Kind {
     Systemd(SystemdKind),
     Other,
@@ -80,6 +82,9 @@ impl std::fmt::Display for Kind {
 impl FromStr for Kind {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
-        Ok(Self::Systemd(SystemdKind::from_str(s)?))
+        match s {
+            "other" => Ok(Self::Other),
+            _ => Ok(Self::Systemd(SystemdKind::from_str(s)?)),
+        }
     }
 }
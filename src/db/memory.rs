@@ -0,0 +1,188 @@
+use super::types::{Quota, Schedule, Secret, User};
+use super::{Store, Transaction};
+use crate::protocol_error;
+use anyhow::Result;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDB {
+    schedules: Vec<Schedule>,
+    placements: HashMap<i64, HashMap<String, String>>,
+    quotas: Vec<Quota>,
+    secrets: Vec<Secret>,
+}
+
+impl MemoryDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn schedules(&self) -> &[Schedule] {
+        &self.schedules
+    }
+
+    pub fn placements(&self, schedule: i64) -> HashMap<String, String> {
+        self.placements.get(&schedule).cloned().unwrap_or_default()
+    }
+
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quotas
+            .retain(|x| x.user().username() != quota.user().username());
+        self.quotas.push(quota);
+    }
+}
+
+pub struct MemoryTransaction<'a> {
+    db: &'a mut MemoryDB,
+    staged: MemoryDB,
+}
+
+impl Transaction for MemoryTransaction<'_> {
+    fn save_schedule(&mut self, schedule: &Schedule) -> Result<i64> {
+        let schedules = &mut self.staged.schedules;
+        let id = schedule
+            .id()
+            .unwrap_or_else(|| schedules.iter().filter_map(|x| x.id()).max().unwrap_or(0) + 1);
+        let schedule = schedule.clone().with_id(id);
+
+        match schedules.iter_mut().find(|x| x.id() == Some(id)) {
+            Some(x) => *x = schedule,
+            None => schedules.push(schedule),
+        }
+
+        Ok(id)
+    }
+
+    fn save_placements(
+        &mut self,
+        schedule: i64,
+        placements: &HashMap<String, String>,
+    ) -> Result<()> {
+        if !self
+            .staged
+            .schedules
+            .iter()
+            .any(|x| x.id() == Some(schedule))
+        {
+            return Err(protocol_error!(
+                NotFound,
+                "no schedule with id {}",
+                schedule
+            ));
+        }
+
+        self.staged.placements.insert(schedule, placements.clone());
+        Ok(())
+    }
+
+    // a user's secret replaces the one stored under the same name.
+    fn save_secret(&mut self, secret: &Secret) -> Result<()> {
+        self.staged.secrets.retain(|x| {
+            x.user().username() != secret.user().username() || x.name() != secret.name()
+        });
+        self.staged.secrets.push(secret.clone());
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        *self.db = self.staged;
+        Ok(())
+    }
+}
+
+impl Store for MemoryDB {
+    fn schedules(&self) -> Result<Vec<Schedule>> {
+        Ok(self.schedules.clone())
+    }
+
+    fn placements(&self, schedule: i64) -> Result<HashMap<String, String>> {
+        Ok(MemoryDB::placements(self, schedule))
+    }
+
+    fn quota(&self, user: &User) -> Result<Option<Quota>> {
+        Ok(self
+            .quotas
+            .iter()
+            .find(|x| x.user().username() == user.username())
+            .cloned())
+    }
+
+    fn secrets(&self) -> Result<Vec<Secret>> {
+        Ok(self.secrets.clone())
+    }
+
+    fn begin(&mut self) -> Result<Box<dyn Transaction + '_>> {
+        Ok(Box::new(MemoryTransaction {
+            staged: self.clone(),
+            db: self,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::types::User;
+    use crate::manifest::Manifest;
+    use crate::protocol::ErrorCode;
+    use std::path::Path;
+
+    #[test]
+    fn test_transaction() -> Result<()> {
+        let schedule = Schedule::new(
+            Manifest::from_file(Path::new("testdata/resources-one.yaml"))?,
+            User::new("erikh", ""),
+        );
+        let placements: HashMap<String, String> = [("foo".to_string(), "node1".to_string())].into();
+        let mut db = MemoryDB::new();
+
+        let mut tx = db.begin()?;
+        assert_eq!(tx.save_schedule(&schedule)?, 1);
+        tx.save_placements(1, &placements)?;
+        drop(tx);
+        assert!(db.schedules().is_empty());
+        assert!(db.placements(1).is_empty());
+
+        let mut tx = db.begin()?;
+        assert_eq!(tx.save_schedule(&schedule)?, 1);
+        tx.save_placements(1, &placements)?;
+        tx.commit()?;
+        assert_eq!(db.schedules().len(), 1);
+        assert_eq!(db.schedules()[0].id(), Some(1));
+        assert_eq!(db.placements(1), placements);
+
+        let mut tx = db.begin()?;
+        assert_eq!(tx.save_schedule(&schedule)?, 2);
+        assert_eq!(tx.save_schedule(&schedule.clone().with_id(1))?, 1);
+        assert_eq!(
+            ErrorCode::of(&tx.save_placements(3, &placements).unwrap_err()),
+            ErrorCode::NotFound
+        );
+        tx.commit()?;
+        assert_eq!(db.schedules().len(), 2);
+
+        let user = User::new("erikh", "");
+        assert!(db.quota(&user)?.is_none());
+        db.set_quota(Quota::new(user.clone(), 1, 2, 3));
+        db.set_quota(Quota::new(user.clone(), 4, 5, 6));
+        assert_eq!(db.quota(&user)?.unwrap().limits().workloads, 4);
+        assert!(db.quota(&User::new("other", ""))?.is_none());
+
+        let key = crate::secrets::Key::generate()?;
+        let mut tx = db.begin()?;
+        tx.save_secret(&Secret::new(user.clone(), "token", "one", &key)?)?;
+        tx.save_secret(&Secret::new(User::new("other", ""), "token", "two", &key)?)?;
+        tx.save_secret(&Secret::new(user.clone(), "token", "three", &key)?)?;
+        tx.commit()?;
+
+        let secrets = db.secrets()?;
+        assert_eq!(secrets.len(), 2);
+        assert_eq!(
+            key.open("token", &secrets[1].sealed())?,
+            "three",
+            "replaced by name"
+        );
+
+        Ok(())
+    }
+}
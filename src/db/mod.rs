@@ -1,8 +1,10 @@
+pub mod memory;
 pub mod sqlite;
 pub mod types;
 
 use anyhow::Result;
 use sqlx::{Encode, Sqlite, Type};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum QueryType {
@@ -35,3 +37,22 @@ where
     fn exists(&self, typ: QueryType) -> &'a str;
     fn count(&self, typ: QueryType) -> &'a str;
 }
+
+pub trait Transaction {
+    fn save_schedule(&mut self, schedule: &types::Schedule) -> Result<i64>;
+    fn save_placements(
+        &mut self,
+        schedule: i64,
+        placements: &HashMap<String, String>,
+    ) -> Result<()>;
+    fn save_secret(&mut self, secret: &types::Secret) -> Result<()>;
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+pub trait Store {
+    fn schedules(&self) -> Result<Vec<types::Schedule>>;
+    fn placements(&self, schedule: i64) -> Result<HashMap<String, String>>;
+    fn quota(&self, user: &types::User) -> Result<Option<types::Quota>>;
+    fn secrets(&self) -> Result<Vec<types::Secret>>;
+    fn begin(&mut self) -> Result<Box<dyn Transaction + '_>>;
+}
@@ -1,6 +1,8 @@
 use super::*;
+use crate::protocol::{Limit, NodeSummary, Payload, QuotaReport, Resources, RevisionSummary};
 use anyhow::anyhow;
 use sqlx::any::AnyValue;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct Node {
@@ -11,6 +13,7 @@ pub struct Node {
     username: String,
     federating: bool,
     alive: bool,
+    labels: String,
 }
 
 impl<'a, T, DB> QueryGenerator<'a, T, DB> for Node
@@ -30,6 +33,7 @@ where
             "username".to_string(),
             "federating".to_string(),
             "alive".to_string(),
+            "labels".to_string(),
         ]
     }
 
@@ -41,6 +45,7 @@ where
             "username" => Ok(self.username),
             "federating" => Ok(self.federating),
             "alive" => Ok(self.alive),
+            "labels" => Ok(self.labels),
             _ => Err(anyhow!("invalid column '{}'", column)),
         }
     }
@@ -50,7 +55,7 @@ where
     }
 
     fn create(&self, _typ: QueryType) -> &'a str {
-        "insert into nodes (name, key, address, username, federating, alive) values (?, ?, ?, ?, ?, ?) returning id"
+        "insert into nodes (name, key, address, username, federating, alive, labels) values (?, ?, ?, ?, ?, ?, ?) returning id"
     }
 
     fn delete(&self, _typ: QueryType) -> &'a str {
@@ -58,7 +63,7 @@ where
     }
 
     fn update(&self, _typ: QueryType) -> &'a str {
-        "update nodes set name=?, key=?, address=?, username=?, federating=?, alive=?) where id=?"
+        "update nodes set name=?, key=?, address=?, username=?, federating=?, alive=?, labels=? where id=?"
     }
 
     fn exists(&self, _typ: QueryType) -> &'a str {
@@ -169,6 +174,7 @@ pub struct Schedule {
     manifest: crate::manifest::Manifest,
     count: u64,
     user: User,
+    revisions: Vec<Revision>,
 }
 
 impl<'a, T, DB> QueryGenerator<'a, T, DB> for Schedule
@@ -211,6 +217,55 @@ where
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Revision {
+    id: Option<i64>,
+    revision: u64,
+    manifest: crate::manifest::Manifest,
+    author: User,
+    created: chrono::DateTime<chrono::Local>,
+}
+
+impl<'a, T, DB> QueryGenerator<'a, T, DB> for Revision
+where
+    DB: sqlx::Database,
+    T: Type<DB> + Encode<'a, DB> + Send,
+{
+    fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    fn bind_columns(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn value(&self, column: &str) -> Result<T> {
+        match column {
+            _ => Err(anyhow!("invalid column '{}'", column)),
+        }
+    }
+
+    fn count(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+
+    fn create(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+
+    fn delete(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+
+    fn update(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+
+    fn exists(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct User {
     id: Option<i64>,
@@ -355,3 +410,804 @@ where
         Default::default()
     }
 }
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Usage {
+    pub workloads: u64,
+    pub cpu: u64,
+    pub mem: u64,
+}
+
+impl std::ops::Add for Usage {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            workloads: self.workloads + rhs.workloads,
+            cpu: self.cpu + rhs.cpu,
+            mem: self.mem + rhs.mem,
+        }
+    }
+}
+
+impl std::iter::Sum for Usage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |acc, x| acc + x)
+    }
+}
+
+impl Schedule {
+    pub fn new(manifest: crate::manifest::Manifest, user: User) -> Self {
+        let mut schedule = Self {
+            id: None,
+            manifest: manifest.clone(),
+            count: 0,
+            user: user.clone(),
+            revisions: Vec::new(),
+        };
+
+        schedule.revise(manifest, user);
+        schedule
+    }
+
+    pub fn with_id(mut self, id: i64) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    pub fn manifest(&self) -> &crate::manifest::Manifest {
+        &self.manifest
+    }
+
+    pub fn current_revision(&self) -> u64 {
+        self.revisions.last().map_or(0, |x| x.revision)
+    }
+
+    pub fn revise(&mut self, manifest: crate::manifest::Manifest, author: User) -> u64 {
+        let revision = self.current_revision() + 1;
+
+        self.count = manifest
+            .commands()
+            .iter()
+            .filter(|x| x.command() == "schedule")
+            .map(|x| x.replicas())
+            .sum();
+        self.manifest = manifest.clone();
+        self.revisions.push(Revision {
+            id: None,
+            revision,
+            manifest,
+            author,
+            created: chrono::Local::now(),
+        });
+
+        revision
+    }
+
+    pub fn revisions(&self) -> &[Revision] {
+        &self.revisions
+    }
+
+    pub fn revision(&self, revision: u64) -> Result<&Revision> {
+        self.revisions
+            .iter()
+            .find(|x| x.revision == revision)
+            .ok_or_else(|| crate::protocol_error!(NotFound, "no revision {} in schedule", revision))
+    }
+
+    pub fn history(&self) -> Payload {
+        let current = self.revisions.last().map(|x| x.revision);
+
+        Payload::History(
+            self.revisions
+                .iter()
+                .map(|x| RevisionSummary {
+                    revision: x.revision,
+                    author: x.author.username.clone(),
+                    timestamp: x.created.naive_local(),
+                    current: Some(x.revision) == current,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn secrets<'a>(&'a self, secrets: &'a [Secret]) -> OwnedSecrets<'a> {
+        OwnedSecrets {
+            user: &self.user,
+            secrets,
+        }
+    }
+
+    pub fn usage(&self) -> Result<Usage> {
+        let (cpu, mem) = self.manifest.requested_resources()?;
+
+        Ok(Usage {
+            workloads: self.count,
+            cpu,
+            mem,
+        })
+    }
+}
+
+impl Revision {
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn manifest(&self) -> &crate::manifest::Manifest {
+        &self.manifest
+    }
+
+    pub fn author(&self) -> &User {
+        &self.author
+    }
+
+    pub fn created(&self) -> chrono::DateTime<chrono::Local> {
+        self.created
+    }
+}
+
+impl User {
+    pub fn new(username: &str, key: &str) -> Self {
+        Self {
+            id: None,
+            username: username.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+impl Plan {
+    pub fn new(node: Node, schedule: Schedule) -> Self {
+        Self {
+            id: None,
+            node: node.clone(),
+            failures: 0,
+            scheduled: true,
+            last_deployed: chrono::Local::now(),
+            plan_node: PlanNode {
+                id: None,
+                node,
+                schedule,
+            },
+        }
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn scheduled(&self) -> bool {
+        self.scheduled
+    }
+
+    pub fn record(
+        &mut self,
+        policy: &crate::manifest::RestartPolicy,
+        failed: bool,
+    ) -> crate::manifest::Decision {
+        if failed {
+            self.failures += 1;
+        }
+
+        let decision = policy.decide(failed, self.failures);
+
+        if decision == crate::manifest::Decision::Reschedule {
+            self.failures = 0;
+            self.scheduled = false;
+        }
+
+        decision
+    }
+
+    pub fn recovered(&mut self) {
+        self.failures = 0;
+    }
+
+    // whether the backoff since the workload was last deployed has passed.
+    pub fn due(&self, backoff: std::time::Duration) -> bool {
+        (chrono::Local::now() - self.last_deployed)
+            .to_std()
+            .is_ok_and(|x| x >= backoff)
+    }
+
+    pub fn deployed(&mut self) {
+        self.last_deployed = chrono::Local::now();
+    }
+}
+
+impl Node {
+    pub fn new(
+        name: &str,
+        address: &str,
+        username: &str,
+        labels: &HashMap<String, String>,
+    ) -> Self {
+        Self {
+            id: None,
+            name: name.to_string(),
+            key: String::new(),
+            address: address.to_string(),
+            username: username.to_string(),
+            federating: false,
+            alive: true,
+            labels: crate::protocol::format_tags(labels),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn alive(&self) -> bool {
+        self.alive
+    }
+
+    pub fn set_alive(&mut self, alive: bool) {
+        self.alive = alive;
+    }
+
+    pub fn labels(&self) -> HashMap<String, String> {
+        crate::protocol::parse_tags(&self.labels).unwrap_or_default()
+    }
+}
+
+impl Status {
+    pub fn new(node: Node, cpu: u64, mem: u64, storage: u64) -> Self {
+        Self {
+            id: None,
+            node,
+            cpu,
+            mem,
+            storage,
+            last_queried: chrono::Local::now(),
+        }
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn node_mut(&mut self) -> &mut Node {
+        &mut self.node
+    }
+
+    pub fn summary(&self) -> NodeSummary {
+        NodeSummary {
+            name: self.node.name.clone(),
+            address: self.node.address.clone(),
+            alive: self.node.alive,
+            resources: Resources {
+                cpu: self.cpu,
+                mem: self.mem,
+                storage: self.storage,
+            },
+            last_queried: Some(self.last_queried.naive_local()),
+            labels: self.node.labels(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Quota {
+    id: Option<i64>,
+    user: User,
+    workloads: u64,
+    cpu: u64,
+    mem: u64,
+}
+
+impl Quota {
+    pub fn new(user: User, workloads: u64, cpu: u64, mem: u64) -> Self {
+        Self {
+            id: None,
+            user,
+            workloads,
+            cpu,
+            mem,
+        }
+    }
+
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn limits(&self) -> Usage {
+        Usage {
+            workloads: self.workloads,
+            cpu: self.cpu,
+            mem: self.mem,
+        }
+    }
+
+    pub fn check(&self, current: &Usage, requested: &Usage) -> Result<()> {
+        let total = current.clone() + requested.clone();
+
+        for (resource, total, limit) in [
+            ("workloads", total.workloads, self.workloads),
+            ("cpu", total.cpu, self.cpu),
+            ("memory", total.mem, self.mem),
+        ] {
+            if total > limit {
+                return Err(crate::protocol_error!(
+                    QuotaExceeded,
+                    "quota exceeded for user '{}': {} would be {}, limit is {}",
+                    self.user.username,
+                    resource,
+                    total,
+                    limit
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn usage(&self, schedules: &[Schedule]) -> Result<Usage> {
+        Ok(schedules
+            .iter()
+            .filter(|s| s.user.username == self.user.username)
+            .map(|s| s.usage())
+            .collect::<Result<Vec<Usage>>>()?
+            .into_iter()
+            .sum())
+    }
+
+    pub fn check_schedules(&self, schedules: &[Schedule], requested: &Usage) -> Result<()> {
+        self.check(&self.usage(schedules)?, requested)
+    }
+
+    pub fn report(&self, current: &Usage) -> Payload {
+        Payload::Quota(QuotaReport {
+            user: self.user.username.clone(),
+            workloads: Limit {
+                used: current.workloads,
+                limit: self.workloads,
+            },
+            cpu: Limit {
+                used: current.cpu,
+                limit: self.cpu,
+            },
+            mem: Limit {
+                used: current.mem,
+                limit: self.mem,
+            },
+        })
+    }
+}
+
+impl<'a, T, DB> QueryGenerator<'a, T, DB> for Quota
+where
+    DB: sqlx::Database,
+    T: Type<DB> + Encode<'a, DB> + Send,
+{
+    fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    fn bind_columns(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn value(&self, column: &str) -> Result<T> {
+        match column {
+            _ => Err(anyhow!("invalid column '{}'", column)),
+        }
+    }
+
+    fn count(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+
+    fn create(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+
+    fn delete(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+
+    fn update(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+
+    fn exists(&self, _typ: QueryType) -> &'a str {
+        Default::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Secret {
+    id: Option<i64>,
+    user: User,
+    name: String,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    created: chrono::DateTime<chrono::Local>,
+}
+
+impl Secret {
+    pub fn new(user: User, name: &str, value: &str, key: &crate::secrets::Key) -> Result<Self> {
+        if !crate::secrets::valid(name) {
+            return Err(crate::protocol_error!(
+                InvalidArgument,
+                "invalid secret name '{}'",
+                name
+            ));
+        }
+
+        let sealed = key.seal(name, value)?;
+
+        Ok(Self {
+            id: None,
+            user,
+            name: name.to_string(),
+            nonce: sealed.nonce,
+            ciphertext: sealed.ciphertext,
+            created: chrono::Local::now(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn created(&self) -> chrono::DateTime<chrono::Local> {
+        self.created
+    }
+
+    pub fn sealed(&self) -> crate::secrets::Sealed {
+        crate::secrets::Sealed {
+            nonce: self.nonce.clone(),
+            ciphertext: self.ciphertext.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OwnedSecrets<'a> {
+    user: &'a User,
+    secrets: &'a [Secret],
+}
+
+impl crate::secrets::Store for OwnedSecrets<'_> {
+    fn sealed(&self, name: &str) -> Option<crate::secrets::Sealed> {
+        self.secrets
+            .iter()
+            .find(|x| x.user.username == self.user.username && x.name == name)
+            .map(|x| x.sealed())
+    }
+}
+
+impl<'a, T, DB> QueryGenerator<'a, T, DB> for Secret
+where
+    DB: sqlx::Database,
+    T: Type<DB> + Encode<'a, DB> + Send,
+{
+    fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    fn bind_columns(&self) -> Vec<String> {
+        vec![
+            "user".to_string(),
+            "name".to_string(),
+            "nonce".to_string(),
+            "ciphertext".to_string(),
+        ]
+    }
+
+    fn value(&self, column: &str) -> Result<T> {
+        match column {
+            "user" => Ok(self.user.username),
+            "name" => Ok(self.name),
+            "nonce" => Ok(self.nonce),
+            "ciphertext" => Ok(self.ciphertext),
+            _ => Err(anyhow!("invalid column '{}'", column)),
+        }
+    }
+
+    fn count(&self, _typ: QueryType) -> &'a str {
+        "select count(*) from secrets"
+    }
+
+    fn create(&self, _typ: QueryType) -> &'a str {
+        "insert into secrets (user, name, nonce, ciphertext) values (?, ?, ?, ?) returning id"
+    }
+
+    fn delete(&self, _typ: QueryType) -> &'a str {
+        "delete from secrets where user=? and name=?"
+    }
+
+    fn update(&self, _typ: QueryType) -> &'a str {
+        "update secrets set nonce=?, ciphertext=? where user=? and name=?"
+    }
+
+    fn exists(&self, _typ: QueryType) -> &'a str {
+        "select 1 from secrets where user=? and name=?"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn make_schedule(username: &str, count: u64) -> Result<Schedule> {
+        Ok(Schedule {
+            id: None,
+            manifest: crate::manifest::Manifest::from_file(&std::path::PathBuf::from_str(
+                "testdata/resources-one.yaml",
+            )?)?,
+            count,
+            user: make_user(username),
+            revisions: Vec::new(),
+        })
+    }
+
+    fn make_user(username: &str) -> User {
+        User {
+            id: None,
+            username: username.to_string(),
+            key: String::new(),
+        }
+    }
+
+    fn make_plan() -> Result<Plan> {
+        let node = Node {
+            id: None,
+            name: "node1".to_string(),
+            key: String::new(),
+            address: "10.0.0.1".to_string(),
+            username: "erikh".to_string(),
+            federating: false,
+            alive: true,
+            labels: String::new(),
+        };
+
+        Ok(Plan::new(node, make_schedule("erikh", 1)?))
+    }
+
+    #[test]
+    fn test_plan_failures() -> Result<()> {
+        use crate::manifest::{Decision, Restart, RestartPolicy};
+        use std::time::Duration;
+
+        let policy = RestartPolicy {
+            restart: Restart::OnFailure,
+            backoff: 1,
+            threshold: 3,
+        };
+
+        let mut plan = make_plan()?;
+        assert_eq!(plan.record(&policy, false), Decision::Ignore);
+        assert_eq!(plan.failures(), 0);
+        assert_eq!(
+            plan.record(&policy, true),
+            Decision::Restart(Duration::from_secs(1))
+        );
+        assert_eq!(
+            plan.record(&policy, true),
+            Decision::Restart(Duration::from_secs(2))
+        );
+        assert_eq!(plan.failures(), 2);
+        plan.recovered();
+        assert_eq!(plan.failures(), 0);
+
+        for _ in 0..2 {
+            plan.record(&policy, true);
+        }
+
+        assert!(plan.scheduled());
+        assert_eq!(plan.record(&policy, true), Decision::Reschedule);
+        assert_eq!(plan.failures(), 0);
+        assert!(!plan.scheduled());
+
+        assert!(plan.due(Duration::ZERO));
+        assert!(!plan.due(Duration::from_secs(60)));
+        plan.deployed();
+        assert_eq!(plan.node().name(), "node1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_summary() -> Result<()> {
+        let labels: HashMap<String, String> = [
+            ("datacenter".to_string(), "xo".to_string()),
+            ("rack".to_string(), "a,b=c".to_string()),
+        ]
+        .into();
+
+        let summary =
+            Status::new(Node::new("node1", "10.0.0.1", "erikh", &labels), 8, 4096, 0).summary();
+        assert_eq!(summary.name, "node1");
+        assert_eq!(summary.labels, labels);
+        assert!(crate::manifest::Manifest::from_file(std::path::Path::new(
+            "testdata/resources-one.yaml"
+        ))?
+        .location()
+        .matches(&summary.labels));
+
+        let summary = Status::new(
+            Node::new("node2", "10.0.0.2", "erikh", &Default::default()),
+            8,
+            4096,
+            0,
+        )
+        .summary();
+        assert!(summary.labels.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota() -> Result<()> {
+        let quota = Quota::new(make_user("erikh"), 4, 12, 8192);
+        let schedules = vec![make_schedule("erikh", 2)?, make_schedule("other", 10)?];
+
+        let one = Usage {
+            workloads: 1,
+            cpu: 2,
+            mem: 512,
+        };
+
+        assert!(quota.check_schedules(&schedules, &one).is_ok());
+        assert!(quota.check(&one, &one).is_ok());
+
+        let table = vec![
+            (
+                Usage {
+                    workloads: 3,
+                    ..Default::default()
+                },
+                "workloads",
+            ),
+            (
+                Usage {
+                    cpu: 7,
+                    ..Default::default()
+                },
+                "cpu",
+            ),
+            (
+                Usage {
+                    mem: 8192,
+                    ..Default::default()
+                },
+                "memory",
+            ),
+        ];
+
+        for (requested, resource) in table {
+            let err = quota.check_schedules(&schedules, &requested).unwrap_err();
+            assert_eq!(
+                crate::protocol::ErrorCode::of(&err),
+                crate::protocol::ErrorCode::QuotaExceeded
+            );
+            assert!(err.to_string().contains(resource), "{}", err);
+            assert!(err.to_string().contains("erikh"), "{}", err);
+        }
+
+        match quota.report(&make_schedule("erikh", 2)?.usage()?) {
+            Payload::Quota(report) => {
+                assert_eq!(report.user, "erikh");
+                assert_eq!(report.workloads, Limit { used: 2, limit: 4 });
+                assert_eq!(report.cpu, Limit { used: 6, limit: 12 });
+                assert_eq!(
+                    report.mem,
+                    Limit {
+                        used: 2560,
+                        limit: 8192
+                    }
+                );
+            }
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_revisions() -> Result<()> {
+        let one = crate::manifest::Manifest::from_file(std::path::Path::new(
+            "testdata/resources-one.yaml",
+        ))?;
+        let two = crate::manifest::Manifest::from_file(std::path::Path::new(
+            "testdata/resources-two.yaml",
+        ))?;
+
+        let mut schedule = Schedule::new(one.clone(), make_user("erikh"));
+        assert_eq!(schedule.revisions().len(), 1);
+        assert_eq!(schedule.revise(two.clone(), make_user("other")), 2);
+        assert_eq!(schedule.manifest(), &two);
+        assert_eq!(schedule.revision(1)?.manifest(), &one);
+        assert_eq!(schedule.revision(2)?.author().username(), "other");
+        assert_eq!(schedule.user().username(), "erikh");
+        assert_eq!(
+            crate::protocol::ErrorCode::of(&schedule.revision(3).unwrap_err()),
+            crate::protocol::ErrorCode::NotFound
+        );
+
+        match schedule.history() {
+            Payload::History(history) => {
+                assert_eq!(
+                    history
+                        .iter()
+                        .map(|x| (x.revision, x.author.as_str(), x.current))
+                        .collect::<Vec<(u64, &str, bool)>>(),
+                    vec![(1, "erikh", false), (2, "other", true)]
+                );
+            }
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_secrets() -> Result<()> {
+        use crate::secrets::{Key, Store};
+
+        let key = Key::generate()?;
+        let secrets = [
+            Secret::new(make_user("erikh"), "db-password", "hunter2", &key)?,
+            Secret::new(make_user("erikh"), "api-token", "abc123", &key)?,
+            Secret::new(make_user("other"), "private-image", "example/app", &key)?,
+            Secret::new(make_user("other"), "health-url", "http://example", &key)?,
+        ];
+        let manifest = crate::manifest::Manifest::from_file(std::path::Path::new(
+            "testdata/secrets-one.yaml",
+        ))?;
+        let schedule = Schedule::new(manifest.clone(), make_user("erikh"));
+        let owned = schedule.secrets(&secrets);
+
+        assert_eq!(secrets[0].name(), "db-password");
+        assert_eq!(secrets[0].user().username(), "erikh");
+        assert!(!format!("{:?}", secrets[0]).contains("hunter2"));
+        assert_eq!(
+            key.open("api-token", &owned.sealed("api-token").unwrap())?,
+            "abc123"
+        );
+        assert!(owned.sealed("missing").is_none());
+        assert!(owned.sealed("private-image").is_none());
+        assert!(Schedule::new(manifest.clone(), make_user("other"))
+            .secrets(&secrets)
+            .sealed("private-image")
+            .is_some());
+
+        let err = crate::secrets::resolve(&manifest.commands()[0], &owned, &key).unwrap_err();
+        assert_eq!(
+            crate::protocol::ErrorCode::of(&err),
+            crate::protocol::ErrorCode::NotFound
+        );
+        assert_eq!(
+            crate::protocol::ErrorCode::of(
+                &Secret::new(make_user("erikh"), "bad name", "x", &key).unwrap_err()
+            ),
+            crate::protocol::ErrorCode::InvalidArgument
+        );
+
+        Ok(())
+    }
+}
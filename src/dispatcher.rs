@@ -0,0 +1,1620 @@
+use crate::common::Kind;
+use crate::db::memory::MemoryDB;
+use crate::db::types::{Plan, Schedule, Secret, Status, User};
+use crate::db::Store;
+use crate::executor::journal::{self, Journalctl, LogQuery};
+use crate::executor::{self, exec, health, Action, Runner};
+use crate::manifest::{Manifest, SchedulingCommand};
+use crate::planner::{
+    self,
+    rollout::{self, Rollout},
+    Applied,
+};
+use crate::protocol::{
+    event::{self, EventKind, EventLog},
+    Command, ErrorCode, Health, Instruction, NodeSummary, Payload, Response, ScheduleReceipt,
+    Selector, WorkloadState, WorkloadStatus,
+};
+use crate::protocol_error;
+use crate::secrets::{self, Key, Redactor, Store as _};
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+
+pub struct Dispatcher {
+    user: User,
+    db: MemoryDB,
+    nodes: Vec<Status>,
+    agent: Box<dyn NodeAgent + Send>,
+    events: Arc<Mutex<EventLog>>,
+    key: Key,
+    workloads: BTreeMap<String, Workload>,
+}
+
+// an instance placed by this server, with the plan that tracks its failures.
+#[derive(Clone)]
+struct Workload {
+    schedule: i64,
+    command: String,
+    plan: Plan,
+    state: WorkloadState,
+    health: Option<Health>,
+    tags: HashMap<String, String>,
+}
+
+// the server's handle on the agents of its nodes: they roll instances out and run
+// invocations for the workloads placed on them.
+pub trait NodeAgent: Rollout + Runner {}
+
+impl<T: Rollout + Runner> NodeAgent for T {}
+
+// records what the agent did to each instance so it can be published once the
+// planner is done with the rollout.
+struct Published<'a> {
+    rollout: &'a mut dyn Rollout,
+    events: Vec<(EventKind, String)>,
+}
+
+impl Rollout for Published<'_> {
+    fn start(&mut self, instance: &str) -> Result<()> {
+        self.rollout.start(instance)
+    }
+
+    fn stop(&mut self, instance: &str) -> Result<()> {
+        self.rollout.stop(instance)?;
+        self.events
+            .push((EventKind::Terminated, instance.to_string()));
+        Ok(())
+    }
+
+    fn healthy(&mut self, instance: &str) -> Result<bool> {
+        let healthy = self.rollout.healthy(instance)?;
+        let kind = if healthy {
+            EventKind::Started
+        } else {
+            EventKind::Failed
+        };
+
+        self.events.push((kind, instance.to_string()));
+        Ok(healthy)
+    }
+}
+
+fn selector(instruction: &Instruction) -> Result<Selector> {
+    instruction.selector().unwrap_or_else(|| {
+        Err(protocol_error!(
+            InvalidCommand,
+            "{} does not select workloads",
+            instruction.command.name()
+        ))
+    })
+}
+
+impl Dispatcher {
+    pub fn new(
+        user: User,
+        db: MemoryDB,
+        nodes: Vec<Status>,
+        agent: Box<dyn NodeAgent + Send>,
+        events: Arc<Mutex<EventLog>>,
+        key: Key,
+    ) -> Self {
+        Self {
+            user,
+            db,
+            nodes,
+            agent,
+            events,
+            key,
+            workloads: BTreeMap::new(),
+        }
+    }
+
+    pub fn db(&self) -> &MemoryDB {
+        &self.db
+    }
+
+    // marks a node up or down, publishing the transition to watchers.
+    pub fn set_alive(&mut self, node: &str, alive: bool) -> Result<()> {
+        let status = self
+            .nodes
+            .iter_mut()
+            .find(|x| x.node().name() == node)
+            .ok_or_else(|| protocol_error!(NotFound, "node '{}' does not exist", node))?;
+
+        if status.node().alive() != alive {
+            status.node_mut().set_alive(alive);
+            self.log()?
+                .publish(EventKind::node(alive), node, None, HashMap::new());
+        }
+
+        Ok(())
+    }
+
+    fn log(&self) -> Result<std::sync::MutexGuard<'_, EventLog>> {
+        self.events
+            .lock()
+            .map_err(|_| anyhow!("event log is poisoned"))
+    }
+
+    fn summaries(&self) -> Vec<NodeSummary> {
+        self.nodes.iter().map(|x| x.summary()).collect()
+    }
+
+    fn placements(&self) -> HashMap<String, String> {
+        self.db
+            .schedules()
+            .iter()
+            .filter_map(|x| x.id())
+            .flat_map(|x| self.db.placements(x))
+            .collect()
+    }
+
+    // runs a planner operation, then publishes where its instances were scheduled
+    // and what the agent started, failed or terminated along the way.
+    fn publish(
+        &mut self,
+        tags: &HashMap<String, String>,
+        run: impl FnOnce(&mut MemoryDB, &mut dyn Rollout, &[NodeSummary]) -> Result<Applied>,
+    ) -> Result<Applied> {
+        let nodes = self.summaries();
+        let previous = self.placements();
+        let mut published = Published {
+            rollout: self.agent.as_mut(),
+            events: Vec::new(),
+        };
+
+        let applied = run(&mut self.db, &mut published, &nodes);
+        let steps = published.events;
+        let placed = match &applied {
+            Ok(applied) if applied.schedule.is_some() => applied
+                .results
+                .iter()
+                .filter_map(|x| Some((x.name.clone(), x.node.clone()?)))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let mut log = self.log()?;
+
+        for (name, node) in &placed {
+            if previous.get(name) != Some(node) {
+                log.publish(EventKind::Scheduled, name, Some(node.clone()), tags.clone());
+            }
+        }
+
+        for (kind, instance) in steps {
+            let name = rollout::workload(&instance);
+            let node = placed
+                .iter()
+                .find(|(x, _)| x == name)
+                .map(|(_, x)| x)
+                .or_else(|| previous.get(name))
+                .cloned();
+            log.publish(kind, name, node, tags.clone());
+        }
+
+        drop(log);
+
+        if let Ok(Applied {
+            schedule: Some(schedule),
+            ..
+        }) = &applied
+        {
+            self.track(schedule, &placed, tags);
+        }
+
+        applied
+    }
+
+    // keeps a plan for every placed instance of the schedule, starting a new one
+    // when an instance lands on a different node.
+    fn track(
+        &mut self,
+        schedule: &Schedule,
+        placed: &[(String, String)],
+        tags: &HashMap<String, String>,
+    ) {
+        let Some(id) = schedule.id() else {
+            return;
+        };
+
+        self.workloads
+            .retain(|name, x| x.schedule != id || placed.iter().any(|(x, _)| x == name));
+
+        for (command, instance) in schedule.manifest().instances() {
+            let Some((_, node)) = placed.iter().find(|(x, _)| *x == instance) else {
+                continue;
+            };
+
+            if command.command() != "schedule"
+                || self
+                    .workloads
+                    .get(&instance)
+                    .is_some_and(|x| x.plan.node().name() == node)
+            {
+                continue;
+            }
+
+            if let Some(status) = self.nodes.iter().find(|x| x.node().name() == node) {
+                let workload = Workload {
+                    schedule: id,
+                    command: command.name().to_string(),
+                    plan: Plan::new(status.node().clone(), schedule.clone()),
+                    state: WorkloadState::Running,
+                    health: None,
+                    tags: tags.clone(),
+                };
+
+                self.workloads.insert(instance, workload);
+            }
+        }
+    }
+
+    // stops an instance where it ran and starts it where it is placed now,
+    // publishing what the agent did.
+    fn restart(
+        &mut self,
+        instance: &str,
+        revision: u64,
+        from: &Workload,
+        to: &Workload,
+    ) -> Result<bool> {
+        let qualified = rollout::instance(instance, revision);
+        let mut published = Published {
+            rollout: self.agent.as_mut(),
+            events: Vec::new(),
+        };
+
+        published.stop(&qualified)?;
+        published.start(&qualified)?;
+        let healthy = published.healthy(&qualified)?;
+        let steps = published.events;
+
+        let mut log = self.log()?;
+        for (kind, _) in steps {
+            let workload = if kind == EventKind::Terminated {
+                from
+            } else {
+                to
+            };
+            log.publish(
+                kind,
+                instance,
+                Some(workload.plan.node().name().to_string()),
+                workload.tags.clone(),
+            );
+        }
+
+        Ok(healthy)
+    }
+
+    // the schedule and command a tracked instance was placed from.
+    fn source(&self, workload: &Workload) -> Result<(Schedule, SchedulingCommand)> {
+        let schedule = self
+            .db
+            .schedules()
+            .iter()
+            .find(|x| x.id() == Some(workload.schedule))
+            .cloned()
+            .ok_or_else(|| {
+                protocol_error!(NotFound, "no schedule with id {}", workload.schedule)
+            })?;
+        let command = schedule
+            .manifest()
+            .command(&workload.command)
+            .cloned()
+            .ok_or_else(|| {
+                protocol_error!(
+                    NotFound,
+                    "'{}' is not part of the schedule",
+                    workload.command
+                )
+            })?;
+
+        Ok((schedule, command))
+    }
+
+    fn describe(&self, instance: &str, workload: &Workload) -> Result<WorkloadStatus> {
+        let (_, command) = self.source(workload)?;
+
+        Ok(WorkloadStatus {
+            name: instance.to_string(),
+            kind: command.kind()?,
+            image: command.args().get("image").cloned().unwrap_or_default(),
+            state: workload.state,
+            node: Some(workload.plan.node().name().to_string()),
+            tags: workload.tags.clone(),
+            health: workload.health.clone(),
+        })
+    }
+
+    // a tracked instance as its node runs it, under the revision it was started from.
+    fn running(&self, name: &str) -> Result<WorkloadStatus> {
+        let workload = self
+            .workloads
+            .get(name)
+            .ok_or_else(|| protocol_error!(NotFound, "no workload named '{}'", name))?;
+        let (schedule, _) = self.source(workload)?;
+        let mut status = self.describe(name, workload)?;
+        status.name = rollout::instance(name, schedule.current_revision());
+
+        Ok(status)
+    }
+
+    // the tracked instances the selector picks, by name.
+    fn select(&self, selector: &Selector) -> Result<Vec<String>> {
+        let mut selected = Vec::new();
+
+        for (instance, workload) in &self.workloads {
+            if selector.matches(&self.describe(instance, workload)?) {
+                selected.push(instance.clone());
+            }
+        }
+
+        Ok(selected)
+    }
+
+    // what the agent on an instance's node needs to check it. secrets go out
+    // sealed, to be opened on the node.
+    pub fn assignment(&self, name: &str) -> Result<health::Assignment> {
+        let workload = self
+            .workloads
+            .get(name)
+            .ok_or_else(|| protocol_error!(NotFound, "no workload named '{}'", name))?;
+        let (schedule, command) = self.source(workload)?;
+        let stored = self.db.secrets()?;
+        let store = schedule.secrets(&stored);
+        let secrets = secrets::references(&command)
+            .into_iter()
+            .filter_map(|x| Some((x.to_string(), store.sealed(x)?)))
+            .collect();
+
+        Ok(health::Assignment {
+            workload: self.running(name)?,
+            command,
+            secrets,
+        })
+    }
+
+    // records what the agent on an instance's node reported about it. the agent
+    // restarts the instance in place itself; once it gives up, the instance is
+    // placed on another node here.
+    pub fn record(&mut self, report: health::Report) -> Result<()> {
+        let instance = rollout::workload(&report.instance).to_string();
+        let mut workload = self
+            .workloads
+            .get(&instance)
+            .cloned()
+            .ok_or_else(|| protocol_error!(NotFound, "no workload named '{}'", instance))?;
+
+        if report.state == WorkloadState::Failed && workload.state != WorkloadState::Failed {
+            self.log()?.publish(
+                EventKind::Failed,
+                &instance,
+                Some(workload.plan.node().name().to_string()),
+                workload.tags.clone(),
+            );
+        }
+
+        workload.state = report.state;
+        workload.health = Some(report.health);
+
+        if !report.reschedule {
+            self.workloads.insert(instance, workload);
+            return Ok(());
+        }
+
+        let (schedule, _) = self.source(&workload)?;
+        let placements = self.db.placements(workload.schedule);
+        let nodes = self.summaries();
+        let result = planner::reschedule(&mut self.db, &schedule, &placements, &instance, &nodes)?;
+
+        let Some(node) = result.node else {
+            workload.state = WorkloadState::Failed;
+            self.workloads.insert(instance, workload);
+            return Ok(());
+        };
+
+        let placed = self
+            .db
+            .placements(workload.schedule)
+            .into_iter()
+            .collect::<Vec<(String, String)>>();
+
+        self.log()?.publish(
+            EventKind::Scheduled,
+            &instance,
+            Some(node),
+            workload.tags.clone(),
+        );
+        self.track(&schedule, &placed, &workload.tags);
+
+        let moved = self.workloads[&instance].clone();
+        if !self.restart(&instance, schedule.current_revision(), &workload, &moved)? {
+            if let Some(moved) = self.workloads.get_mut(&instance) {
+                moved.state = WorkloadState::Failed;
+            }
+        }
+
+        Ok(())
+    }
+
+    // reports the state and health last recorded for each selected instance.
+    fn status(&self, selector: &Selector) -> Result<Response> {
+        let mut workloads = Vec::new();
+
+        for instance in self.select(selector)? {
+            workloads.push(self.describe(&instance, &self.workloads[&instance])?);
+        }
+
+        Ok(Response::ok(Payload::Workloads(workloads)))
+    }
+
+    // starts, stops or restarts an instance on its node.
+    fn control(&mut self, action: Action, name: &str) -> Result<Response> {
+        let running = self.running(name)?;
+        let invocation = executor::invocation(action, &running)?;
+        let result = self.agent.run(&invocation.command_line(), &mut |_| true)?;
+
+        match result.code {
+            Some(0) => {}
+            Some(code) => {
+                return Err(protocol_error!(
+                    Execution,
+                    "{} of '{}' exited with status {}",
+                    action,
+                    name,
+                    code
+                ))
+            }
+            None => {
+                return Err(protocol_error!(
+                    Execution,
+                    "{} of '{}' was killed",
+                    action,
+                    name
+                ))
+            }
+        }
+
+        let state = match action {
+            Action::Stop => WorkloadState::Stopped,
+            _ => WorkloadState::Running,
+        };
+
+        let workload = self.workloads.get_mut(name).unwrap();
+        workload.state = state;
+        let workload = workload.clone();
+
+        Ok(Response::ok(Payload::Workloads(vec![
+            self.describe(name, &workload)?
+        ])))
+    }
+
+    // runs a command inside an instance on its node, streaming its output.
+    fn exec(
+        &mut self,
+        name: &str,
+        cmd: &str,
+        send: &mut dyn FnMut(Response) -> bool,
+    ) -> Result<Response> {
+        let running = self.running(name)?;
+        let main_pid = exec::main_pid(&running, self.agent.as_mut())?;
+        let invocation = exec::invocation(&running, cmd, main_pid)?;
+
+        exec::stream(self.agent.as_mut(), &invocation.command_line(), send)
+    }
+
+    // reads the journal of an instance on its node, streaming it in pages.
+    fn logs(
+        &mut self,
+        name: &str,
+        since: Option<&str>,
+        lines: Option<u64>,
+        follow: bool,
+        send: &mut dyn FnMut(Response) -> bool,
+    ) -> Result<Response> {
+        let running = self.running(name)?;
+        let query = LogQuery::new(
+            &running.name,
+            &running.kind,
+            since.map(|x| x.to_string()),
+            lines,
+            follow,
+        )?;
+        let mut reader = Journalctl::new(running.node, self.agent.as_mut());
+
+        journal::stream(&mut reader, &query, journal::PAGE_SIZE, send)
+    }
+
+    // seals a secret under the user's name for commands to refer to. its value is
+    // only ever opened by the agents resolving those references.
+    fn secret(&mut self, name: &str, value: &str) -> Result<Response> {
+        let secret = Secret::new(self.user.clone(), name, value, &self.key)?;
+
+        let mut tx = self.db.begin()?;
+        tx.save_secret(&secret)?;
+        tx.commit()?;
+
+        let mut payload = HashMap::default();
+        payload.insert("name".to_string(), name.to_string());
+        Ok(Response::ok(payload))
+    }
+
+    // replaces any secret value in status and plan output with its reference.
+    fn redact(&self, mut response: Response) -> Result<Response> {
+        if matches!(response.payload, Payload::Workloads(_) | Payload::Plan(_)) {
+            let secrets = self.db.secrets()?;
+            let redactor =
+                Redactor::for_secrets(secrets.iter().map(|x| (x.name(), x.sealed())), &self.key)?;
+            response.payload = redactor.payload(response.payload);
+        }
+
+        Ok(response)
+    }
+
+    // stops every selected instance and drops it from its schedule's placements.
+    fn terminate(&mut self, selector: &Selector) -> Result<Response> {
+        let selected = self.select(selector)?;
+
+        if let (Selector::Name(name), true) = (selector, selected.is_empty()) {
+            return Err(protocol_error!(NotFound, "no workload named '{}'", name));
+        }
+
+        let mut workloads = Vec::new();
+
+        for instance in selected {
+            let workload = self.workloads[&instance].clone();
+            let (schedule, _) = self.source(&workload)?;
+            let mut status = self.describe(&instance, &workload)?;
+
+            self.agent
+                .stop(&rollout::instance(&instance, schedule.current_revision()))?;
+
+            let mut placements = self.db.placements(workload.schedule);
+            placements.remove(&instance);
+
+            let mut tx = self.db.begin()?;
+            tx.save_placements(workload.schedule, &placements)?;
+            tx.commit()?;
+
+            self.workloads.remove(&instance);
+            self.log()?.publish(
+                EventKind::Terminated,
+                &instance,
+                status.node.clone(),
+                workload.tags.clone(),
+            );
+
+            status.state = WorkloadState::Terminated;
+            workloads.push(status);
+        }
+
+        Ok(Response::ok(Payload::Workloads(workloads)))
+    }
+
+    fn schedule(
+        &mut self,
+        name: &str,
+        image: &str,
+        kind: &Kind,
+        tags: &HashMap<String, String>,
+    ) -> Result<Response> {
+        if self
+            .db
+            .schedules()
+            .iter()
+            .any(|x| x.manifest().command(name).is_some())
+        {
+            return Err(protocol_error!(
+                InvalidArgument,
+                "'{}' is already scheduled",
+                name
+            ));
+        }
+
+        let user = self.user.clone();
+        let manifest = Manifest::schedule(name, image, kind);
+        let applied = self.publish(tags, |db, rollout, nodes| {
+            planner::apply(db, rollout, None, &user, manifest, nodes)
+        })?;
+        let result = &applied.results[0];
+
+        let payload = Payload::Receipt(ScheduleReceipt {
+            name: name.to_string(),
+            node: result.node.clone(),
+            scheduled: applied.schedule.is_some(),
+        });
+
+        Ok(match &result.error {
+            Some(error) if applied.schedule.is_none() => Response {
+                payload,
+                ..Response::error(ErrorCode::Execution, error)
+            },
+            _ => Response::ok(payload),
+        })
+    }
+
+    fn apply(&mut self, manifest: &Manifest, tags: &HashMap<String, String>) -> Result<Response> {
+        let user = self.user.clone();
+        let schedules = self.db.schedules().to_vec();
+        let current = planner::owning(&schedules, &user, manifest);
+
+        let applied = self.publish(tags, |db, rollout, nodes| {
+            planner::apply(db, rollout, current, &user, manifest.clone(), nodes)
+        })?;
+        Ok(applied.response())
+    }
+
+    fn plan(&self, manifest: &Manifest) -> Result<Response> {
+        let current = planner::owning(self.db.schedules(), &self.user, manifest);
+        let placements = current
+            .and_then(|x| x.id())
+            .map(|x| self.db.placements(x))
+            .unwrap_or_default();
+
+        Ok(planner::diff(current, &placements, manifest, &self.summaries())?.response())
+    }
+
+    fn history(&self, id: Option<i64>) -> Result<Response> {
+        let schedule = planner::select(self.db.schedules(), &self.user, id)?;
+        Ok(Response::ok(schedule.history()))
+    }
+
+    fn rollback(
+        &mut self,
+        revision: u64,
+        id: Option<i64>,
+        tags: &HashMap<String, String>,
+    ) -> Result<Response> {
+        let user = self.user.clone();
+        let schedule = planner::select(self.db.schedules(), &user, id)?.clone();
+
+        let applied = self.publish(tags, |db, rollout, nodes| {
+            planner::rollback(db, rollout, &schedule, revision, &user, nodes)
+        })?;
+        Ok(applied.response())
+    }
+
+    fn scale(
+        &mut self,
+        name: &str,
+        replicas: u64,
+        id: Option<i64>,
+        tags: &HashMap<String, String>,
+    ) -> Result<Response> {
+        let user = self.user.clone();
+        let schedule = planner::select(self.db.schedules(), &user, id)?.clone();
+
+        let applied = self.publish(tags, |db, rollout, nodes| {
+            planner::scale(db, rollout, &schedule, name, replicas, &user, nodes)
+        })?;
+        Ok(applied.response())
+    }
+
+    fn quota(&self, user: Option<&str>) -> Result<Response> {
+        if let Some(user) = user.filter(|x| *x != self.user.username()) {
+            return Err(protocol_error!(
+                PermissionDenied,
+                "'{}' cannot read the quota of '{}'",
+                self.user.username(),
+                user
+            ));
+        }
+
+        let quota = self.db.quota(&self.user)?.ok_or_else(|| {
+            protocol_error!(NotFound, "user '{}' has no quota", self.user.username())
+        })?;
+        let usage = quota.usage(self.db.schedules())?;
+
+        Ok(Response::ok(quota.report(&usage)))
+    }
+
+    // every command is handled here, so the server advertises them all in its hello.
+    // watch, exec and logs stream partial responses through send until it returns
+    // false; watch also stops when idle, polled while no events are pending,
+    // returns false.
+    pub fn handle(
+        &mut self,
+        instruction: &Instruction,
+        send: &mut dyn FnMut(Response) -> bool,
+        idle: &mut dyn FnMut() -> bool,
+    ) -> Response {
+        let tags = &instruction.tags;
+        let result = match &instruction.command {
+            Command::Schedule(name, image, kind) => self.schedule(name, image, kind, tags),
+            Command::Apply(manifest) => self.apply(manifest, tags),
+            Command::Plan(manifest) => self.plan(manifest),
+            Command::History(id) => self.history(*id),
+            Command::Rollback(revision, id) => self.rollback(*revision, *id, tags),
+            Command::Scale(name, replicas, id) => self.scale(name, *replicas, *id, tags),
+            Command::Quota(user) => self.quota(user.as_deref()),
+            Command::Secret(name, value) => self.secret(name, value),
+            Command::Status(_) => selector(instruction).and_then(|x| self.status(&x)),
+            Command::Terminate(..) => selector(instruction).and_then(|x| self.terminate(&x)),
+            Command::Start(name) => self.control(Action::Start, name),
+            Command::Stop(name) => self.control(Action::Stop, name),
+            Command::Restart(name) => self.control(Action::Restart, name),
+            Command::Exec(name, cmd) => self.exec(name, cmd, send),
+            Command::Logs(name, since, lines, follow) => {
+                self.logs(name, since.as_deref(), *lines, *follow, send)
+            }
+            Command::Watch(_, since) => {
+                let filter = instruction.watch_filter().unwrap_or_default();
+                event::stream(&self.events, *since, &filter, send, idle)
+            }
+            Command::Cancel => Ok(Response::ok(Payload::default())),
+        };
+
+        result
+            .and_then(|x| self.redact(x))
+            .unwrap_or_else(|e| Response::from(&e))
+    }
+
+    pub fn serve(&mut self, r: &Receiver<Instruction>, s: &SyncSender<Response>) -> Result<()> {
+        while let Ok(instruction) = r.recv() {
+            if instruction.is_cancel() {
+                continue;
+            }
+
+            let cancelled = || r.try_recv().is_ok_and(|x| x.is_cancel());
+            let mut send = |response| s.send(response).is_ok() && !cancelled();
+            let mut idle = || !cancelled();
+
+            s.send(self.handle(&instruction, &mut send, &mut idle))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::types::{Node, Quota};
+    use crate::protocol::{ExecResult, Limit, OutputChunk, OutputStream};
+    use std::path::Path;
+
+    #[derive(Clone, Default)]
+    struct Agent {
+        steps: Arc<Mutex<Vec<String>>>,
+        output: Vec<String>,
+        code: i32,
+    }
+
+    impl Rollout for Agent {
+        fn start(&mut self, instance: &str) -> Result<()> {
+            self.steps
+                .lock()
+                .unwrap()
+                .push(format!("start {}", instance));
+            Ok(())
+        }
+
+        fn stop(&mut self, instance: &str) -> Result<()> {
+            self.steps
+                .lock()
+                .unwrap()
+                .push(format!("stop {}", instance));
+            Ok(())
+        }
+
+        fn healthy(&mut self, _instance: &str) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    impl Runner for Agent {
+        fn run(
+            &mut self,
+            argv: &[String],
+            f: &mut dyn FnMut(OutputChunk) -> bool,
+        ) -> Result<ExecResult> {
+            self.steps
+                .lock()
+                .unwrap()
+                .push(format!("run {}", argv.join(" ")));
+
+            for data in &self.output {
+                let chunk = OutputChunk {
+                    stream: OutputStream::Stdout,
+                    data: data.clone(),
+                };
+
+                if !f(chunk) {
+                    return Ok(ExecResult { code: None });
+                }
+            }
+
+            Ok(ExecResult {
+                code: Some(self.code),
+            })
+        }
+    }
+
+    fn dispatcher(quota: Option<(u64, u64, u64)>, agent: &Agent) -> Result<Dispatcher> {
+        let user = User::new("erikh", "");
+        let mut db = MemoryDB::new();
+
+        if let Some((workloads, cpu, mem)) = quota {
+            db.set_quota(Quota::new(user.clone(), workloads, cpu, mem));
+        }
+
+        let labels = [("datacenter".to_string(), "xo".to_string())].into();
+        let nodes = vec![Status::new(
+            Node::new("node1", "10.0.0.1", "erikh", &labels),
+            64,
+            65536,
+            0,
+        )];
+
+        let events = Arc::new(Mutex::new(EventLog::new(100)));
+        Ok(Dispatcher::new(
+            user,
+            db,
+            nodes,
+            Box::new(agent.clone()),
+            events,
+            Key::generate()?,
+        ))
+    }
+
+    fn instruction(command: Command) -> Instruction {
+        Instruction {
+            command,
+            tags: Default::default(),
+        }
+    }
+
+    fn run(dispatcher: &mut Dispatcher, instruction: Instruction) -> Response {
+        dispatcher.handle(&instruction, &mut |_| true, &mut || false)
+    }
+
+    fn manifest(file: &str) -> Result<Manifest> {
+        Manifest::from_file(Path::new(file))
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let agent = Agent::default();
+        let mut dispatcher = dispatcher(None, &agent)?;
+        let one = manifest("testdata/resources-one.yaml")?;
+        let two = manifest("testdata/resources-two.yaml")?;
+
+        let response = run(&mut dispatcher, instruction(Command::Plan(one.clone())));
+        assert!(response.status, "{:?}", response);
+
+        let response = run(&mut dispatcher, instruction(Command::Apply(one.clone())));
+        assert!(response.status, "{:?}", response);
+        assert_eq!(dispatcher.db().schedules().len(), 1);
+        assert_eq!(dispatcher.db().placements(1)["foo"], "node1");
+
+        let response = run(&mut dispatcher, instruction(Command::Apply(two)));
+        assert!(response.status, "{:?}", response);
+        assert_eq!(dispatcher.db().schedules().len(), 1);
+        assert_eq!(dispatcher.db().schedules()[0].revisions().len(), 2);
+        assert_eq!(
+            *agent.steps.lock().unwrap(),
+            vec!["stop foo-r1", "start foo-r2"]
+        );
+
+        let response = run(&mut dispatcher, instruction(Command::Rollback(1, None)));
+        assert!(response.status, "{:?}", response);
+        assert_eq!(dispatcher.db().schedules()[0].manifest(), &one);
+
+        match run(&mut dispatcher, instruction(Command::History(None))).payload {
+            Payload::History(x) => assert_eq!(x.len(), 3),
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        match run(&mut dispatcher, instruction(Command::Status(None))).payload {
+            Payload::Workloads(x) => {
+                assert_eq!(
+                    x.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+                    vec!["bar", "foo"]
+                );
+                assert!(x
+                    .iter()
+                    .all(|x| x.state == WorkloadState::Running && x.health.is_none()));
+            }
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        agent.steps.lock().unwrap().clear();
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Stop("foo".to_string())),
+        );
+        match response.payload {
+            Payload::Workloads(x) => {
+                assert_eq!(x.len(), 1);
+                assert_eq!(x[0].name, "foo");
+                assert_eq!(x[0].state, WorkloadState::Stopped);
+            }
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Restart("foo".to_string())),
+        );
+        assert!(response.status, "{:?}", response);
+        assert_eq!(
+            *agent.steps.lock().unwrap(),
+            vec![
+                "run ssh node1 -- machinectl poweroff foo-r3",
+                "run ssh node1 -- machinectl reboot foo-r3",
+            ]
+        );
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Start("nope".to_string())),
+        );
+        assert_eq!(response.code, Some(ErrorCode::NotFound));
+
+        let agent = Agent {
+            code: 1,
+            ..Default::default()
+        };
+        let mut dispatcher = self::dispatcher(None, &agent)?;
+        let response = run(&mut dispatcher, instruction(Command::Apply(one)));
+        assert!(response.status, "{:?}", response);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Stop("bar".to_string())),
+        );
+        assert_eq!(response.code, Some(ErrorCode::Execution));
+        assert_eq!(
+            response.error.as_deref(),
+            Some("stop of 'bar' exited with status 1")
+        );
+
+        match run(
+            &mut dispatcher,
+            instruction(Command::Status(Some("bar".to_string()))),
+        )
+        .payload
+        {
+            Payload::Workloads(x) => assert_eq!(x[0].state, WorkloadState::Running),
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exec() -> Result<()> {
+        let agent = Agent {
+            output: vec!["one".to_string(), "two".to_string()],
+            code: 3,
+            ..Default::default()
+        };
+        let mut dispatcher = dispatcher(None, &agent)?;
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Apply(manifest("testdata/resources-one.yaml")?)),
+        );
+        assert!(response.status, "{:?}", response);
+
+        let mut streamed = Vec::new();
+        let response = dispatcher.handle(
+            &instruction(Command::Exec("foo".to_string(), "ps auxw".to_string())),
+            &mut |x| {
+                streamed.push(x);
+                true
+            },
+            &mut || false,
+        );
+        assert!(response.status, "{:?}", response);
+        assert!(!response.more);
+        assert_eq!(
+            response.payload,
+            Payload::Exit(ExecResult { code: Some(3) })
+        );
+        assert!(streamed.iter().all(|x| x.more));
+        assert_eq!(
+            streamed
+                .into_iter()
+                .map(|x| match x.payload {
+                    Payload::Output(chunk) => chunk.data,
+                    x => panic!("unexpected payload {:?}", x),
+                })
+                .collect::<Vec<String>>(),
+            vec!["one", "two"]
+        );
+        assert_eq!(
+            *agent.steps.lock().unwrap(),
+            vec!["run ssh node1 -- systemd-run --pipe --wait --quiet --machine=foo-r1 /bin/sh -c 'ps auxw'"]
+        );
+
+        let response = dispatcher.handle(
+            &instruction(Command::Exec("foo".to_string(), "yes".to_string())),
+            &mut |_| false,
+            &mut || false,
+        );
+        assert_eq!(response.payload, Payload::Exit(ExecResult { code: None }));
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Exec("nope".to_string(), "ps".to_string())),
+        );
+        assert_eq!(response.code, Some(ErrorCode::NotFound));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_logs() -> Result<()> {
+        let agent = Agent {
+            output: vec![
+                r#"{"__REALTIME_TIMESTAMP":"1700000000000000","MESSAGE":"started"}"#.to_string(),
+                r#"{"__REALTIME_TIMESTAMP":"1700000001000000","MESSAGE":"ready"}"#.to_string(),
+            ],
+            ..Default::default()
+        };
+        let mut dispatcher = dispatcher(None, &agent)?;
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Apply(manifest("testdata/resources-one.yaml")?)),
+        );
+        assert!(response.status, "{:?}", response);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Logs("bar".to_string(), None, Some(10), false)),
+        );
+        match response.payload {
+            Payload::Logs(x) => assert_eq!(
+                x.iter().map(|x| x.message.as_str()).collect::<Vec<_>>(),
+                vec!["started", "ready"]
+            ),
+            x => panic!("unexpected payload {:?}", x),
+        }
+        assert_eq!(
+            *agent.steps.lock().unwrap(),
+            vec!["run ssh node1 -- journalctl --output=json --no-pager --unit=systemd-nspawn@bar-r1.service --lines=10"]
+        );
+
+        let mut pages = 0;
+        let response = dispatcher.handle(
+            &instruction(Command::Logs("bar".to_string(), None, None, true)),
+            &mut |x| {
+                assert!(x.more);
+                pages += 1;
+                false
+            },
+            &mut || false,
+        );
+        assert!(response.status, "{:?}", response);
+        assert_eq!(pages, 1);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Logs("nope".to_string(), None, None, false)),
+        );
+        assert_eq!(response.code, Some(ErrorCode::NotFound));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() -> Result<()> {
+        let agent = Agent::default();
+        let mut dispatcher = dispatcher(None, &agent)?;
+        let labels = [("datacenter".to_string(), "xo".to_string())].into();
+        dispatcher.nodes.push(Status::new(
+            Node::new("node2", "10.0.0.2", "erikh", &labels),
+            64,
+            65536,
+            0,
+        ));
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let manifest = Manifest::from_io(
+            format!(
+                r#"
+location:
+  kind: systemd
+  filter:
+    datacenter: xo
+commands:
+  - name: web
+    command: schedule
+    args:
+      kind: nspawn
+      image: nginx
+      health-tcp: {}
+      restart: on-failure
+      restart-backoff: "0"
+      restart-threshold: "2"
+"#,
+                closed
+            )
+            .as_bytes(),
+        )?;
+
+        let response = run(&mut dispatcher, instruction(Command::Apply(manifest)));
+        assert!(response.status, "{:?}", response);
+        assert_eq!(dispatcher.db().placements(1)["web"], "node1");
+
+        let status =
+            |dispatcher: &mut Dispatcher| match run(dispatcher, instruction(Command::Status(None)))
+                .payload
+            {
+                Payload::Workloads(mut x) if x.len() == 1 => x.remove(0),
+                x => panic!("unexpected payload {:?}", x),
+            };
+
+        let web = status(&mut dispatcher);
+        assert_eq!(web.health, None);
+        assert_eq!(web.state, WorkloadState::Running);
+        assert_eq!(status(&mut dispatcher), web);
+        assert!(
+            agent.steps.lock().unwrap().is_empty(),
+            "status has no side effects"
+        );
+
+        let key = crate::secrets::Key::generate()?;
+        let mut monitor = health::Monitor::default();
+        let mut check = |dispatcher: &mut Dispatcher| -> Result<()> {
+            let assignment = dispatcher.assignment("web")?;
+            let report = monitor.check(&assignment, &key, &mut agent.clone())?;
+            dispatcher.record(report.unwrap())
+        };
+
+        assert_eq!(dispatcher.assignment("web")?.workload.name, "web-r1");
+        check(&mut dispatcher)?;
+
+        let web = status(&mut dispatcher);
+        assert!(
+            matches!(web.health, Some(Health::Unhealthy(_))),
+            "{:?}",
+            web
+        );
+        assert_eq!(web.state, WorkloadState::Running, "restarted in place");
+        assert_eq!(web.node.as_deref(), Some("node1"));
+        assert_eq!(status(&mut dispatcher), web);
+        assert_eq!(
+            *agent.steps.lock().unwrap(),
+            vec!["run machinectl reboot web-r1"]
+        );
+
+        check(&mut dispatcher)?;
+        let web = status(&mut dispatcher);
+        assert_eq!(web.node.as_deref(), Some("node2"), "rescheduled");
+        assert_eq!(web.state, WorkloadState::Running);
+        assert_eq!(web.health, None);
+        assert_eq!(dispatcher.db().placements(1)["web"], "node2");
+        assert_eq!(
+            agent.steps.lock().unwrap()[1..],
+            ["stop web-r1", "start web-r1"]
+        );
+
+        let report = health::Report {
+            instance: "db-r1".to_string(),
+            health: Health::Healthy,
+            state: WorkloadState::Running,
+            reschedule: false,
+        };
+        assert_eq!(
+            ErrorCode::of(&dispatcher.record(report).unwrap_err()),
+            ErrorCode::NotFound
+        );
+
+        match run(
+            &mut dispatcher,
+            instruction(Command::Status(Some("db".to_string()))),
+        )
+        .payload
+        {
+            Payload::Workloads(x) => assert!(x.is_empty()),
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        let events = dispatcher
+            .events
+            .lock()
+            .unwrap()
+            .replay(0, &Default::default())?
+            .into_iter()
+            .map(|x| (x.kind, x.node))
+            .collect::<Vec<_>>();
+        let node = |x: &str| Some(x.to_string());
+
+        assert_eq!(
+            events,
+            vec![
+                (EventKind::Scheduled, node("node1")),
+                (EventKind::Scheduled, node("node2")),
+                (EventKind::Terminated, node("node1")),
+                (EventKind::Started, node("node2")),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_secrets() -> Result<()> {
+        let agent = Agent::default();
+        let mut dispatcher = dispatcher(None, &agent)?;
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Secret(
+                "db-health".to_string(),
+                "hunter2".to_string(),
+            )),
+        );
+        assert!(response.status, "{:?}", response);
+        assert!(
+            !format!("{:?}", response).contains("hunter2"),
+            "the value is never echoed"
+        );
+        assert_eq!(dispatcher.db().secrets()?.len(), 1);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Secret("not valid".to_string(), "x".to_string())),
+        );
+        assert_eq!(response.code, Some(ErrorCode::InvalidArgument));
+
+        let manifest = Manifest::from_io(
+            br#"
+location:
+  kind: systemd
+  filter: {}
+commands:
+  - name: web
+    command: schedule
+    args:
+      kind: nspawn
+      image: nginx
+      health-tcp: secret://db-health
+"#
+            .as_slice(),
+        )?;
+        let response = run(&mut dispatcher, instruction(Command::Apply(manifest)));
+        assert!(response.status, "{:?}", response);
+
+        let assignment = dispatcher.assignment("web")?;
+        assert_eq!(
+            dispatcher
+                .key
+                .open("db-health", &assignment.secrets["db-health"])?,
+            "hunter2",
+            "sealed for the node to open"
+        );
+
+        dispatcher.record(health::Report {
+            instance: "web-r1".to_string(),
+            health: Health::Unhealthy("could not reach hunter2".to_string()),
+            state: WorkloadState::Running,
+            reschedule: false,
+        })?;
+
+        match run(&mut dispatcher, instruction(Command::Status(None))).payload {
+            Payload::Workloads(x) => assert_eq!(
+                x[0].health,
+                Some(Health::Unhealthy(
+                    "could not reach secret://db-health".to_string()
+                ))
+            ),
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota() -> Result<()> {
+        let mut dispatcher = dispatcher(Some((3, 64, 65536)), &Agent::default())?;
+        let kind = Kind::Systemd(crate::common::SystemdKind::NSpawn);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Schedule(
+                "single".to_string(),
+                "linux".to_string(),
+                kind.clone(),
+            )),
+        );
+        assert!(response.status, "{:?}", response);
+        assert_eq!(
+            response.payload,
+            Payload::Receipt(ScheduleReceipt {
+                name: "single".to_string(),
+                node: Some("node1".to_string()),
+                scheduled: true,
+            })
+        );
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Schedule(
+                "single".to_string(),
+                "linux".to_string(),
+                kind.clone(),
+            )),
+        );
+        assert_eq!(response.code, Some(ErrorCode::InvalidArgument));
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Apply(manifest("testdata/resources-one.yaml")?)),
+        );
+        assert!(response.status, "{:?}", response);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Apply(manifest("testdata/replicas-one.yaml")?)),
+        );
+        assert!(!response.status);
+        assert_eq!(response.code, Some(ErrorCode::QuotaExceeded));
+        assert_eq!(dispatcher.db().schedules().len(), 2);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Schedule(
+                "another".to_string(),
+                "linux".to_string(),
+                kind,
+            )),
+        );
+        assert_eq!(response.code, Some(ErrorCode::QuotaExceeded));
+
+        match run(&mut dispatcher, instruction(Command::Quota(None))).payload {
+            Payload::Quota(report) => {
+                assert_eq!(report.user, "erikh");
+                assert_eq!(report.workloads, Limit { used: 3, limit: 3 });
+            }
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Quota(Some("erikh".to_string()))),
+        );
+        assert!(response.status, "{:?}", response);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Quota(Some("other".to_string()))),
+        );
+        assert_eq!(response.code, Some(ErrorCode::PermissionDenied));
+
+        let response = run(
+            &mut self::dispatcher(None, &Agent::default())?,
+            instruction(Command::Quota(None)),
+        );
+        assert_eq!(response.code, Some(ErrorCode::NotFound));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_events() -> Result<()> {
+        let mut dispatcher = dispatcher(None, &Agent::default())?;
+        let tags: HashMap<String, String> = [("team".to_string(), "web".to_string())].into();
+
+        let response = run(
+            &mut dispatcher,
+            Instruction {
+                command: Command::Apply(manifest("testdata/resources-one.yaml")?),
+                tags: tags.clone(),
+            },
+        );
+        assert!(response.status, "{:?}", response);
+
+        let response = run(
+            &mut dispatcher,
+            instruction(Command::Apply(manifest("testdata/resources-two.yaml")?)),
+        );
+        assert!(response.status, "{:?}", response);
+
+        dispatcher.set_alive("node1", false)?;
+        dispatcher.set_alive("node1", false)?;
+        assert!(dispatcher.set_alive("node2", true).is_err());
+
+        let mut seen = Vec::new();
+        let response = dispatcher.handle(
+            &instruction(Command::Watch(Some("foo".to_string()), Some(0))),
+            &mut |x| {
+                seen.push(x);
+                true
+            },
+            &mut || false,
+        );
+        assert!(response.status, "{:?}", response);
+        assert!(!response.more);
+
+        let events = seen
+            .into_iter()
+            .map(|x| match x.payload {
+                Payload::Event(event) => event,
+                x => panic!("unexpected payload {:?}", x),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events
+                .iter()
+                .map(|x| (x.kind, x.name.as_str(), x.node.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                (EventKind::Scheduled, "foo", Some("node1")),
+                (EventKind::Terminated, "foo", Some("node1")),
+                (EventKind::Started, "foo", Some("node1")),
+            ]
+        );
+        assert_eq!(events[0].tags, tags);
+        assert!(events[1].tags.is_empty());
+
+        let mut last = None;
+        let response = dispatcher.handle(
+            &instruction(Command::Watch(None, Some(0))),
+            &mut |x| {
+                last = Some(x);
+                true
+            },
+            &mut || false,
+        );
+        assert!(response.status, "{:?}", response);
+        match last.map(|x| x.payload) {
+            Some(Payload::Event(event)) => {
+                assert_eq!(event.sequence, 7);
+                assert_eq!(event.kind, EventKind::NodeDown);
+                assert_eq!(event.name, "node1");
+            }
+            x => panic!("unexpected payload {:?}", x),
+        }
+
+        let response = run(&mut dispatcher, instruction(Command::Watch(None, Some(9))));
+        assert_eq!(response.code, Some(ErrorCode::InvalidArgument));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_watch() -> Result<()> {
+        use crate::transports::server::tcp::TcpServer;
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        let server = TcpListener::bind("localhost:0")?;
+        let addr = server.local_addr()?;
+        let (ins_s, ins_r) = std::sync::mpsc::sync_channel(1000);
+        let (resp_s, resp_r) = std::sync::mpsc::sync_channel(1000);
+        let (close_s, close_r) = std::sync::mpsc::sync_channel(1);
+
+        let resp_r = Arc::new(Mutex::new(resp_r));
+
+        std::thread::spawn(move || {
+            let (sock, _) = server.accept().unwrap();
+            let mut server = TcpServer::new(sock);
+            server.run(ins_s, resp_r, close_r)
+        });
+
+        let mut dispatcher = dispatcher(None, &Agent::default())?;
+        let events = dispatcher.events.clone();
+        dispatcher.set_alive("node1", false)?;
+
+        std::thread::spawn(move || dispatcher.serve(&ins_r, &resp_s));
+
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut next = || -> Result<Response> {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            Ok(serde_json::from_str(&line)?)
+        };
+
+        stream.write_all(b"watch since=\"0\"\n")?;
+
+        let response = next()?;
+        assert!(response.more);
+        assert!(matches!(
+            response.payload,
+            Payload::Event(ref x) if x.kind == EventKind::NodeDown
+        ));
+
+        events
+            .lock()
+            .unwrap()
+            .publish(EventKind::NodeUp, "node1", None, HashMap::new());
+
+        let response = next()?;
+        assert!(response.more);
+        assert!(matches!(
+            response.payload,
+            Payload::Event(ref x) if x.kind == EventKind::NodeUp
+        ));
+
+        let cancel = crate::protocol::Encoding::Text.encode(&Instruction::cancel())?;
+        stream.write_all(format!("{}\n", cancel).as_bytes())?;
+
+        let response = next()?;
+        assert!(response.status, "{:?}", response);
+        assert!(!response.more);
+
+        close_s.send(()).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn test_terminate() -> Result<()> {
+        let agent = Agent::default();
+        let mut dispatcher = dispatcher(None, &agent)?;
+        let tags: HashMap<String, String> = [("team".to_string(), "infra".to_string())].into();
+
+        let response = run(
+            &mut dispatcher,
+            Instruction {
+                command: Command::Apply(manifest("testdata/resources-one.yaml")?),
+                tags,
+            },
+        );
+        assert!(response.status, "{:?}", response);
+
+        let names = |response: Response| match response.payload {
+            Payload::Workloads(x) => x
+                .into_iter()
+                .map(|x| (x.name, x.state))
+                .collect::<Vec<(String, WorkloadState)>>(),
+            x => panic!("unexpected payload {:?}", x),
+        };
+        let mut send = |text: &str| -> Result<Response> {
+            Ok(run(&mut dispatcher, text.parse::<Instruction>()?))
+        };
+
+        assert_eq!(
+            names(send(r#"status tags="team=infra""#)?),
+            vec![
+                ("bar".to_string(), WorkloadState::Running),
+                ("foo".to_string(), WorkloadState::Running)
+            ]
+        );
+        assert!(names(send(r#"status tags="team=ops""#)?).is_empty());
+
+        assert_eq!(
+            send(r#"terminate name="nope""#)?.code,
+            Some(ErrorCode::NotFound)
+        );
+
+        assert_eq!(
+            names(send(r#"terminate name="foo""#)?),
+            vec![("foo".to_string(), WorkloadState::Terminated)]
+        );
+        assert_eq!(names(send("status")?).len(), 1);
+        assert_eq!(
+            names(send(r#"terminate tags="team=infra" all="true""#)?),
+            vec![("bar".to_string(), WorkloadState::Terminated)]
+        );
+        assert!(names(send("status")?).is_empty());
+
+        let response = run(
+            &mut dispatcher,
+            Instruction {
+                command: Command::Terminate(None, false),
+                tags: [("team".to_string(), "infra".to_string())].into(),
+            },
+        );
+        assert_eq!(response.code, Some(ErrorCode::InvalidArgument));
+
+        assert_eq!(
+            *agent.steps.lock().unwrap(),
+            vec!["stop foo-r1", "stop bar-r1"]
+        );
+        assert_eq!(
+            dispatcher.db().placements(1).keys().collect::<Vec<_>>(),
+            vec!["foo-network"]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,414 @@
+use super::{unit_name, Invocation, Runner};
+use crate::common::*;
+use crate::protocol::{ExecResult, OutputChunk, OutputStream, Response, WorkloadStatus};
+use crate::protocol_error;
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+// machines get their command through systemd-run inside the container. other
+// workloads have no container to run in, so the command enters the namespaces of
+// the unit's main process instead; it does not join the unit's cgroup.
+pub fn argv(name: &str, kind: &Kind, cmd: &str, main_pid: Option<u32>) -> Result<Vec<String>> {
+    let mut argv = match kind {
+        Kind::Systemd(SystemdKind::NSpawn) | Kind::Systemd(SystemdKind::Machine) => vec![
+            "systemd-run".to_string(),
+            "--pipe".to_string(),
+            "--wait".to_string(),
+            "--quiet".to_string(),
+            format!("--machine={}", name),
+        ],
+        Kind::Systemd(_) => match main_pid {
+            Some(pid) => vec![
+                "nsenter".to_string(),
+                format!("--target={}", pid),
+                "--all".to_string(),
+            ],
+            None => {
+                return Err(protocol_error!(
+                    Execution,
+                    "cannot exec in workload '{}': it has no running main process",
+                    name
+                ))
+            }
+        },
+        Kind::Other => {
+            return Err(protocol_error!(
+                Unsupported,
+                "cannot exec in workload '{}' of kind '{}'",
+                name,
+                kind
+            ))
+        }
+    };
+
+    argv.extend(["/bin/sh", "-c", cmd].iter().map(|x| x.to_string()));
+    Ok(argv)
+}
+
+// the unit whose main process a command is run next to; timers run in their service.
+fn main_unit(name: &str, kind: &Kind) -> Option<String> {
+    match kind {
+        Kind::Systemd(SystemdKind::NSpawn) | Kind::Systemd(SystemdKind::Machine) => None,
+        Kind::Systemd(SystemdKind::Timer) => Some(unit_name(name, &SystemdKind::Service)),
+        Kind::Systemd(kind) => Some(unit_name(name, kind)),
+        Kind::Other => None,
+    }
+}
+
+pub fn main_pid_argv(name: &str, kind: &Kind) -> Option<Vec<String>> {
+    main_unit(name, kind).map(|unit| {
+        vec![
+            "systemctl".to_string(),
+            "show".to_string(),
+            "--property=MainPID".to_string(),
+            "--value".to_string(),
+            unit,
+        ]
+    })
+}
+
+fn parse_main_pid(output: &str) -> Option<u32> {
+    output.trim().parse().ok().filter(|pid| *pid != 0)
+}
+
+pub fn main_pid(workload: &WorkloadStatus, runner: &mut dyn Runner) -> Result<Option<u32>> {
+    let argv = match main_pid_argv(&workload.name, &workload.kind) {
+        Some(argv) => argv,
+        None => return Ok(None),
+    };
+
+    let invocation = Invocation {
+        node: workload.node.clone(),
+        argv,
+    };
+
+    let mut output = String::new();
+    runner.run(&invocation.command_line(), &mut |chunk| {
+        if chunk.stream == OutputStream::Stdout {
+            output += &chunk.data;
+        }
+        true
+    })?;
+
+    Ok(parse_main_pid(&output))
+}
+
+pub fn invocation(
+    workload: &WorkloadStatus,
+    cmd: &str,
+    main_pid: Option<u32>,
+) -> Result<Invocation> {
+    Ok(Invocation {
+        node: workload.node.clone(),
+        argv: argv(&workload.name, &workload.kind, cmd, main_pid)?,
+    })
+}
+
+fn forward(io: impl Read, stream: OutputStream, s: Sender<OutputChunk>) {
+    for line in BufReader::new(io).lines() {
+        let data = match line {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+
+        if s.send(OutputChunk { stream, data }).is_err() {
+            return;
+        }
+    }
+}
+
+pub fn run(argv: &[String], f: &mut dyn FnMut(OutputChunk) -> bool) -> Result<ExecResult> {
+    run_until(argv, None, f)
+}
+
+// like run, but kills the command and fails if it has not exited by the deadline.
+pub fn run_until(
+    argv: &[String],
+    deadline: Option<Instant>,
+    f: &mut dyn FnMut(OutputChunk) -> bool,
+) -> Result<ExecResult> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| anyhow!("no command given to execute"))?;
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| protocol_error!(Execution, "could not run {:?}: {}", program, e))?;
+
+    let (s, r) = channel();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let handles = [
+        {
+            let s = s.clone();
+            std::thread::spawn(move || forward(stdout, OutputStream::Stdout, s))
+        },
+        std::thread::spawn(move || forward(stderr, OutputStream::Stderr, s)),
+    ];
+
+    let remaining = || deadline.map(|x| x.saturating_duration_since(Instant::now()));
+    let mut expired = false;
+
+    loop {
+        let chunk = match remaining() {
+            Some(timeout) => match r.recv_timeout(timeout) {
+                Ok(chunk) => chunk,
+                Err(RecvTimeoutError::Timeout) => {
+                    expired = true;
+                    break;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match r.recv() {
+                Ok(chunk) => chunk,
+                Err(_) => break,
+            },
+        };
+
+        if !f(chunk) {
+            child.kill()?;
+            break;
+        }
+    }
+
+    // the command may close its output and keep running.
+    while !expired && remaining().is_some() && child.try_wait()?.is_none() {
+        expired = remaining().is_some_and(|x| x.is_zero());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    if expired {
+        child.kill()?;
+        child.wait()?;
+
+        // descendants of the command can keep its output open, so the forwarding
+        // threads are left to finish on their own.
+        return Err(protocol_error!(
+            Execution,
+            "{:?} did not exit before its deadline",
+            program
+        ));
+    }
+
+    let status = child.wait()?;
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    Ok(ExecResult {
+        code: status.code(),
+    })
+}
+
+pub fn stream(
+    runner: &mut dyn Runner,
+    argv: &[String],
+    send: &mut dyn FnMut(Response) -> bool,
+) -> Result<Response> {
+    let result = runner.run(argv, &mut |chunk| send(Response::partial(chunk)))?;
+    Ok(Response::ok(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Local;
+    use crate::protocol::{ErrorCode, Payload};
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn test_argv() -> Result<()> {
+        let table = vec![
+            (
+                Kind::Systemd(SystemdKind::NSpawn),
+                None,
+                vec![
+                    "systemd-run",
+                    "--pipe",
+                    "--wait",
+                    "--quiet",
+                    "--machine=foo",
+                ],
+                "nspawn machine",
+            ),
+            (
+                Kind::Systemd(SystemdKind::Service),
+                Some(42),
+                vec!["nsenter", "--target=42", "--all"],
+                "service",
+            ),
+            (
+                Kind::Systemd(SystemdKind::Timer),
+                Some(42),
+                vec!["nsenter", "--target=42", "--all"],
+                "timer runs in its service",
+            ),
+        ];
+
+        for (kind, pid, target, annotation) in table {
+            let argv = argv("foo", &kind, "ps auxw", pid)?;
+            assert_eq!(argv[..target.len()], target, "{}", annotation);
+            assert_eq!(
+                argv[target.len()..],
+                ["/bin/sh", "-c", "ps auxw"],
+                "{}",
+                annotation
+            );
+        }
+
+        assert_eq!(
+            ErrorCode::of(&argv("foo", &Kind::Other, "ps", None).unwrap_err()),
+            ErrorCode::Unsupported
+        );
+        assert_eq!(
+            ErrorCode::of(
+                &argv("foo", &Kind::Systemd(SystemdKind::Service), "ps", None).unwrap_err()
+            ),
+            ErrorCode::Execution
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_main_pid() -> Result<()> {
+        let table = vec![
+            (
+                Kind::Systemd(SystemdKind::Service),
+                Some("foo.service"),
+                "service",
+            ),
+            (
+                Kind::Systemd(SystemdKind::Timer),
+                Some("foo.service"),
+                "timer",
+            ),
+            (Kind::Systemd(SystemdKind::NSpawn), None, "machine"),
+            (Kind::Other, None, "other"),
+        ];
+
+        for (kind, unit, annotation) in table {
+            assert_eq!(
+                main_pid_argv("foo", &kind).map(|x| x[4].clone()).as_deref(),
+                unit,
+                "{}",
+                annotation
+            );
+        }
+
+        assert_eq!(parse_main_pid("1234\n"), Some(1234));
+        assert_eq!(parse_main_pid("0\n"), None);
+        assert_eq!(parse_main_pid(""), None);
+
+        let workload = WorkloadStatus {
+            name: "foo".to_string(),
+            kind: Kind::Systemd(SystemdKind::Service),
+            image: "linux".to_string(),
+            state: crate::protocol::WorkloadState::Running,
+            node: Some("node1".to_string()),
+            tags: Default::default(),
+            health: None,
+        };
+        assert_eq!(
+            invocation(&workload, "ps", Some(7))?.command_line(),
+            vec![
+                "ssh",
+                "node1",
+                "--",
+                "nsenter --target=7 --all /bin/sh -c ps"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run() -> Result<()> {
+        let mut chunks = Vec::new();
+        let result = run(&sh("echo out; echo err >&2; exit 3"), &mut |chunk| {
+            chunks.push(chunk);
+            true
+        })?;
+
+        assert_eq!(result.code, Some(3));
+        chunks.sort_by_key(|x| x.stream);
+        assert_eq!(
+            chunks,
+            vec![
+                OutputChunk {
+                    stream: OutputStream::Stdout,
+                    data: "out".to_string(),
+                },
+                OutputChunk {
+                    stream: OutputStream::Stderr,
+                    data: "err".to_string(),
+                },
+            ]
+        );
+
+        let result = run(&sh("while true; do echo y; sleep 0.01; done"), &mut |_| {
+            false
+        })?;
+        assert_eq!(result.code, None);
+
+        assert_eq!(
+            ErrorCode::of(&run(&["/nonexistent/dao-test".to_string()], &mut |_| true).unwrap_err()),
+            ErrorCode::Execution
+        );
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let error = run_until(&sh("sleep 5"), Some(deadline), &mut |_| true).unwrap_err();
+        assert_eq!(ErrorCode::of(&error), ErrorCode::Execution);
+        assert!(Instant::now() < deadline + Duration::from_secs(2));
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let error = run_until(&sh("sleep 5 >&- 2>&-"), Some(deadline), &mut |_| true).unwrap_err();
+        assert_eq!(ErrorCode::of(&error), ErrorCode::Execution);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(
+            run_until(&sh("exit 2"), Some(deadline), &mut |_| true)?.code,
+            Some(2)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream() -> Result<()> {
+        let mut responses = Vec::new();
+        let response = stream(&mut Local, &sh("echo one; echo two"), &mut |response| {
+            responses.push(response);
+            true
+        })?;
+
+        assert!(responses.iter().all(|x| x.more));
+        assert_eq!(
+            responses
+                .iter()
+                .map(|x| match &x.payload {
+                    Payload::Output(chunk) => chunk.data.clone(),
+                    _ => Default::default(),
+                })
+                .collect::<Vec<String>>(),
+            vec!["one", "two"]
+        );
+        assert!(!response.more);
+        assert_eq!(
+            response.payload,
+            Payload::Exit(ExecResult { code: Some(0) })
+        );
+
+        Ok(())
+    }
+}
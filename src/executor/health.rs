@@ -0,0 +1,386 @@
+use super::{exec, invocation, Action, Local, Runner};
+use crate::manifest::{Decision, HealthCheck, SchedulingCommand};
+use crate::protocol::{Health, WorkloadState, WorkloadStatus};
+use crate::secrets::{self, Key, Sealed};
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn connect(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut error = anyhow!("could not resolve '{}'", addr);
+
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = anyhow!("could not connect to '{}': {}", addr, e),
+        }
+    }
+
+    Err(error)
+}
+
+fn http(url: &str, timeout: Duration) -> Result<()> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("unsupported url '{}'", url))?;
+
+    let (host, path) = match rest.find('/') {
+        Some(x) => (&rest[..x], &rest[x..]),
+        None => (rest, "/"),
+    };
+
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = connect(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(
+        format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        )
+        .as_bytes(),
+    )?;
+
+    let mut response = Vec::new();
+    stream.take(1024).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .lines()
+        .next()
+        .and_then(|x| x.split_whitespace().nth(1))
+        .and_then(|x| x.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("invalid http response from '{}'", url))?;
+
+    if (200..400).contains(&status) {
+        Ok(())
+    } else {
+        Err(anyhow!("'{}' returned status {}", url, status))
+    }
+}
+
+fn run(workload: &WorkloadStatus, cmd: &str, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let invocation = exec::invocation(workload, cmd, exec::main_pid(workload, &mut Local)?)?;
+    let result = exec::run_until(&invocation.command_line(), Some(deadline), &mut |_| true)
+        .map_err(|e| {
+            if Instant::now() >= deadline {
+                anyhow!("'{}' did not finish within {:?}", cmd, timeout)
+            } else {
+                e
+            }
+        })?;
+
+    match result.code {
+        Some(0) => Ok(()),
+        Some(code) => Err(anyhow!("'{}' exited with status {}", cmd, code)),
+        None => Err(anyhow!("'{}' was killed", cmd)),
+    }
+}
+
+pub fn check(check: &HealthCheck, workload: &WorkloadStatus, timeout: Duration) -> Health {
+    let result = match check {
+        HealthCheck::Exec(cmd) => run(workload, cmd, timeout),
+        HealthCheck::Tcp(addr) => connect(addr, timeout).map(|_| ()),
+        HealthCheck::Http(url) => http(url, timeout),
+    };
+
+    match result {
+        Ok(()) => Health::Healthy,
+        Err(e) => Health::Unhealthy(e.to_string()),
+    }
+}
+
+// what the server hands a node agent to check an instance: the instance as the
+// node runs it, the command it was scheduled from with its secret references
+// intact, and the sealed secrets those references name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub workload: WorkloadStatus,
+    pub command: SchedulingCommand,
+    pub secrets: BTreeMap<String, Sealed>,
+}
+
+// what a node agent found when it checked an instance, and what it did about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub instance: String,
+    pub health: Health,
+    pub state: WorkloadState,
+    pub reschedule: bool,
+}
+
+struct Failures {
+    count: u32,
+    deployed: Instant,
+}
+
+// runs in the node agent: probes the instances on its node and restarts them in
+// place under their restart policy. moving an instance to another node is left
+// to the server, which the report asks for it.
+#[derive(Default)]
+pub struct Monitor {
+    failures: HashMap<String, Failures>,
+}
+
+impl Monitor {
+    pub fn check(
+        &mut self,
+        assignment: &Assignment,
+        key: &Key,
+        runner: &mut dyn Runner,
+    ) -> Result<Option<Report>> {
+        let resolved = secrets::resolve(&assignment.command, &assignment.secrets, key)?;
+        let Some(health_check) = resolved.command.health_check()? else {
+            return Ok(None);
+        };
+
+        let workload = WorkloadStatus {
+            node: None,
+            ..assignment.workload.clone()
+        };
+        let health = match check(&health_check, &workload, TIMEOUT) {
+            Health::Unhealthy(message) => Health::Unhealthy(resolved.redactor.redact(&message)),
+            health => health,
+        };
+
+        let mut report = Report {
+            instance: workload.name.clone(),
+            health,
+            state: WorkloadState::Running,
+            reschedule: false,
+        };
+
+        let failures = self
+            .failures
+            .entry(workload.name.clone())
+            .or_insert_with(|| Failures {
+                count: 0,
+                deployed: Instant::now(),
+            });
+
+        if report.health == Health::Healthy {
+            failures.count = 0;
+            return Ok(Some(report));
+        }
+
+        failures.count += 1;
+        report.state = match resolved
+            .command
+            .restart_policy()?
+            .decide(true, failures.count)
+        {
+            Decision::Ignore => WorkloadState::Failed,
+            Decision::Restart(backoff) if failures.deployed.elapsed() >= backoff => {
+                failures.deployed = Instant::now();
+                let invocation = invocation(Action::Restart, &workload)?;
+
+                match runner.run(&invocation.command_line(), &mut |_| true)?.code {
+                    Some(0) => WorkloadState::Running,
+                    _ => WorkloadState::Failed,
+                }
+            }
+            Decision::Restart(_) => WorkloadState::Pending,
+            Decision::Reschedule => {
+                self.failures.remove(&workload.name);
+                report.reschedule = true;
+                WorkloadState::Pending
+            }
+        };
+
+        Ok(Some(report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Kind, SystemdKind};
+    use crate::manifest::Manifest;
+    use crate::protocol::ErrorCode;
+    use std::net::TcpListener;
+
+    fn serve(response: &'static str) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?.to_string();
+
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        Ok(addr)
+    }
+
+    fn closed() -> Result<String> {
+        Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string())
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let kind = Kind::Systemd(SystemdKind::NSpawn);
+
+        let table = vec![
+            (
+                HealthCheck::Tcp(serve("")?),
+                true,
+                "tcp connect to a listener",
+            ),
+            (HealthCheck::Tcp(closed()?), false, "tcp connect refused"),
+            (
+                HealthCheck::Http(format!(
+                    "http://{}/healthz",
+                    serve("HTTP/1.0 200 OK\r\n\r\nok")?
+                )),
+                true,
+                "http ok",
+            ),
+            (
+                HealthCheck::Http(format!(
+                    "http://{}",
+                    serve("HTTP/1.1 503 Service Unavailable\r\n\r\n")?
+                )),
+                false,
+                "http server error",
+            ),
+            (
+                HealthCheck::Http(format!("http://{}/", serve("garbage")?)),
+                false,
+                "invalid http response",
+            ),
+            (
+                HealthCheck::Http(format!("http://{}/", closed()?)),
+                false,
+                "http connect refused",
+            ),
+        ];
+
+        let workload = WorkloadStatus {
+            name: "foo".to_string(),
+            kind,
+            image: "nginx".to_string(),
+            state: WorkloadState::Running,
+            node: None,
+            tags: Default::default(),
+            health: None,
+        };
+
+        for (health_check, healthy, annotation) in table {
+            let health = check(&health_check, &workload, TIMEOUT);
+            assert_eq!(
+                health == Health::Healthy,
+                healthy,
+                "{}: {:?}",
+                annotation,
+                health
+            );
+        }
+
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Runner for Recorder {
+        fn run(
+            &mut self,
+            argv: &[String],
+            _f: &mut dyn FnMut(crate::protocol::OutputChunk) -> bool,
+        ) -> Result<crate::protocol::ExecResult> {
+            self.0.push(argv.join(" "));
+            Ok(crate::protocol::ExecResult { code: Some(0) })
+        }
+    }
+
+    #[test]
+    fn test_monitor() -> Result<()> {
+        let key = Key::generate()?;
+        let addr = closed()?;
+        let manifest = Manifest::from_io(
+            r#"
+location:
+  kind: systemd
+  filter: {}
+commands:
+  - name: db
+    command: schedule
+    args:
+      kind: nspawn
+      image: postgres
+      health-tcp: secret://db-health
+      restart: on-failure
+      restart-backoff: "0"
+      restart-threshold: "2"
+"#
+            .as_bytes(),
+        )?;
+
+        let mut assignment = Assignment {
+            workload: WorkloadStatus {
+                name: "db-r1".to_string(),
+                kind: Kind::Systemd(SystemdKind::NSpawn),
+                image: "postgres".to_string(),
+                state: WorkloadState::Running,
+                node: Some("node1".to_string()),
+                tags: Default::default(),
+                health: None,
+            },
+            command: manifest.commands()[0].clone(),
+            secrets: [("db-health".to_string(), key.seal("db-health", &addr)?)].into(),
+        };
+
+        let mut monitor = Monitor::default();
+        let mut runner = Recorder::default();
+
+        let report = monitor.check(&assignment, &key, &mut runner)?.unwrap();
+        match &report.health {
+            Health::Unhealthy(message) => {
+                assert!(message.contains("secret://db-health"), "{}", message);
+                assert!(!message.contains(&addr), "{}", message);
+            }
+            x => panic!("unexpected health {:?}", x),
+        }
+        assert_eq!(report.state, WorkloadState::Running, "restarted in place");
+        assert!(!report.reschedule);
+        assert_eq!(runner.0, vec!["machinectl reboot db-r1"]);
+
+        let report = monitor.check(&assignment, &key, &mut runner)?.unwrap();
+        assert_eq!(report.state, WorkloadState::Pending);
+        assert!(report.reschedule);
+        assert_eq!(runner.0.len(), 1);
+
+        assignment.secrets =
+            [("db-health".to_string(), key.seal("db-health", &serve("")?)?)].into();
+        let report = monitor.check(&assignment, &key, &mut runner)?.unwrap();
+        assert_eq!(report.health, Health::Healthy);
+        assert_eq!(report.state, WorkloadState::Running);
+
+        assert_eq!(
+            ErrorCode::of(
+                &monitor
+                    .check(&assignment, &Key::generate()?, &mut runner)
+                    .unwrap_err()
+            ),
+            ErrorCode::PermissionDenied
+        );
+
+        assignment.command =
+            Manifest::from_file(std::path::Path::new("testdata/resources-one.yaml"))?.commands()[1]
+                .clone();
+        assert!(monitor.check(&assignment, &key, &mut runner)?.is_none());
+
+        Ok(())
+    }
+}
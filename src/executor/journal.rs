@@ -0,0 +1,316 @@
+use super::{unit_name, Invocation, Runner};
+use crate::common::*;
+use crate::protocol::{LogEntry, OutputStream, Payload, Response};
+use crate::protocol_error;
+use anyhow::{anyhow, Result};
+
+pub const PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogQuery {
+    pub unit: String,
+    pub since: Option<String>,
+    pub lines: Option<u64>,
+    pub follow: bool,
+}
+
+impl LogQuery {
+    pub fn new(
+        name: &str,
+        kind: &Kind,
+        since: Option<String>,
+        lines: Option<u64>,
+        follow: bool,
+    ) -> Result<Self> {
+        let unit = match kind {
+            Kind::Systemd(kind) => unit_name(name, kind),
+            Kind::Other => {
+                return Err(protocol_error!(
+                    Unsupported,
+                    "cannot read logs for workload '{}' of kind '{}'",
+                    name,
+                    kind
+                ))
+            }
+        };
+
+        Ok(Self {
+            unit,
+            since,
+            lines,
+            follow,
+        })
+    }
+}
+
+pub trait JournalReader {
+    fn read(&mut self, query: &LogQuery, f: &mut dyn FnMut(LogEntry) -> bool) -> Result<()>;
+}
+
+pub fn parse_entry(line: &str) -> Result<LogEntry> {
+    let value: serde_json::Value = serde_json::from_str(line)?;
+
+    let micros = value
+        .get("__REALTIME_TIMESTAMP")
+        .and_then(|x| x.as_str())
+        .and_then(|x| x.parse::<i64>().ok())
+        .ok_or_else(|| anyhow!("journal entry has no valid timestamp"))?;
+
+    let timestamp = chrono::DateTime::from_timestamp_micros(micros)
+        .ok_or_else(|| anyhow!("journal timestamp {} is out of range", micros))?
+        .naive_utc();
+
+    let message = match value.get("MESSAGE") {
+        Some(serde_json::Value::String(message)) => message.clone(),
+        Some(serde_json::Value::Array(bytes)) => String::from_utf8_lossy(
+            &bytes
+                .iter()
+                .filter_map(|x| x.as_u64().map(|x| x as u8))
+                .collect::<Vec<u8>>(),
+        )
+        .to_string(),
+        _ => String::new(),
+    };
+
+    Ok(LogEntry { timestamp, message })
+}
+
+// reads the journal of the node a workload runs on.
+pub struct Journalctl<'a> {
+    node: Option<String>,
+    runner: &'a mut dyn Runner,
+}
+
+impl<'a> Journalctl<'a> {
+    pub fn new(node: Option<String>, runner: &'a mut dyn Runner) -> Self {
+        Self { node, runner }
+    }
+
+    pub fn argv(query: &LogQuery) -> Vec<String> {
+        let mut argv = vec![
+            "journalctl".to_string(),
+            "--output=json".to_string(),
+            "--no-pager".to_string(),
+            format!("--unit={}", query.unit),
+        ];
+
+        if let Some(since) = &query.since {
+            argv.push(format!("--since={}", since));
+        }
+
+        if let Some(lines) = query.lines {
+            argv.push(format!("--lines={}", lines));
+        }
+
+        if query.follow {
+            argv.push("--follow".to_string());
+        }
+
+        argv
+    }
+}
+
+impl JournalReader for Journalctl<'_> {
+    fn read(&mut self, query: &LogQuery, f: &mut dyn FnMut(LogEntry) -> bool) -> Result<()> {
+        let invocation = Invocation {
+            node: self.node.clone(),
+            argv: Self::argv(query),
+        };
+        let mut error = None;
+
+        let result = self.runner.run(&invocation.command_line(), &mut |chunk| {
+            if chunk.stream != OutputStream::Stdout {
+                return true;
+            }
+
+            match parse_entry(&chunk.data) {
+                Ok(entry) => f(entry),
+                Err(e) => {
+                    error = Some(e);
+                    false
+                }
+            }
+        })?;
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        match result.code {
+            Some(0) | None => Ok(()),
+            Some(code) => Err(protocol_error!(
+                Execution,
+                "journalctl exited with status {}",
+                code
+            )),
+        }
+    }
+}
+
+pub fn stream(
+    reader: &mut dyn JournalReader,
+    query: &LogQuery,
+    page_size: usize,
+    send: &mut dyn FnMut(Response) -> bool,
+) -> Result<Response> {
+    let page_size = if query.follow { 1 } else { page_size.max(1) };
+    let mut page = Vec::new();
+
+    reader.read(query, &mut |entry| {
+        page.push(entry);
+
+        if page.len() < page_size {
+            return true;
+        }
+
+        send(Response::partial(Payload::Logs(std::mem::take(&mut page))))
+    })?;
+
+    Ok(Response::ok(Payload::Logs(page)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    struct CannedJournal(Vec<LogEntry>);
+
+    impl JournalReader for CannedJournal {
+        fn read(&mut self, query: &LogQuery, f: &mut dyn FnMut(LogEntry) -> bool) -> Result<()> {
+            let skip = query
+                .lines
+                .map_or(0, |x| self.0.len().saturating_sub(x as usize));
+
+            for entry in self.0.iter().skip(skip) {
+                if !f(entry.clone()) {
+                    break;
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    fn canned(count: usize) -> CannedJournal {
+        CannedJournal(
+            (0..count)
+                .map(|x| LogEntry {
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    message: format!("line {}", x),
+                })
+                .collect(),
+        )
+    }
+
+    fn messages(payload: &Payload) -> Vec<String> {
+        match payload {
+            Payload::Logs(entries) => entries.iter().map(|x| x.message.clone()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_query() -> Result<()> {
+        let query = LogQuery::new(
+            "foo",
+            &Kind::Systemd(SystemdKind::NSpawn),
+            Some("-1h".to_string()),
+            Some(10),
+            true,
+        )?;
+
+        assert_eq!(
+            Journalctl::argv(&query),
+            vec![
+                "journalctl",
+                "--output=json",
+                "--no-pager",
+                "--unit=systemd-nspawn@foo.service",
+                "--since=-1h",
+                "--lines=10",
+                "--follow",
+            ]
+        );
+
+        let query = LogQuery::new(
+            "foo",
+            &Kind::Systemd(SystemdKind::Service),
+            None,
+            None,
+            false,
+        )?;
+        assert_eq!(Journalctl::argv(&query).len(), 4);
+        assert!(LogQuery::new("foo", &Kind::Other, None, None, false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_entry() -> Result<()> {
+        let entry = parse_entry(
+            r#"{"__REALTIME_TIMESTAMP":"1700000000123456","MESSAGE":"hello","_PID":"1"}"#,
+        )?;
+        assert_eq!(entry.message, "hello");
+        assert_eq!(
+            entry.timestamp,
+            chrono::DateTime::from_timestamp(1700000000, 123456000)
+                .unwrap()
+                .naive_utc()
+        );
+
+        let entry = parse_entry(r#"{"__REALTIME_TIMESTAMP":"0","MESSAGE":[104,105]}"#)?;
+        assert_eq!(entry.message, "hi");
+
+        assert!(parse_entry(r#"{"MESSAGE":"hello"}"#).is_err());
+        assert!(parse_entry("garbage").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream() -> Result<()> {
+        let query = LogQuery::new(
+            "foo",
+            &Kind::Systemd(SystemdKind::Service),
+            None,
+            None,
+            false,
+        )?;
+
+        let mut pages = Vec::new();
+        let response = stream(&mut canned(5), &query, 2, &mut |response| {
+            assert!(response.more);
+            pages.push(messages(&response.payload));
+            true
+        })?;
+        assert_eq!(
+            pages,
+            vec![vec!["line 0", "line 1"], vec!["line 2", "line 3"]]
+        );
+        assert!(!response.more);
+        assert_eq!(messages(&response.payload), vec!["line 4"]);
+
+        let query = LogQuery {
+            lines: Some(2),
+            ..query
+        };
+        let response = stream(&mut canned(5), &query, PAGE_SIZE, &mut |_| true)?;
+        assert_eq!(messages(&response.payload), vec!["line 3", "line 4"]);
+
+        let query = LogQuery {
+            lines: None,
+            follow: true,
+            ..query
+        };
+        let mut count = 0;
+        let response = stream(&mut canned(5), &query, PAGE_SIZE, &mut |_| {
+            count += 1;
+            count < 3
+        })?;
+        assert_eq!(count, 3);
+        assert!(messages(&response.payload).is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+pub mod exec;
+pub mod health;
+pub mod journal;
+
+use crate::common::*;
+use crate::protocol::{Command, ExecResult, OutputChunk, WorkloadStatus};
+use crate::protocol_error;
+use anyhow::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Start,
+    Stop,
+    Restart,
+    Terminate,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Start => "start",
+            Self::Stop => "stop",
+            Self::Restart => "restart",
+            Self::Terminate => "terminate",
+        })
+    }
+}
+
+impl Action {
+    pub fn of(command: &Command) -> Option<Self> {
+        match command {
+            Command::Start(_) => Some(Self::Start),
+            Command::Stop(_) => Some(Self::Stop),
+            Command::Restart(_) => Some(Self::Restart),
+            Command::Terminate(..) => Some(Self::Terminate),
+            _ => None,
+        }
+    }
+
+    fn machinectl(&self) -> &'static str {
+        match self {
+            Self::Start => "start",
+            Self::Stop => "poweroff",
+            Self::Restart => "reboot",
+            Self::Terminate => "terminate",
+        }
+    }
+
+    fn systemctl(&self) -> &'static [&'static str] {
+        match self {
+            Self::Start => &["start"],
+            Self::Stop => &["stop"],
+            Self::Restart => &["restart"],
+            Self::Terminate => &["disable", "--now"],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub node: Option<String>,
+    pub argv: Vec<String>,
+}
+
+impl Invocation {
+    // the argv to run locally; invocations for another node go through ssh, which
+    // hands the remote shell a single command line, so each argument is quoted.
+    pub fn command_line(&self) -> Vec<String> {
+        match &self.node {
+            Some(node) => vec![
+                "ssh".to_string(),
+                node.clone(),
+                "--".to_string(),
+                self.argv
+                    .iter()
+                    .map(|x| shell_quote(x))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ],
+            None => self.argv.clone(),
+        }
+    }
+}
+
+// runs the command line of an invocation, streaming its output to f until f
+// returns false.
+pub trait Runner {
+    fn run(
+        &mut self,
+        argv: &[String],
+        f: &mut dyn FnMut(OutputChunk) -> bool,
+    ) -> Result<ExecResult>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Local;
+
+impl Runner for Local {
+    fn run(
+        &mut self,
+        argv: &[String],
+        f: &mut dyn FnMut(OutputChunk) -> bool,
+    ) -> Result<ExecResult> {
+        exec::run(argv, f)
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', r#"'\''"#))
+    }
+}
+
+pub fn unit_name(name: &str, kind: &SystemdKind) -> String {
+    match kind {
+        SystemdKind::NSpawn => format!("systemd-nspawn@{}.service", name),
+        SystemdKind::Machine => format!("machine-{}.scope", name),
+        SystemdKind::Timer => format!("{}.timer", name),
+        SystemdKind::OneShot | SystemdKind::Service => format!("{}.service", name),
+    }
+}
+
+pub fn argv(action: Action, name: &str, kind: &Kind) -> Result<Vec<String>> {
+    let argv = match kind {
+        Kind::Systemd(SystemdKind::NSpawn) | Kind::Systemd(SystemdKind::Machine) => {
+            vec!["machinectl", action.machinectl(), name]
+                .into_iter()
+                .map(|x| x.to_string())
+                .collect()
+        }
+        Kind::Systemd(kind) => {
+            let mut argv = vec!["systemctl".to_string()];
+            argv.extend(action.systemctl().iter().map(|x| x.to_string()));
+            argv.push(unit_name(name, kind));
+            argv
+        }
+        Kind::Other => {
+            return Err(protocol_error!(
+                Unsupported,
+                "cannot {} workload '{}' of kind '{}'",
+                action,
+                name,
+                kind
+            ))
+        }
+    };
+
+    Ok(argv)
+}
+
+pub fn invocation(action: Action, workload: &WorkloadStatus) -> Result<Invocation> {
+    Ok(Invocation {
+        node: workload.node.clone(),
+        argv: argv(action, &workload.name, &workload.kind)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ErrorCode, Instruction, WorkloadState};
+
+    #[test]
+    fn test_argv() -> Result<()> {
+        let table = vec![
+            (
+                Action::Start,
+                Kind::Systemd(SystemdKind::NSpawn),
+                vec!["machinectl", "start", "foo"],
+            ),
+            (
+                Action::Stop,
+                Kind::Systemd(SystemdKind::NSpawn),
+                vec!["machinectl", "poweroff", "foo"],
+            ),
+            (
+                Action::Restart,
+                Kind::Systemd(SystemdKind::Machine),
+                vec!["machinectl", "reboot", "foo"],
+            ),
+            (
+                Action::Terminate,
+                Kind::Systemd(SystemdKind::NSpawn),
+                vec!["machinectl", "terminate", "foo"],
+            ),
+            (
+                Action::Start,
+                Kind::Systemd(SystemdKind::Service),
+                vec!["systemctl", "start", "foo.service"],
+            ),
+            (
+                Action::Stop,
+                Kind::Systemd(SystemdKind::OneShot),
+                vec!["systemctl", "stop", "foo.service"],
+            ),
+            (
+                Action::Restart,
+                Kind::Systemd(SystemdKind::Timer),
+                vec!["systemctl", "restart", "foo.timer"],
+            ),
+            (
+                Action::Terminate,
+                Kind::Systemd(SystemdKind::Service),
+                vec!["systemctl", "disable", "--now", "foo.service"],
+            ),
+        ];
+
+        for (action, kind, result) in table {
+            assert_eq!(argv(action, "foo", &kind)?, result, "{} {}", action, kind);
+        }
+
+        assert_eq!(
+            ErrorCode::of(&argv(Action::Restart, "foo", &Kind::Other).unwrap_err()),
+            ErrorCode::Unsupported
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invocation() -> Result<()> {
+        let workload = WorkloadStatus {
+            name: "foo".to_string(),
+            kind: Kind::Systemd(SystemdKind::NSpawn),
+            image: "linux".to_string(),
+            state: WorkloadState::Failed,
+            node: Some("node1".to_string()),
+            tags: Default::default(),
+            health: None,
+        };
+
+        let instruction: Instruction = r#"restart name="foo""#.parse()?;
+        let action = Action::of(&instruction.command).unwrap();
+        assert_eq!(
+            invocation(action, &workload)?,
+            Invocation {
+                node: Some("node1".to_string()),
+                argv: vec![
+                    "machinectl".to_string(),
+                    "reboot".to_string(),
+                    "foo".to_string()
+                ],
+            }
+        );
+
+        assert!(Action::of(&"status".parse::<Instruction>()?.command).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_line() -> Result<()> {
+        let argv = vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "echo 'hi' $HOME".to_string(),
+        ];
+
+        let table = vec![
+            (None, argv.clone(), "local"),
+            (
+                Some("node1".to_string()),
+                vec![
+                    "ssh".to_string(),
+                    "node1".to_string(),
+                    "--".to_string(),
+                    r#"/bin/sh -c 'echo '\''hi'\'' $HOME'"#.to_string(),
+                ],
+                "remote",
+            ),
+        ];
+
+        for (node, result, annotation) in table {
+            let invocation = Invocation {
+                node,
+                argv: argv.clone(),
+            };
+            assert_eq!(invocation.command_line(), result, "{}", annotation);
+        }
+
+        assert_eq!(shell_quote(""), "''");
+
+        Ok(())
+    }
+}
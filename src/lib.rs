@@ -1,14 +1,18 @@
 pub mod common;
 pub mod db;
+pub mod dispatcher;
+pub mod executor;
 pub mod manifest;
+pub mod planner;
 pub mod protocol;
+pub mod secrets;
 pub mod transports;
 
 #[cfg(test)]
 pub(crate) mod testdata {
     use crate::common::*;
     use crate::protocol::*;
-    use chrono::NaiveDateTime;
+    use chrono::DateTime;
     use std::collections::HashMap;
 
     fn make_map(set: Vec<String>) -> HashMap<String, String> {
@@ -47,24 +51,141 @@ pub(crate) mod testdata {
                 Response {
                     status: true,
                     error: None,
-                    timestamp: NaiveDateTime::UNIX_EPOCH,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
                     payload: Default::default(),
+                    more: false,
                 },
                 "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
                 "basic response".into(),
             ),
             (
-                "terminate name=\"terminate-test\" tags=\"four=quux,three=baz\"".into(),
+                "terminate name=\"terminate-test\"".into(),
                 Instruction {
-                    command: Command::Terminate("terminate-test".to_string()),
-                    tags: TAG_SETS[1].clone(),
+                    command: Command::Terminate(Some("terminate-test".to_string()), false),
+                    tags: std::collections::HashMap::default(),
                 },
                 "terminate test".into(),
                 Response {
                     status: true,
                     error: None,
-                    timestamp: NaiveDateTime::UNIX_EPOCH,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "terminate all=\"true\" tags=\"five=frobnik\"".into(),
+                Instruction {
+                    command: Command::Terminate(None, true),
+                    tags: TAG_SETS[2].clone(),
+                },
+                "terminate test by tags".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "restart name=\"restart-test\"".into(),
+                Instruction {
+                    command: Command::Restart("restart-test".to_string()),
+                    tags: std::collections::HashMap::default(),
+                },
+                "restart test".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "exec name=\"exec-test\" cmd=\"ps auxw\"".into(),
+                Instruction {
+                    command: Command::Exec("exec-test".to_string(), "ps auxw".to_string()),
+                    tags: std::collections::HashMap::default(),
+                },
+                "exec test".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "logs name=\"logs-test\" since=\"-1h\" lines=\"100\" follow=\"true\"".into(),
+                Instruction {
+                    command: Command::Logs(
+                        "logs-test".to_string(),
+                        Some("-1h".to_string()),
+                        Some(100),
+                        true,
+                    ),
+                    tags: std::collections::HashMap::default(),
+                },
+                "logs test".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "rollback revision=\"3\" schedule=\"12\"".into(),
+                Instruction {
+                    command: Command::Rollback(3, Some(12)),
+                    tags: std::collections::HashMap::default(),
+                },
+                "rollback test".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
                     payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "scale name=\"web\" replicas=\"4\"".into(),
+                Instruction {
+                    command: Command::Scale("web".into(), 4, None),
+                    tags: std::collections::HashMap::default(),
+                },
+                "scale test".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
                 },
                 "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
                 "basic response".into(),
@@ -79,8 +200,10 @@ pub(crate) mod testdata {
                 Response {
                     status: true,
                     error: None,
-                    timestamp: NaiveDateTime::UNIX_EPOCH,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
                     payload: Default::default(),
+                    more: false,
                 },
                 "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
                 "basic response".into(),
@@ -95,8 +218,64 @@ pub(crate) mod testdata {
                 Response {
                     status: true,
                     error: None,
-                    timestamp: NaiveDateTime::UNIX_EPOCH,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "quota user=\"erikh\"".into(),
+                Instruction {
+                    command: Command::Quota(Some("erikh".to_string())),
+                    tags: std::collections::HashMap::default(),
+                },
+                "quota test w/ user".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "quota".into(),
+                Instruction {
+                    command: Command::Quota(None),
+                    tags: std::collections::HashMap::default(),
+                },
+                "quota test w/o user".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
                     payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
+                "basic response".into(),
+            ),
+            (
+                "watch name=\"foo\" since=\"42\" tags=\"five=frobnik\"".into(),
+                Instruction {
+                    command: Command::Watch(Some("foo".to_string()), Some(42)),
+                    tags: TAG_SETS[2].clone(),
+                },
+                "watch test".into(),
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
                 },
                 "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}".into(),
                 "basic response".into(),
@@ -113,6 +292,37 @@ pub(crate) mod testdata {
                 "schedule kind=\"nspawn\" image=\"linux\"".into(),
                 "schedule without name key".into(),
             ),
+            ("quota name=\"blah\"".into(), "quota with name key".into()),
+            (
+                "schedule name=\"a\" garbage image=\"b\" kind=\"nspawn\"".into(),
+                "schedule with stray token".into(),
+            ),
+            (
+                "schedule name=\"a\" image=\"b\" kind=\"nspawn\" image=\"c\"".into(),
+                "schedule with duplicate key".into(),
+            ),
+            (
+                "status name=\"a\" NAME=\"b\"".into(),
+                "status with wrong-case duplicate key".into(),
+            ),
+            (
+                "terminate name=\"a\" image=\"b\"".into(),
+                "terminate with unknown key".into(),
+            ),
+            ("watch since=\"soon\"".into(), "watch with non-numeric since".into()),
+            ("stop".into(), "stop with no keys".into()),
+            (
+                "terminate tags=\"team=infra\"".into(),
+                "terminate by tags without all".into(),
+            ),
+            (
+                "terminate tags=\"\" all=\"true\"".into(),
+                "terminate with empty tags".into(),
+            ),
+            (
+                "terminate name=\"a\" all=\"true\"".into(),
+                "terminate by name with all".into(),
+            ),
         ];
     }
 }
@@ -0,0 +1,179 @@
+use super::Manifest;
+use crate::protocol_error;
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "yaml" | "yml" => Ok(Self::Yaml),
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            _ => Err(protocol_error!(
+                Unsupported,
+                "unsupported manifest format '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Yaml => "yaml",
+            Self::Json => "json",
+            Self::Toml => "toml",
+        })
+    }
+}
+
+impl Format {
+    pub fn detect(filename: &Path) -> Option<Self> {
+        filename
+            .extension()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse().ok())
+    }
+
+    pub fn of(filename: &Path) -> Self {
+        Self::detect(filename).unwrap_or_default()
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, text: &str) -> Result<T> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::from_str(text)?,
+            Self::Json => serde_json::from_str(text)?,
+            Self::Toml => toml::from_str(text)?,
+        })
+    }
+
+    pub fn write(self, manifest: &Manifest) -> Result<String> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::to_string(manifest)?,
+            Self::Json => serde_json::to_string_pretty(manifest)? + "\n",
+            Self::Toml => toml::to_string(manifest)?,
+        })
+    }
+}
+
+pub fn convert(text: &str, from: Format, to: Format) -> Result<String> {
+    to.write(&Manifest::from_io_as(text.as_bytes(), from)?)
+}
+
+pub fn convert_file(input: &Path, output: &Path) -> Result<()> {
+    Manifest::from_file(input)?.to_file(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTDATA: &[&str] = &[
+        "testdata/combined-one.yaml",
+        "testdata/resources-one.yaml",
+        "testdata/resources-two.yaml",
+        "testdata/replicas-one.yaml",
+        "testdata/health-one.yaml",
+    ];
+
+    const FORMATS: &[Format] = &[Format::Yaml, Format::Json, Format::Toml];
+
+    fn scratch(name: &str, format: Format) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "dao-format-{}-{}-{}.{}",
+            std::process::id(),
+            name.replace('/', "-"),
+            format,
+            format
+        ))
+    }
+
+    #[test]
+    fn test_detect() {
+        let table = vec![
+            ("manifest.yaml", Some(Format::Yaml)),
+            ("manifest.yml", Some(Format::Yaml)),
+            ("manifest.json", Some(Format::Json)),
+            ("manifest.toml", Some(Format::Toml)),
+            ("manifest.txt", None),
+            ("manifest", None),
+        ];
+
+        for (filename, format) in table {
+            assert_eq!(Format::detect(Path::new(filename)), format, "{}", filename);
+        }
+
+        assert_eq!(Format::of(Path::new("manifest")), Format::Yaml);
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        for file in TESTDATA {
+            let manifest = Manifest::from_file(Path::new(file))?;
+
+            for format in FORMATS {
+                let text = format.write(&manifest)?;
+                assert_eq!(
+                    format.parse::<Manifest>(&text)?,
+                    manifest,
+                    "{} as {}",
+                    file,
+                    format
+                );
+
+                let path = scratch(file, *format);
+                manifest.to_file(&path)?;
+                let result = Manifest::from_file(&path);
+                std::fs::remove_file(&path)?;
+                assert_eq!(result?, manifest, "{} via {}", file, path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert() -> Result<()> {
+        let yaml = std::fs::read_to_string("testdata/combined-one.yaml")?;
+        let manifest = Manifest::from_io(yaml.as_bytes())?;
+
+        let json = convert(&yaml, Format::Yaml, Format::Json)?;
+        assert!(json.starts_with('{'));
+        let toml = convert(&json, Format::Json, Format::Toml)?;
+        assert!(toml.contains("[[commands]]"));
+        let back = convert(&toml, Format::Toml, Format::Yaml)?;
+        assert_eq!(Manifest::from_io(back.as_bytes())?, manifest);
+
+        let duplicate = json.replace("\"foo-network\"", "\"foo\"");
+        assert!(convert(&duplicate, Format::Json, Format::Yaml)
+            .unwrap_err()
+            .to_string()
+            .contains("duplicate command name 'foo'"));
+        assert!(convert("{\"commands\": []}", Format::Json, Format::Yaml)
+            .unwrap_err()
+            .to_string()
+            .contains("manifest has no location"));
+
+        let output = scratch("convert", Format::Toml);
+        convert_file(Path::new("testdata/combined-one.yaml"), &output)?;
+        let result = Manifest::from_file(&output);
+        std::fs::remove_file(&output)?;
+        assert_eq!(result?, manifest);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,582 @@
+use super::format::Format;
+use super::template::{self, Values};
+use super::validate::{Position, Problem};
+use super::{Location, Manifest, SchedulingCommand, SchedulingDocument};
+use crate::protocol_error;
+use anyhow::Result;
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Origin {
+    pub file: Option<PathBuf>,
+    pub position: Option<Position>,
+    pub args: BTreeMap<String, Position>,
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, &self.position) {
+            (Some(file), Some(position)) => write!(f, "{}: {}", file.display(), position),
+            (Some(file), None) => write!(f, "{}", file.display()),
+            (None, Some(position)) => write!(f, "{}", position),
+            (None, None) => f.write_str("an unknown location"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Loaded {
+    pub manifest: Manifest,
+    pub origins: Vec<Origin>,
+    pub problems: Vec<Problem>,
+}
+
+impl Loaded {
+    pub fn into_manifest(self) -> Result<Manifest> {
+        if self.problems.is_empty() {
+            return Ok(self.manifest);
+        }
+
+        Err(protocol_error!(
+            InvalidArgument,
+            "{}",
+            self.problems
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct Document {
+    location: Option<Location>,
+    #[serde(default)]
+    commands: Vec<SchedulingCommand>,
+    #[serde(default)]
+    include: Vec<String>,
+}
+
+const FOUND: &str = "the located node";
+
+#[derive(Debug, Clone, Copy)]
+enum Step<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+// walks one document of a YAML stream along a path of keys and sequence indexes and
+// fails on purpose at the node it ends on, so that serde_yaml reports where that
+// node starts. a path ending in a key locates the key itself.
+struct Locator<'a> {
+    text: &'a str,
+    document: usize,
+}
+
+impl Locator<'_> {
+    fn locate(&self, path: &[Step]) -> Option<Position> {
+        let document = serde_yaml::Deserializer::from_str(self.text).nth(self.document)?;
+
+        match Probe(path).deserialize(document) {
+            Err(e) if e.to_string().contains(FOUND) => e.location().map(|x| Position {
+                line: x.line(),
+                column: x.column(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+struct Found;
+
+impl<'de> Visitor<'de> for Found {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(FOUND)
+    }
+}
+
+struct Key<'a> {
+    key: &'a str,
+    last: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for Key<'_> {
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Key<'_> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a mapping key")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<bool, E> {
+        match v == self.key {
+            true if self.last => Err(E::custom(FOUND)),
+            matched => Ok(matched),
+        }
+    }
+}
+
+struct Probe<'a>(&'a [Step<'a>]);
+
+impl<'de> DeserializeSeed<'de> for Probe<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        match self.0 {
+            [] => deserializer.deserialize_any(Found),
+            _ => deserializer.deserialize_any(self),
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for Probe<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("any node")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        if let [Step::Key(key), rest @ ..] = self.0 {
+            let last = rest.is_empty();
+
+            while let Some(matched) = map.next_key_seed(Key { key, last })? {
+                if matched {
+                    return map.next_value_seed(Probe(rest));
+                }
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        if let [Step::Index(index), rest @ ..] = self.0 {
+            for _ in 0..*index {
+                if seq.next_element::<IgnoredAny>()?.is_none() {
+                    return Ok(());
+                }
+            }
+
+            seq.next_element_seed(Probe(rest))?;
+        }
+
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Loader {
+    location: Option<(Location, Origin)>,
+    commands: Vec<SchedulingCommand>,
+    origins: Vec<Origin>,
+    stack: Vec<PathBuf>,
+    values: Option<Values>,
+}
+
+impl Loader {
+    fn path(&mut self, path: &Path) -> Result<()> {
+        if !path.is_dir() {
+            return self.file(path);
+        }
+
+        let mut files = std::fs::read_dir(path)?
+            .map(|x| x.map(|x| x.path()))
+            .collect::<std::io::Result<Vec<PathBuf>>>()?;
+        files.retain(|x| x.is_file() && Format::detect(x).is_some());
+        files.sort();
+
+        for file in files {
+            self.file(&file)?;
+        }
+
+        Ok(())
+    }
+
+    fn file(&mut self, path: &Path) -> Result<()> {
+        let canonical = path.canonicalize().map_err(|e| {
+            protocol_error!(
+                NotFound,
+                "could not read manifest '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+
+        if self.stack.contains(&canonical) {
+            return Err(protocol_error!(
+                InvalidArgument,
+                "include cycle detected at manifest '{}'",
+                path.display()
+            ));
+        }
+
+        self.stack.push(canonical);
+        let result = std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| self.text(&text, Some(path), Format::of(path)));
+        self.stack.pop();
+
+        result
+    }
+
+    fn text(&mut self, text: &str, file: Option<&Path>, format: Format) -> Result<()> {
+        let prefix = file.map_or_else(String::new, |x| format!("{}: ", x.display()));
+
+        let text = match &self.values {
+            Some(values) => template::render(text, values).map_err(|e| {
+                protocol_error!(
+                    InvalidArgument,
+                    "{}",
+                    e.to_string()
+                        .lines()
+                        .map(|x| format!("{}{}", prefix, x))
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                )
+            })?,
+            None => text.to_string(),
+        };
+
+        if format != Format::Yaml {
+            let document = format
+                .parse(&text)
+                .map_err(|e| protocol_error!(InvalidArgument, "{}{}", prefix, e))?;
+
+            return self.document(document, None, file);
+        }
+
+        for (index, document) in serde_yaml::Deserializer::from_str(&text).enumerate() {
+            let document = Option::<Document>::deserialize(document).map_err(|e| {
+                let position = e.location().map_or_else(String::new, |x| {
+                    format!(
+                        "{}: ",
+                        Position {
+                            line: x.line(),
+                            column: x.column(),
+                        }
+                    )
+                });
+
+                protocol_error!(InvalidArgument, "{}{}{}", prefix, position, e)
+            })?;
+
+            if let Some(document) = document {
+                let locator = Locator {
+                    text: &text,
+                    document: index,
+                };
+                self.document(document, Some(&locator), file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn document(
+        &mut self,
+        document: Document,
+        locator: Option<&Locator>,
+        file: Option<&Path>,
+    ) -> Result<()> {
+        let locate = |path: &[Step]| locator.and_then(|x| x.locate(path));
+
+        if let Some(location) = document.location {
+            let origin = Origin {
+                file: file.map(Path::to_path_buf),
+                position: locate(&[Step::Key("location")]),
+                args: Default::default(),
+            };
+
+            match &self.location {
+                Some((existing, first)) if *existing != location => {
+                    return Err(protocol_error!(
+                        InvalidArgument,
+                        "location at {} conflicts with location at {}",
+                        origin,
+                        first
+                    ))
+                }
+                Some(_) => {}
+                None => self.location = Some((location, origin)),
+            }
+        }
+
+        for (index, command) in document.commands.into_iter().enumerate() {
+            let item = [Step::Key("commands"), Step::Index(index)];
+
+            self.origins.push(Origin {
+                file: file.map(Path::to_path_buf),
+                position: locate(&item),
+                args: command
+                    .args
+                    .keys()
+                    .filter_map(|key| {
+                        locate(&[item[0], item[1], Step::Key("args"), Step::Key(key)])
+                            .map(|x| (key.clone(), x))
+                    })
+                    .collect(),
+            });
+            self.commands.push(command);
+        }
+
+        let base = file
+            .and_then(Path::parent)
+            .unwrap_or_else(|| Path::new("."));
+
+        for include in document.include {
+            self.path(&base.join(include))?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Loaded> {
+        let (location, _) = self
+            .location
+            .ok_or_else(|| protocol_error!(InvalidArgument, "manifest has no location"))?;
+
+        let mut seen: HashMap<&str, &Origin> = HashMap::default();
+        let mut problems = Vec::new();
+
+        for (command, origin) in self.commands.iter().zip(&self.origins) {
+            if let Some(first) = seen.get(command.name.as_str()) {
+                problems.push(Problem {
+                    file: origin.file.clone(),
+                    position: origin.position,
+                    message: format!(
+                        "duplicate command name '{}', first defined at {}",
+                        command.name, first
+                    ),
+                });
+            } else {
+                seen.insert(&command.name, origin);
+            }
+        }
+
+        Ok(Loaded {
+            manifest: Manifest {
+                location,
+                commands: SchedulingDocument(self.commands),
+            },
+            origins: self.origins,
+            problems,
+        })
+    }
+}
+
+// manifests are only treated as templates when values are given for them, so a
+// literal ${...} in a plain manifest is left alone.
+fn load(values: Option<&Values>, f: impl FnOnce(&mut Loader) -> Result<()>) -> Result<Loaded> {
+    let mut loader = Loader {
+        values: values.cloned(),
+        ..Default::default()
+    };
+    f(&mut loader)?;
+    loader.finish()
+}
+
+pub fn read(path: &Path) -> Result<Loaded> {
+    load(None, |x| x.path(path))
+}
+
+pub fn read_with(path: &Path, values: &Values) -> Result<Loaded> {
+    load(Some(values), |x| x.path(path))
+}
+
+pub fn read_str(text: &str) -> Result<Loaded> {
+    read_str_as(text, Format::Yaml)
+}
+
+pub fn read_str_as(text: &str, format: Format) -> Result<Loaded> {
+    load(None, |x| x.text(text, None, format))
+}
+
+pub fn read_str_with(text: &str, values: &Values) -> Result<Loaded> {
+    load(Some(values), |x| x.text(text, None, Format::Yaml))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+
+    fn names(manifest: &Manifest) -> Vec<&str> {
+        manifest.commands().iter().map(|x| x.name()).collect()
+    }
+
+    #[test]
+    fn test_read() -> Result<()> {
+        let table = vec![
+            (
+                "testdata/multi",
+                vec!["baz", "foo-network", "foo", "bar"],
+                "directory",
+            ),
+            (
+                "testdata/multi/main.yaml",
+                vec!["foo-network", "foo", "bar"],
+                "file with includes",
+            ),
+        ];
+
+        for (path, commands, annotation) in table {
+            let loaded = read(Path::new(path))?;
+            assert!(loaded.problems.is_empty(), "{}", annotation);
+            assert_eq!(names(&loaded.manifest), commands, "{}", annotation);
+            loaded.manifest.validate()?;
+        }
+
+        let loaded = read(Path::new("testdata/multi/main.yaml"))?;
+        assert_eq!(
+            loaded.origins[2].to_string(),
+            "testdata/multi/services/web.yaml: line 9, column 5"
+        );
+        assert_eq!(
+            loaded.origins[2].args.get("image"),
+            Some(&Position {
+                line: 13,
+                column: 7
+            })
+        );
+
+        let manifest = Manifest::from_file(Path::new("testdata/multi"))?;
+        assert_eq!(names(&manifest), vec!["baz", "foo-network", "foo", "bar"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_errors() -> Result<()> {
+        let loaded = read(Path::new("testdata/duplicate"))?;
+        assert_eq!(
+            loaded
+                .problems
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>(),
+            vec!["testdata/duplicate/b.yaml: line 7, column 5: duplicate command name 'foo', first defined at testdata/duplicate/a.yaml: line 6, column 5"]
+        );
+
+        let err = Manifest::from_file(Path::new("testdata/duplicate")).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::InvalidArgument);
+        assert!(err.to_string().contains("duplicate command name 'foo'"));
+
+        let table = vec![
+            (
+                read(Path::new("testdata/cycle/a.yaml")).map(drop),
+                "include cycle detected at manifest 'testdata/cycle/a.yaml'",
+                ErrorCode::InvalidArgument,
+            ),
+            (
+                read(Path::new("testdata/multi/services/web.yaml")).map(drop),
+                "manifest has no location",
+                ErrorCode::InvalidArgument,
+            ),
+            (
+                read(Path::new("testdata/missing.yaml")).map(drop),
+                "could not read manifest 'testdata/missing.yaml'",
+                ErrorCode::NotFound,
+            ),
+            (
+                read_str(
+                    "location:\n  kind: systemd\n  filter: {}\n---\nlocation:\n  kind: systemd\n  filter:\n    datacenter: xo\n",
+                )
+                .map(drop),
+                "location at line 5, column 1 conflicts with location at line 1, column 1",
+                ErrorCode::InvalidArgument,
+            ),
+            (
+                read_str("location:\n  kind: systemd\n  filter: {}\n---\ncommands: [\n").map(drop),
+                "line 6, column 1",
+                ErrorCode::InvalidArgument,
+            ),
+        ];
+
+        for (result, message, code) in table {
+            let err = result.unwrap_err();
+            assert!(err.to_string().contains(message), "{}: {}", message, err);
+            assert_eq!(ErrorCode::of(&err), code, "{}", message);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_positions() -> Result<()> {
+        let loaded = read_str(
+            "location: {kind: systemd, filter: {}}\ncommands:\n- {name: foo, command: schedule,\n   args: {kind: nspawn, \"image\": nginx}}\n",
+        )?;
+
+        assert_eq!(loaded.origins[0].to_string(), "line 3, column 3");
+        assert_eq!(
+            loaded.origins[0].args.get("image"),
+            Some(&Position {
+                line: 4,
+                column: 25
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_stream() -> Result<()> {
+        let text = std::fs::read_to_string("testdata/multi/services/web.yaml")?;
+        let text = format!(
+            "location:\n  kind: systemd\n  filter:\n    datacenter: xo\n---\n{}",
+            text
+        );
+
+        let manifest = Manifest::from_io(text.as_bytes())?;
+        assert_eq!(names(&manifest), vec!["foo", "bar"]);
+
+        let loaded = read_str(
+            "--- {location: {kind: systemd, filter: {}}}\n...\n# nothing here\n---\n--- {commands: [{name: foo, command: schedule, args: {kind: nspawn, image: nginx}}]}\n",
+        )?;
+        assert_eq!(names(&loaded.manifest), vec!["foo"]);
+        assert_eq!(loaded.origins[0].to_string(), "line 5, column 17");
+
+        Ok(())
+    }
+}
@@ -1,8 +1,19 @@
+pub mod format;
+pub mod loader;
+pub mod schema;
+pub mod template;
+pub mod validate;
+
 use crate::common::*;
-use anyhow::Result;
+use crate::protocol_error;
+use crate::secrets;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::Path;
+use std::str::FromStr;
+
+pub const MAX_REPLICAS: u64 = 1024;
 
 #[derive(Debug, Clone, PartialEq, Eq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Manifest {
@@ -26,30 +37,594 @@ pub struct SchedulingCommand {
     args: BTreeMap<String, String>,
     #[serde(rename = "schedule-with")]
     schedule_with: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replicas: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    Rolling {
+        max_unavailable: u64,
+        max_surge: u64,
+    },
+    #[default]
+    Recreate,
+    BlueGreen,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthCheck {
+    Exec(String),
+    Tcp(String),
+    Http(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Restart {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Ignore,
+    Restart(std::time::Duration),
+    Reschedule,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    pub restart: Restart,
+    pub backoff: u64,
+    pub threshold: u32,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            restart: Restart::Never,
+            backoff: 1,
+            threshold: 5,
+        }
+    }
+}
+
+impl RestartPolicy {
+    const MAX_BACKOFF: u64 = 300;
+
+    pub fn decide(&self, failed: bool, failures: u32) -> Decision {
+        match self.restart {
+            Restart::Never => return Decision::Ignore,
+            Restart::OnFailure if !failed => return Decision::Ignore,
+            _ => {}
+        }
+
+        if failures >= self.threshold {
+            return Decision::Reschedule;
+        }
+
+        let backoff = self
+            .backoff
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(Self::MAX_BACKOFF);
+
+        Decision::Restart(std::time::Duration::from_secs(backoff))
+    }
 }
 
 impl Manifest {
     pub fn from_io(io: impl std::io::Read) -> Result<Self> {
-        Ok(serde_yaml::from_reader(io)?)
+        Self::from_io_as(io, format::Format::Yaml)
+    }
+
+    pub fn from_io_as(mut io: impl std::io::Read, format: format::Format) -> Result<Self> {
+        let mut text = String::new();
+        io.read_to_string(&mut text)?;
+
+        loader::read_str_as(&text, format)?.into_manifest()
     }
 
     pub fn to_io(&self, io: impl std::io::Write) -> Result<()> {
-        Ok(serde_yaml::to_writer(io, self)?)
+        self.to_io_as(io, format::Format::Yaml)
+    }
+
+    pub fn to_io_as(&self, mut io: impl std::io::Write, format: format::Format) -> Result<()> {
+        Ok(io.write_all(format.write(self)?.as_bytes())?)
+    }
+
+    pub fn schedule(name: &str, image: &str, kind: &Kind) -> Self {
+        Self {
+            location: Location {
+                kind: ShellKind::Systemd,
+                filter: Default::default(),
+            },
+            commands: SchedulingDocument(vec![SchedulingCommand {
+                name: name.to_string(),
+                command: "schedule".to_string(),
+                args: [
+                    ("kind".to_string(), kind.to_string()),
+                    ("image".to_string(), image.to_string()),
+                ]
+                .into(),
+                schedule_with: None,
+                replicas: None,
+            }]),
+        }
     }
 
     pub fn from_file(filename: &Path) -> Result<Self> {
-        let mut io = std::fs::OpenOptions::new();
-        io.read(true);
+        loader::read(filename)?.into_manifest()
+    }
 
-        Self::from_io(io.open(filename)?)
+    pub fn from_file_with(filename: &Path, values: &template::Values) -> Result<Self> {
+        loader::read_with(filename, values)?.into_manifest()
     }
 
     pub fn to_file(&self, filename: &Path) -> Result<()> {
-        let mut io = std::fs::OpenOptions::new();
-        io.write(true);
-        io.create(true);
+        self.to_io_as(
+            std::fs::File::create(filename)?,
+            format::Format::of(filename),
+        )
+    }
+
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    pub fn commands(&self) -> &[SchedulingCommand] {
+        &self.commands.0
+    }
+
+    pub fn command(&self, name: &str) -> Option<&SchedulingCommand> {
+        self.commands.0.iter().find(|x| x.name == name)
+    }
+
+    pub fn requested_resources(&self) -> Result<(u64, u64)> {
+        let mut cpu = 0;
+        let mut mem = 0;
+
+        for command in &self.commands.0 {
+            let (c, m) = command.resources()?;
+            let overflow = || {
+                protocol_error!(
+                    InvalidArgument,
+                    "resources requested by command '{}' are too large",
+                    command.name
+                )
+            };
+
+            cpu = c
+                .checked_mul(command.replicas())
+                .and_then(|x| x.checked_add(cpu))
+                .ok_or_else(overflow)?;
+            mem = m
+                .checked_mul(command.replicas())
+                .and_then(|x| x.checked_add(mem))
+                .ok_or_else(overflow)?;
+        }
+
+        Ok((cpu, mem))
+    }
+
+    pub fn instances(&self) -> Vec<(&SchedulingCommand, String)> {
+        self.commands
+            .0
+            .iter()
+            .flat_map(|command| command.instances().into_iter().map(move |x| (command, x)))
+            .collect()
+    }
+
+    pub fn scale(&mut self, name: &str, replicas: u64) -> Result<()> {
+        let command = self
+            .commands
+            .0
+            .iter_mut()
+            .find(|x| x.name == name)
+            .ok_or_else(|| protocol_error!(NotFound, "no command '{}' in manifest", name))?;
+
+        if command.command != "schedule" {
+            return Err(protocol_error!(
+                InvalidArgument,
+                "command '{}' is not a schedule command and cannot be scaled",
+                name
+            ));
+        }
+
+        command.replicas = Some(replicas);
+        self.validate()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.check().map_err(|(_, e)| e)
+    }
+
+    // like validate, but also gives the index of the command the problem is in.
+    pub(crate) fn check(&self) -> std::result::Result<(), (Option<usize>, anyhow::Error)> {
+        if self.commands.0.is_empty() {
+            return Err((
+                None,
+                protocol_error!(InvalidArgument, "manifest has no commands"),
+            ));
+        }
+
+        let mut names = BTreeSet::new();
+
+        for (index, command) in self.commands.0.iter().enumerate() {
+            if command.name.is_empty() {
+                return Err((
+                    Some(index),
+                    protocol_error!(
+                        InvalidArgument,
+                        "manifest command '{}' has no name",
+                        command.command
+                    ),
+                ));
+            }
+
+            if !names.insert(command.name.as_str()) {
+                return Err((
+                    Some(index),
+                    protocol_error!(
+                        InvalidArgument,
+                        "duplicate command name '{}' in manifest",
+                        command.name
+                    ),
+                ));
+            }
+        }
+
+        let mut instances = BTreeSet::new();
+
+        for (index, command) in self.commands.0.iter().enumerate() {
+            if command.replicas.is_some() && command.command != "schedule" {
+                return Err((
+                    Some(index),
+                    protocol_error!(
+                        InvalidArgument,
+                        "replicas can only be given for schedule commands, not '{}'",
+                        command.name
+                    ),
+                ));
+            }
+
+            if command.replicas() > MAX_REPLICAS {
+                return Err((
+                    Some(index),
+                    protocol_error!(
+                        InvalidArgument,
+                        "command '{}' asks for {} replicas, more than the limit of {}",
+                        command.name,
+                        command.replicas(),
+                        MAX_REPLICAS
+                    ),
+                ));
+            }
+
+            for instance in command.instances() {
+                if (instance != command.name && names.contains(instance.as_str()))
+                    || !instances.insert(instance.clone())
+                {
+                    return Err((
+                        Some(index),
+                        protocol_error!(
+                            InvalidArgument,
+                            "replica '{}' of command '{}' conflicts with another command",
+                            instance,
+                            command.name
+                        ),
+                    ));
+                }
+            }
+        }
+
+        for (index, command) in self.commands.0.iter().enumerate() {
+            for with in command.schedule_with() {
+                if *with == command.name || !names.contains(with.as_str()) {
+                    return Err((
+                        Some(index),
+                        protocol_error!(
+                            InvalidArgument,
+                            "command '{}' is scheduled with unknown command '{}'",
+                            command.name,
+                            with
+                        ),
+                    ));
+                }
+
+                if self.command(with).is_some_and(|x| x.replicas() != 1) {
+                    return Err((
+                        Some(index),
+                        protocol_error!(
+                            InvalidArgument,
+                            "command '{}' cannot be scheduled with replicated command '{}'",
+                            command.name,
+                            with
+                        ),
+                    ));
+                }
+            }
+
+            if let Some((_, message)) = validate::check_command(command).into_iter().next() {
+                return Err((Some(index), protocol_error!(InvalidArgument, "{}", message)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Location {
+    pub fn kind(&self) -> &ShellKind {
+        &self.kind
+    }
+
+    pub fn filter(&self) -> &BTreeMap<String, String> {
+        &self.filter
+    }
+
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.filter
+            .iter()
+            .all(|(k, v)| labels.get(k).is_some_and(|x| x == v))
+    }
+}
+
+impl SchedulingCommand {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn args(&self) -> &BTreeMap<String, String> {
+        &self.args
+    }
+
+    pub fn schedule_with(&self) -> &[String] {
+        self.schedule_with.as_deref().unwrap_or_default()
+    }
+
+    pub fn with_args(&self, args: BTreeMap<String, String>) -> Self {
+        Self {
+            args,
+            ..self.clone()
+        }
+    }
+
+    pub fn replicas(&self) -> u64 {
+        self.replicas.unwrap_or(1)
+    }
+
+    // the first replica keeps the command's name so scaling never renames it.
+    pub fn instances(&self) -> Vec<String> {
+        (1..=self.replicas())
+            .map(|x| match x {
+                1 => self.name.clone(),
+                x => format!("{}-{}", self.name, x),
+            })
+            .collect()
+    }
+
+    pub fn kind(&self) -> Result<Kind> {
+        let kind = self
+            .args
+            .get("kind")
+            .map(|x| x.as_str())
+            .unwrap_or_default();
+
+        Kind::from_str(kind).map_err(|e| {
+            protocol_error!(
+                InvalidArgument,
+                "invalid kind '{}' in command '{}': {}",
+                kind,
+                self.name,
+                e
+            )
+        })
+    }
+
+    pub fn strategy(&self) -> Result<Strategy> {
+        let number = |key: &str| -> Result<Option<u64>> {
+            self.args
+                .get(key)
+                .map(|value| {
+                    value.parse::<u64>().map_err(|_| {
+                        protocol_error!(
+                            InvalidArgument,
+                            "invalid {} value '{}' in command '{}'",
+                            key,
+                            value,
+                            self.name
+                        )
+                    })
+                })
+                .transpose()
+        };
+
+        let max_unavailable = number("max-unavailable")?;
+        let max_surge = number("max-surge")?;
 
-        self.to_io(io.open(filename)?)
+        let strategy = match self.args.get("strategy").map(|x| x.as_str()) {
+            Some("rolling") => Strategy::Rolling {
+                max_unavailable: max_unavailable.unwrap_or(1),
+                max_surge: max_surge.unwrap_or_default(),
+            },
+            Some("recreate") | None => Strategy::Recreate,
+            Some("blue-green") => Strategy::BlueGreen,
+            Some(strategy) => {
+                return Err(protocol_error!(
+                    InvalidArgument,
+                    "invalid strategy '{}' in command '{}'",
+                    strategy,
+                    self.name
+                ))
+            }
+        };
+
+        match strategy {
+            Strategy::Rolling {
+                max_unavailable: 0,
+                max_surge: 0,
+            } => Err(protocol_error!(
+                InvalidArgument,
+                "max-unavailable and max-surge cannot both be zero in command '{}'",
+                self.name
+            )),
+            Strategy::Rolling { .. } => Ok(strategy),
+            _ if max_unavailable.is_some() || max_surge.is_some() => Err(protocol_error!(
+                InvalidArgument,
+                "max-unavailable and max-surge require the rolling strategy in command '{}'",
+                self.name
+            )),
+            _ => Ok(strategy),
+        }
+    }
+
+    pub fn health_check(&self) -> Result<Option<HealthCheck>> {
+        let mut checks = Vec::new();
+
+        if let Some(cmd) = self.args.get("health-exec") {
+            checks.push(HealthCheck::Exec(cmd.clone()));
+        }
+
+        if let Some(addr) = self.args.get("health-tcp") {
+            if secrets::reference(addr).is_none()
+                && !addr
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+            {
+                return Err(protocol_error!(
+                    InvalidArgument,
+                    "invalid health-tcp address '{}' in command '{}'; expected host:port",
+                    addr,
+                    self.name
+                ));
+            }
+
+            checks.push(HealthCheck::Tcp(addr.clone()));
+        }
+
+        if let Some(url) = self.args.get("health-http") {
+            if secrets::reference(url).is_none() && !url.starts_with("http://") {
+                return Err(protocol_error!(
+                    InvalidArgument,
+                    "invalid health-http url '{}' in command '{}'; only http:// is supported",
+                    url,
+                    self.name
+                ));
+            }
+
+            checks.push(HealthCheck::Http(url.clone()));
+        }
+
+        if checks.len() > 1 {
+            return Err(protocol_error!(
+                InvalidArgument,
+                "only one health check may be given in command '{}'",
+                self.name
+            ));
+        }
+
+        Ok(checks.pop())
+    }
+
+    pub fn restart_policy(&self) -> Result<RestartPolicy> {
+        let restart = match self.args.get("restart").map(|x| x.as_str()) {
+            Some("never") | None => Restart::Never,
+            Some("on-failure") => Restart::OnFailure,
+            Some("always") => Restart::Always,
+            Some(restart) => {
+                return Err(protocol_error!(
+                    InvalidArgument,
+                    "invalid restart policy '{}' in command '{}'",
+                    restart,
+                    self.name
+                ))
+            }
+        };
+
+        let mut policy = RestartPolicy {
+            restart,
+            ..Default::default()
+        };
+
+        for (key, value) in &self.args {
+            let invalid = || {
+                protocol_error!(
+                    InvalidArgument,
+                    "invalid {} value '{}' in command '{}'",
+                    key,
+                    value,
+                    self.name
+                )
+            };
+
+            match key.as_str() {
+                "restart-backoff" => policy.backoff = value.parse().map_err(|_| invalid())?,
+                "restart-threshold" => {
+                    policy.threshold = value.parse().ok().filter(|x| *x > 0).ok_or_else(invalid)?
+                }
+                _ => {}
+            }
+        }
+
+        Ok(policy)
+    }
+
+    pub fn environment(&self) -> Result<BTreeMap<String, String>> {
+        let mut environment = BTreeMap::new();
+
+        for (key, value) in &self.args {
+            let Some(name) = key.strip_prefix(validate::ENV_PREFIX) else {
+                continue;
+            };
+
+            if !name.starts_with(|x: char| x.is_ascii_alphabetic() || x == '_')
+                || !name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+            {
+                return Err(protocol_error!(
+                    InvalidArgument,
+                    "invalid environment variable '{}' in command '{}'",
+                    name,
+                    self.name
+                ));
+            }
+
+            environment.insert(name.to_string(), value.clone());
+        }
+
+        Ok(environment)
+    }
+
+    pub fn resources(&self) -> Result<(u64, u64)> {
+        if self.command != "schedule" {
+            return Ok((0, 0));
+        }
+
+        let mut cpu = 0;
+        let mut mem = 0;
+
+        if let Some(value) = self.args.get("cpu") {
+            cpu = value
+                .parse::<u64>()
+                .map_err(|_| anyhow!("invalid cpu value '{}' in command '{}'", value, self.name))?;
+        }
+
+        if let Some(value) = self.args.get("memory") {
+            mem = value.parse::<u64>().map_err(|_| {
+                anyhow!(
+                    "invalid memory value '{}' in command '{}'",
+                    value,
+                    self.name
+                )
+            })?;
+        }
+
+        Ok((cpu, mem))
     }
 }
 
@@ -123,4 +698,362 @@ mod tests {
         assert!(manifest.commands.0[1].schedule_with.is_none());
         Ok(())
     }
+
+    #[test]
+    fn test_requested_resources() -> Result<()> {
+        use std::str::FromStr;
+        let manifest =
+            Manifest::from_file(&std::path::PathBuf::from_str("testdata/combined-one.yaml")?)?;
+        assert_eq!(manifest.requested_resources()?, (0, 0));
+        let manifest = Manifest::from_file(&std::path::PathBuf::from_str(
+            "testdata/resources-one.yaml",
+        )?)?;
+        assert_eq!(manifest.requested_resources()?, (6, 2560));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        use crate::protocol::ErrorCode;
+
+        for file in ["testdata/combined-one.yaml", "testdata/resources-one.yaml"] {
+            Manifest::from_file(Path::new(file))?.validate()?;
+        }
+
+        let base = Manifest::from_file(Path::new("testdata/resources-one.yaml"))?;
+
+        type Modify = fn(&mut Manifest);
+
+        let table: Vec<(Modify, &str)> = vec![
+            (|m| m.commands.0.clear(), "manifest has no commands"),
+            (
+                |m| m.commands.0[2].name = "foo".to_string(),
+                "duplicate command name 'foo'",
+            ),
+            (|m| m.commands.0[1].name.clear(), "has no name"),
+            (
+                |m| m.commands.0[0].schedule_with = Some(vec!["baz".to_string()]),
+                "scheduled with unknown command 'baz'",
+            ),
+            (
+                |m| m.commands.0[0].schedule_with = Some(vec!["foo-network".to_string()]),
+                "scheduled with unknown command 'foo-network'",
+            ),
+            (
+                |m| {
+                    m.commands.0[1].args.remove("image");
+                },
+                "image cannot be omitted in schedule command 'foo'",
+            ),
+            (
+                |m| {
+                    m.commands.0[1]
+                        .args
+                        .insert("kind".to_string(), "bogus".to_string());
+                },
+                "invalid kind 'bogus' in command 'foo'",
+            ),
+            (
+                |m| {
+                    m.commands.0[2]
+                        .args
+                        .insert("cpu".to_string(), "lots".to_string());
+                },
+                "invalid cpu value 'lots' in command 'bar'",
+            ),
+            (
+                |m| {
+                    m.commands.0[1]
+                        .args
+                        .insert("strategy".to_string(), "canary".to_string());
+                },
+                "invalid strategy 'canary' in command 'foo'",
+            ),
+            (
+                |m| {
+                    m.commands.0[1]
+                        .args
+                        .insert("max-surge".to_string(), "1".to_string());
+                },
+                "require the rolling strategy in command 'foo'",
+            ),
+            (
+                |m| {
+                    let args = &mut m.commands.0[1].args;
+                    args.insert("strategy".to_string(), "rolling".to_string());
+                    args.insert("max-unavailable".to_string(), "0".to_string());
+                },
+                "cannot both be zero in command 'foo'",
+            ),
+            (
+                |m| {
+                    let args = &mut m.commands.0[1].args;
+                    args.insert("strategy".to_string(), "rolling".to_string());
+                    args.insert("max-unavailable".to_string(), "-1".to_string());
+                },
+                "invalid max-unavailable value '-1' in command 'foo'",
+            ),
+            (
+                |m| m.commands.0[0].replicas = Some(2),
+                "replicas can only be given for schedule commands",
+            ),
+            (
+                |m| m.commands.0[1].replicas = Some(3),
+                "cannot be scheduled with replicated command 'foo'",
+            ),
+            (
+                |m| {
+                    m.commands.0[2].replicas = Some(2);
+                    m.commands.0[1].name = "bar-2".to_string();
+                    m.commands.0[0].schedule_with = None;
+                },
+                "replica 'bar-2' of command 'bar' conflicts",
+            ),
+            (
+                |m| {
+                    m.commands.0[1]
+                        .args
+                        .insert("health-tcp".to_string(), "8080".to_string());
+                },
+                "invalid health-tcp address '8080' in command 'foo'",
+            ),
+            (
+                |m| {
+                    m.commands.0[1]
+                        .args
+                        .insert("health-http".to_string(), "https://foo/".to_string());
+                },
+                "only http:// is supported",
+            ),
+            (
+                |m| {
+                    let args = &mut m.commands.0[1].args;
+                    args.insert("health-exec".to_string(), "true".to_string());
+                    args.insert("health-tcp".to_string(), "localhost:80".to_string());
+                },
+                "only one health check may be given in command 'foo'",
+            ),
+            (
+                |m| {
+                    m.commands.0[1]
+                        .args
+                        .insert("restart".to_string(), "sometimes".to_string());
+                },
+                "invalid restart policy 'sometimes' in command 'foo'",
+            ),
+            (
+                |m| {
+                    m.commands.0[1]
+                        .args
+                        .insert("restart-threshold".to_string(), "0".to_string());
+                },
+                "invalid restart-threshold value '0' in command 'foo'",
+            ),
+        ];
+
+        for (modify, message) in table {
+            let mut manifest = base.clone();
+            modify(&mut manifest);
+            let err = manifest.validate().unwrap_err();
+            assert!(err.to_string().contains(message), "{}: {}", message, err);
+            assert_eq!(
+                ErrorCode::of(&err),
+                ErrorCode::InvalidArgument,
+                "{}",
+                message
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strategy() -> Result<()> {
+        let base = Manifest::from_file(Path::new("testdata/resources-one.yaml"))?;
+
+        let table = vec![
+            (vec![], Strategy::Recreate, "default strategy"),
+            (
+                vec![("strategy", "rolling")],
+                Strategy::Rolling {
+                    max_unavailable: 1,
+                    max_surge: 0,
+                },
+                "rolling defaults",
+            ),
+            (
+                vec![
+                    ("strategy", "rolling"),
+                    ("max-unavailable", "0"),
+                    ("max-surge", "2"),
+                ],
+                Strategy::Rolling {
+                    max_unavailable: 0,
+                    max_surge: 2,
+                },
+                "rolling with surge",
+            ),
+            (
+                vec![("strategy", "recreate")],
+                Strategy::Recreate,
+                "recreate",
+            ),
+            (
+                vec![("strategy", "blue-green")],
+                Strategy::BlueGreen,
+                "blue-green",
+            ),
+        ];
+
+        for (args, strategy, annotation) in table {
+            let mut command = base.commands.0[1].clone();
+
+            for (k, v) in args {
+                command.args.insert(k.to_string(), v.to_string());
+            }
+
+            assert_eq!(command.strategy()?, strategy, "{}", annotation);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replicas() -> Result<()> {
+        use crate::protocol::ErrorCode;
+
+        let mut manifest = Manifest::from_file(Path::new("testdata/replicas-one.yaml"))?;
+        manifest.validate()?;
+
+        let names = |m: &Manifest| {
+            m.instances()
+                .iter()
+                .map(|(_, x)| x.clone())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(names(&manifest), vec!["web", "web-2", "web-3", "db"]);
+        assert_eq!(manifest.requested_resources()?, (5, 1792));
+
+        manifest.scale("web", 1)?;
+        assert_eq!(names(&manifest), vec!["web", "db"]);
+        manifest.scale("web", 2)?;
+        assert_eq!(names(&manifest), vec!["web", "web-2", "db"]);
+        manifest.scale("web", 1)?;
+        manifest.scale("db", 0)?;
+        assert_eq!(names(&manifest), vec!["web"]);
+        assert_eq!(manifest.requested_resources()?, (1, 256));
+
+        assert_eq!(
+            ErrorCode::of(&manifest.scale("cache", 2).unwrap_err()),
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            ErrorCode::of(&manifest.scale("web", MAX_REPLICAS + 1).unwrap_err()),
+            ErrorCode::InvalidArgument
+        );
+        manifest.scale("web", MAX_REPLICAS)?;
+
+        let web = &mut manifest.commands.0[0];
+        web.args.insert("cpu".to_string(), u64::MAX.to_string());
+        web.replicas = Some(2);
+        assert_eq!(
+            ErrorCode::of(&manifest.requested_resources().unwrap_err()),
+            ErrorCode::InvalidArgument
+        );
+
+        let mut manifest = Manifest::from_file(Path::new("testdata/resources-one.yaml"))?;
+        assert_eq!(
+            ErrorCode::of(&manifest.scale("foo-network", 2).unwrap_err()),
+            ErrorCode::InvalidArgument
+        );
+        assert_eq!(
+            ErrorCode::of(&manifest.scale("foo", 2).unwrap_err()),
+            ErrorCode::InvalidArgument
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_health_and_restart() -> Result<()> {
+        use std::time::Duration;
+
+        let manifest = Manifest::from_file(Path::new("testdata/health-one.yaml"))?;
+        manifest.validate()?;
+
+        let checks = manifest
+            .commands()
+            .iter()
+            .map(|x| x.health_check())
+            .collect::<Result<Vec<Option<HealthCheck>>>>()?;
+        assert_eq!(
+            checks,
+            vec![
+                Some(HealthCheck::Http(
+                    "http://localhost:8080/healthz".to_string()
+                )),
+                Some(HealthCheck::Tcp("localhost:5432".to_string())),
+                Some(HealthCheck::Exec("redis-cli ping".to_string())),
+            ]
+        );
+
+        let policy = manifest.commands()[0].restart_policy()?;
+        assert_eq!(
+            policy,
+            RestartPolicy {
+                restart: Restart::OnFailure,
+                backoff: 2,
+                threshold: 3,
+            }
+        );
+        assert_eq!(
+            manifest.commands()[1].restart_policy()?.restart,
+            Restart::Always
+        );
+        assert_eq!(
+            manifest.commands()[2].restart_policy()?,
+            RestartPolicy::default()
+        );
+
+        let table = vec![
+            (policy, true, 1, Decision::Restart(Duration::from_secs(2))),
+            (policy, true, 2, Decision::Restart(Duration::from_secs(4))),
+            (policy, true, 3, Decision::Reschedule),
+            (policy, false, 1, Decision::Ignore),
+            (
+                RestartPolicy {
+                    restart: Restart::Always,
+                    ..policy
+                },
+                false,
+                0,
+                Decision::Restart(Duration::from_secs(2)),
+            ),
+            (
+                RestartPolicy {
+                    restart: Restart::Always,
+                    backoff: 100,
+                    threshold: 10,
+                },
+                true,
+                4,
+                Decision::Restart(Duration::from_secs(300)),
+            ),
+            (RestartPolicy::default(), true, 10, Decision::Ignore),
+        ];
+
+        for (policy, failed, failures, decision) in table {
+            assert_eq!(
+                policy.decide(failed, failures),
+                decision,
+                "{:?} failed={} failures={}",
+                policy,
+                failed,
+                failures
+            );
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,338 @@
+use super::validate::{Rule, IPV4_PROPS, NETWORK_KINDS, RULES};
+use crate::common::{Kind, ShellKind, SystemdKind};
+use crate::secrets;
+use anyhow::Result;
+use serde_json::{json, Value};
+
+pub const SHELL_KINDS: &[ShellKind] = &[ShellKind::Systemd];
+
+pub const SCHEDULE_KINDS: &[Kind] = &[
+    Kind::Systemd(SystemdKind::Timer),
+    Kind::Systemd(SystemdKind::NSpawn),
+    Kind::Systemd(SystemdKind::Machine),
+    Kind::Systemd(SystemdKind::OneShot),
+    Kind::Systemd(SystemdKind::Service),
+    Kind::Other,
+];
+
+pub const STRATEGIES: &[&str] = &["rolling", "recreate", "blue-green"];
+pub const RESTARTS: &[&str] = &["never", "on-failure", "always"];
+
+const NUMBER: &str = "^[0-9]+$";
+const POSITIVE: &str = "^0*[1-9][0-9]*$";
+
+fn strings<T: ToString>(values: &[T]) -> Value {
+    values.iter().map(|x| x.to_string()).collect()
+}
+
+fn arg(command: &str, key: &str) -> Value {
+    match (command, key) {
+        ("schedule", "kind") => json!({ "type": "string", "enum": strings(SCHEDULE_KINDS) }),
+        ("schedule", "cpu" | "memory" | "max-unavailable" | "max-surge" | "restart-backoff") => {
+            json!({ "type": "string", "pattern": NUMBER })
+        }
+        ("schedule", "restart-threshold") => json!({ "type": "string", "pattern": POSITIVE }),
+        ("schedule", "strategy") => json!({ "type": "string", "enum": STRATEGIES }),
+        ("schedule", "restart") => json!({ "type": "string", "enum": RESTARTS }),
+        ("schedule", "health-tcp") => json!({ "type": "string", "pattern": "^.+:[0-9]{1,5}$" }),
+        ("schedule", "health-http") => json!({ "type": "string", "pattern": "^http://" }),
+        ("network", "kind") => json!({ "type": "string", "enum": NETWORK_KINDS }),
+        ("network", "ipv4-props") => {
+            let props = IPV4_PROPS.join("|");
+
+            json!({
+                "type": "string",
+                "pattern": format!("^({})=[^,=]+(,({})=[^,=]+)*$", props, props),
+            })
+        }
+        _ => json!({ "type": "string" }),
+    }
+}
+
+fn rule(rule: &Rule) -> Value {
+    let properties: serde_json::Map<String, Value> = rule
+        .required
+        .iter()
+        .chain(rule.optional)
+        .map(|key| {
+            let schema = arg(rule.command, key);
+
+            let schema = if rule.secrets.contains(key) {
+                json!({ "anyOf": [schema, { "$ref": "#/definitions/SecretReference" }] })
+            } else {
+                schema
+            };
+
+            (key.to_string(), schema)
+        })
+        .collect();
+
+    let patterns: serde_json::Map<String, Value> = rule
+        .prefixes
+        .iter()
+        .map(|prefix| {
+            (
+                format!("^{}[A-Za-z_][A-Za-z0-9_]*$", prefix),
+                json!({
+                    "anyOf": [
+                        { "type": "string" },
+                        { "$ref": "#/definitions/SecretReference" },
+                    ],
+                }),
+            )
+        })
+        .collect();
+
+    let mut then = json!({
+        "properties": {
+            "args": {
+                "type": "object",
+                "required": rule.required,
+                "properties": properties,
+                "patternProperties": patterns,
+                "additionalProperties": false,
+            },
+        },
+    });
+
+    if rule.command != "schedule" {
+        then["not"] = json!({ "required": ["replicas"] });
+    }
+
+    json!({
+        "if": {
+            "properties": { "command": { "const": rule.command } },
+            "required": ["command"],
+        },
+        "then": then,
+    })
+}
+
+pub fn schema() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "dao manifest",
+        "type": "object",
+        "properties": {
+            "location": { "$ref": "#/definitions/Location" },
+            "commands": { "$ref": "#/definitions/SchedulingDocument" },
+            "include": {
+                "type": "array",
+                "items": { "type": "string" },
+            },
+        },
+        "additionalProperties": false,
+        "definitions": {
+            "ShellKind": {
+                "type": "string",
+                "enum": serde_json::to_value(SHELL_KINDS).unwrap_or_default(),
+            },
+            "SecretReference": {
+                "type": "string",
+                "pattern": format!("^{}[A-Za-z0-9_.-]+$", secrets::PREFIX),
+            },
+            "Location": {
+                "type": "object",
+                "properties": {
+                    "kind": { "$ref": "#/definitions/ShellKind" },
+                    "filter": {
+                        "type": "object",
+                        "additionalProperties": { "type": "string" },
+                    },
+                },
+                "required": ["kind", "filter"],
+                "additionalProperties": false,
+            },
+            "SchedulingDocument": {
+                "type": "array",
+                "items": { "$ref": "#/definitions/SchedulingCommand" },
+            },
+            "SchedulingCommand": {
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string", "minLength": 1 },
+                    "command": {
+                        "type": "string",
+                        "enum": RULES.iter().map(|x| x.command).collect::<Vec<&str>>(),
+                    },
+                    "args": {
+                        "type": "object",
+                        "additionalProperties": { "type": "string" },
+                    },
+                    "schedule-with": {
+                        "type": ["array", "null"],
+                        "items": { "type": "string" },
+                    },
+                    "replicas": { "type": "integer", "minimum": 0, "maximum": super::MAX_REPLICAS },
+                },
+                "required": ["name", "command", "args"],
+                "additionalProperties": false,
+                "allOf": RULES.iter().map(rule).collect::<Vec<Value>>(),
+            },
+        },
+    })
+}
+
+pub fn to_string() -> Result<String> {
+    Ok(serde_json::to_string_pretty(&schema())? + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{Manifest, SchedulingCommand};
+    use std::path::Path;
+    use std::str::FromStr;
+
+    const SCHEMA: &str = "schema/manifest.schema.json";
+
+    #[test]
+    fn test_schema_file() -> Result<()> {
+        let generated = to_string()?;
+
+        if std::env::var_os("DAO_UPDATE_SCHEMA").is_some() {
+            std::fs::write(SCHEMA, &generated)?;
+        }
+
+        assert_eq!(
+            std::fs::read_to_string(SCHEMA)?,
+            generated,
+            "{} is out of date; regenerate it with DAO_UPDATE_SCHEMA=1 cargo test",
+            SCHEMA
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_enums() -> Result<()> {
+        for kind in SHELL_KINDS {
+            match kind {
+                ShellKind::Systemd => {}
+            }
+
+            assert_eq!(&ShellKind::from_str(&kind.to_string())?, kind);
+        }
+
+        for kind in SCHEDULE_KINDS {
+            match kind {
+                Kind::Systemd(
+                    SystemdKind::Timer
+                    | SystemdKind::NSpawn
+                    | SystemdKind::Machine
+                    | SystemdKind::OneShot
+                    | SystemdKind::Service,
+                )
+                | Kind::Other => {}
+            }
+
+            assert_eq!(&Kind::from_str(&kind.to_string())?, kind);
+        }
+
+        let command = |key: &str, value: &str| SchedulingCommand {
+            name: "foo".to_string(),
+            command: "schedule".to_string(),
+            args: [(key.to_string(), value.to_string())].into(),
+            schedule_with: None,
+            replicas: None,
+        };
+
+        for strategy in STRATEGIES {
+            command("strategy", strategy).strategy()?;
+        }
+
+        for restart in RESTARTS {
+            command("restart", restart).restart_policy()?;
+        }
+
+        Ok(())
+    }
+
+    fn keys(value: &Value) -> Vec<&String> {
+        value
+            .as_object()
+            .map(|x| x.keys().collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_testdata() -> Result<()> {
+        let schema = schema();
+        let definitions = &schema["definitions"];
+        let command = &definitions["SchedulingCommand"];
+
+        for file in [
+            "testdata/combined-one.yaml",
+            "testdata/resources-one.yaml",
+            "testdata/resources-two.yaml",
+            "testdata/replicas-one.yaml",
+            "testdata/health-one.yaml",
+            "testdata/secrets-one.yaml",
+        ] {
+            let manifest = serde_json::to_value(Manifest::from_file(Path::new(file))?)?;
+
+            for key in keys(&manifest) {
+                assert!(schema["properties"].get(key).is_some(), "{}: {}", file, key);
+            }
+
+            for key in keys(&manifest["location"]) {
+                assert!(
+                    definitions["Location"]["properties"].get(key).is_some(),
+                    "{}: location {}",
+                    file,
+                    key
+                );
+            }
+
+            for item in manifest["commands"].as_array().unwrap() {
+                for key in keys(item) {
+                    assert!(
+                        command["properties"].get(key).is_some(),
+                        "{}: {}",
+                        file,
+                        key
+                    );
+                }
+
+                let rule = command["allOf"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|x| x["if"]["properties"]["command"]["const"] == item["command"])
+                    .unwrap();
+
+                let args = &rule["then"]["properties"]["args"];
+
+                for key in keys(&args["properties"]) {
+                    let Some(value) = item["args"][key].as_str() else {
+                        continue;
+                    };
+
+                    if let Some(values) = args["properties"][key]["enum"].as_array() {
+                        assert!(
+                            values.iter().any(|x| x == value),
+                            "{}: {}={}",
+                            file,
+                            key,
+                            value
+                        );
+                    }
+                }
+
+                for key in keys(&item["args"]) {
+                    assert!(
+                        args["properties"].get(key).is_some()
+                            || keys(&args["patternProperties"])
+                                .iter()
+                                .any(|x| { regex::Regex::new(x).is_ok_and(|x| x.is_match(key)) }),
+                        "{}: argument {}",
+                        file,
+                        key
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,425 @@
+use super::loader;
+use super::validate::Position;
+use crate::protocol_error;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Values {
+    file: BTreeMap<String, String>,
+    env: bool,
+    overrides: BTreeMap<String, String>,
+}
+
+impl Values {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_file(filename: &Path) -> Result<Self> {
+        let values: BTreeMap<String, serde_yaml::Value> =
+            serde_yaml::from_str(&std::fs::read_to_string(filename)?)?;
+
+        let mut file = BTreeMap::new();
+
+        for (key, value) in values {
+            let value = match value {
+                serde_yaml::Value::String(x) => x,
+                serde_yaml::Value::Number(x) => x.to_string(),
+                serde_yaml::Value::Bool(x) => x.to_string(),
+                serde_yaml::Value::Null => String::new(),
+                _ => {
+                    return Err(protocol_error!(
+                        InvalidArgument,
+                        "value for '{}' in '{}' must be a string, number or boolean",
+                        key,
+                        filename.display()
+                    ))
+                }
+            };
+
+            file.insert(key, value);
+        }
+
+        Ok(Self {
+            file,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_env(mut self) -> Self {
+        self.env = true;
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.overrides.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn set_override(&mut self, pair: &str) -> Result<()> {
+        let (key, value) = pair.split_once('=').ok_or_else(|| {
+            protocol_error!(
+                InvalidArgument,
+                "invalid override '{}'; expected name=value",
+                pair
+            )
+        })?;
+
+        self.set(key, value);
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.overrides
+            .get(key)
+            .cloned()
+            .or_else(|| self.env.then(|| std::env::var(key).ok()).flatten())
+            .or_else(|| self.file.get(key).cloned())
+    }
+}
+
+fn valid(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+        && chars.all(|x| x.is_ascii_alphanumeric() || matches!(x, '_' | '-' | '.'))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    Plain,
+    Double,
+    Single,
+}
+
+impl Context {
+    // follows quoted scalars through a piece of a line. quotes only open a scalar
+    // where one can start, so an apostrophe inside a plain scalar stays plain.
+    fn scan(mut self, line: &str, start: usize, end: usize) -> Self {
+        let mut previous = line[..start].chars().next_back();
+        let mut escaped = false;
+
+        for c in line[start..end].chars() {
+            self = match (self, c) {
+                (Self::Plain, '"' | '\'') if previous.is_none_or(|x| " \t[{,".contains(x)) => {
+                    if c == '"' {
+                        Self::Double
+                    } else {
+                        Self::Single
+                    }
+                }
+                (Self::Double, '\\') if !escaped => {
+                    escaped = true;
+                    previous = Some(c);
+                    continue;
+                }
+                (Self::Double, '"') if !escaped => Self::Plain,
+                (Self::Single, '\'') => Self::Plain,
+                (context, _) => context,
+            };
+
+            escaped = false;
+            previous = Some(c);
+        }
+
+        self
+    }
+}
+
+// values that read back as the same scalar when written without quotes.
+fn plain(value: &str) -> bool {
+    value
+        .chars()
+        .next()
+        .is_some_and(|x| x.is_ascii_alphanumeric() || "._/".contains(x))
+        && !value.ends_with(':')
+        && value
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || "._/+-=:".contains(x))
+}
+
+fn quote(name: &str, value: &str, context: Context, whole: bool) -> Result<String, String> {
+    match context {
+        Context::Double => {
+            let quoted = serde_json::to_string(value).map_err(|e| e.to_string())?;
+            Ok(quoted[1..quoted.len() - 1].to_string())
+        }
+        Context::Single if value.contains('\n') => Err(format!(
+            "value of variable '{}' spans lines and cannot be used in a single-quoted scalar",
+            name
+        )),
+        Context::Single => Ok(value.replace('\'', "''")),
+        Context::Plain if plain(value) => Ok(value.to_string()),
+        Context::Plain if whole => serde_json::to_string(value).map_err(|e| e.to_string()),
+        Context::Plain => Err(format!(
+            "value of variable '{}' must be quoted where it is used",
+            name
+        )),
+    }
+}
+
+pub fn render(text: &str, values: &Values) -> Result<String> {
+    let mut output = String::with_capacity(text.len());
+    let mut problems = Vec::new();
+
+    for (number, line) in text.split_inclusive('\n').enumerate() {
+        let mut rest = line;
+        let mut context = Context::Plain;
+
+        while let Some(start) = rest.find('$') {
+            output.push_str(&rest[..start]);
+            let offset = line.len() - rest.len();
+            context = context.scan(line, offset, offset + start);
+            let position = Position {
+                line: number + 1,
+                column: offset + start + 1,
+            };
+
+            let tail = &rest[start..];
+
+            if let Some(tail) = tail.strip_prefix("$${") {
+                output.push_str("${");
+                rest = tail;
+                continue;
+            }
+
+            let Some(tail) = tail.strip_prefix("${") else {
+                output.push('$');
+                rest = &tail[1..];
+                continue;
+            };
+
+            let Some(end) = tail.find('}') else {
+                problems.push(format!("{}: unterminated variable reference", position));
+                rest = "";
+                break;
+            };
+
+            let expression = &tail[..end];
+            rest = &tail[end + 1..];
+
+            let (name, default) = match expression.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expression, None),
+            };
+
+            if !valid(name) {
+                problems.push(format!("{}: invalid variable name '{}'", position, name));
+                continue;
+            }
+
+            let Some(value) = values.get(name).or_else(|| default.map(str::to_string)) else {
+                problems.push(format!(
+                    "{}: required variable '{}' is not set",
+                    position, name
+                ));
+                continue;
+            };
+
+            let before = &line[..position.column - 1];
+            let after = rest.trim_start();
+            let whole = (before.trim().is_empty()
+                || before.ends_with(": ")
+                || before.ends_with("- ")
+                || before.trim_end().ends_with(['[', '{', ',']))
+                && (after.is_empty() || after.starts_with(['#', ',', ']', '}']));
+
+            match quote(name, &value, context, whole) {
+                Ok(value) => output.push_str(&value),
+                Err(e) => problems.push(format!("{}: {}", position, e)),
+            }
+        }
+
+        output.push_str(rest);
+    }
+
+    if !problems.is_empty() {
+        return Err(protocol_error!(InvalidArgument, "{}", problems.join("\n")));
+    }
+
+    Ok(output)
+}
+
+pub fn preview(filename: &Path, values: &Values) -> Result<String> {
+    let manifest = loader::read_with(filename, values)?.into_manifest()?;
+    Ok(serde_yaml::to_string(&manifest)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::protocol::ErrorCode;
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let mut values = Values::new();
+        values.set("dc", "xo");
+        values.set("image", "nginx");
+        values.set("note", "xo\nimage: evil # \"quoted\"");
+        values.set("quote", "it's");
+
+        let table = vec![
+            ("datacenter: ${dc}", Ok("datacenter: xo"), "substitution"),
+            (
+                "${image}:${dc}",
+                Ok("nginx:xo"),
+                "several variables on one line",
+            ),
+            (
+                "cpu: ${cpu:-2}",
+                Ok("cpu: 2"),
+                "default for a missing variable",
+            ),
+            ("dc: ${dc:-elsewhere}", Ok("dc: xo"), "default ignored when set"),
+            ("empty: ${cpu:-}", Ok("empty: \"\""), "empty default"),
+            ("price: $5 $${dc}", Ok("price: $5 ${dc}"), "escapes"),
+            (
+                "dc: ${note}",
+                Ok("dc: \"xo\\nimage: evil # \\\"quoted\\\"\""),
+                "a whole plain scalar is quoted",
+            ),
+            (
+                "dc: \"${note}\"",
+                Ok("dc: \"xo\\nimage: evil # \\\"quoted\\\"\""),
+                "escaped inside a double-quoted scalar",
+            ),
+            (
+                "dc: '${quote}'",
+                Ok("dc: 'it''s'"),
+                "escaped inside a single-quoted scalar",
+            ),
+            ("list: [${note}, b]", Ok("list: [\"xo\\nimage: evil # \\\"quoted\\\"\", b]"), "flow item"),
+            (
+                "image: nginx:${note}",
+                Err("line 1, column 14: value of variable 'note' must be quoted where it is used"),
+                "unsafe value inside a plain scalar",
+            ),
+            ("it's ${dc}", Ok("it's xo"), "apostrophe in a plain scalar"),
+            (
+                "a: b\nimage: ${tag}",
+                Err("line 2, column 8: required variable 'tag' is not set"),
+                "missing required variable",
+            ),
+            (
+                "${a}\n  ${b}",
+                Err("line 1, column 1: required variable 'a' is not set\nline 2, column 3: required variable 'b' is not set"),
+                "all missing variables are reported",
+            ),
+            (
+                "${1st}",
+                Err("line 1, column 1: invalid variable name '1st'"),
+                "invalid name",
+            ),
+            (
+                "x: ${dc",
+                Err("line 1, column 4: unterminated variable reference"),
+                "unterminated reference",
+            ),
+        ];
+
+        for (text, result, annotation) in table {
+            match (render(text, &values), result) {
+                (Ok(output), Ok(expected)) => assert_eq!(output, expected, "{}", annotation),
+                (Err(e), Err(expected)) => {
+                    assert_eq!(e.to_string(), expected, "{}", annotation);
+                    assert_eq!(ErrorCode::of(&e), ErrorCode::InvalidArgument);
+                }
+                (output, _) => panic!("{}: unexpected result {:?}", annotation, output),
+            }
+        }
+
+        let rendered = render("a: ${note}\nb: \"${note}\"\nc: [${note}]\n", &values)?;
+        let parsed: serde_yaml::Value = serde_yaml::from_str(&rendered)?;
+        let note = values.get("note");
+        assert_eq!(parsed["a"].as_str(), note.as_deref());
+        assert_eq!(parsed["b"].as_str(), note.as_deref());
+        assert_eq!(parsed["c"][0].as_str(), note.as_deref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_values() -> Result<()> {
+        let values = Values::from_file(Path::new("testdata/values-xo.yaml"))?;
+        assert_eq!(values.get("datacenter"), Some("xo".to_string()));
+        assert_eq!(values.get("replicas"), Some("3".to_string()));
+
+        std::env::set_var("DAO_TEMPLATE_TEST_IMAGE", "from-env");
+        assert_eq!(values.get("DAO_TEMPLATE_TEST_IMAGE"), None);
+        let mut values = values.with_env();
+        assert_eq!(
+            values.get("DAO_TEMPLATE_TEST_IMAGE"),
+            Some("from-env".to_string())
+        );
+
+        values.set_override("datacenter=yz")?;
+        values.set_override("DAO_TEMPLATE_TEST_IMAGE=from-cli")?;
+        assert_eq!(values.get("datacenter"), Some("yz".to_string()));
+        assert_eq!(
+            values.get("DAO_TEMPLATE_TEST_IMAGE"),
+            Some("from-cli".to_string())
+        );
+        assert!(values.set_override("datacenter").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_manifest() -> Result<()> {
+        let template = Path::new("testdata/template-one.yaml");
+        let mut values = Values::from_file(Path::new("testdata/values-xo.yaml"))?;
+
+        let manifest = Manifest::from_file_with(template, &values)?;
+        assert_eq!(
+            manifest.location().filter().get("datacenter"),
+            Some(&"xo".to_string())
+        );
+        assert_eq!(manifest.commands()[0].replicas(), 3);
+        assert_eq!(
+            manifest.commands()[0].args().get("image"),
+            Some(&"nginx:1.25".to_string())
+        );
+        assert_eq!(
+            manifest.commands()[0].args().get("memory"),
+            Some(&"256".to_string())
+        );
+
+        values.set("datacenter", "yz");
+        let preview = preview(template, &values)?;
+        assert!(preview.contains("datacenter: yz"));
+        assert_eq!(
+            Manifest::from_io(preview.as_bytes())?
+                .location()
+                .filter()
+                .get("datacenter"),
+            Some(&"yz".to_string())
+        );
+
+        let manifest = Manifest::from_io(
+            "location:\n  kind: systemd\n  filter: {}\ncommands:\n  - name: web\n    command: schedule\n    args:\n      kind: nspawn\n      image: nginx\n      health-exec: test -d ${HOME}\n"
+                .as_bytes(),
+        )?;
+        assert_eq!(
+            manifest.commands()[0].args().get("health-exec"),
+            Some(&"test -d ${HOME}".to_string()),
+            "manifests are only rendered when values are given"
+        );
+
+        let err = Manifest::from_file_with(template, &Values::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            [
+                "testdata/template-one.yaml: line 4, column 17: required variable 'datacenter' is not set",
+                "testdata/template-one.yaml: line 8, column 15: required variable 'replicas' is not set",
+                "testdata/template-one.yaml: line 12, column 20: required variable 'tag' is not set",
+            ]
+            .join("\n")
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+use super::loader::{self, Loaded};
+use super::{Manifest, SchedulingCommand};
+use crate::protocol_error;
+use crate::secrets;
+use anyhow::Result;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+pub const NETWORK_KINDS: &[&str] = &["veth", "bridge", "macvlan", "ipvlan"];
+pub const IPV4_PROPS: &[&str] = &["address", "gateway", "netmask"];
+pub const ENV_PREFIX: &str = "env-";
+
+pub struct Rule {
+    pub command: &'static str,
+    pub required: &'static [&'static str],
+    pub optional: &'static [&'static str],
+    pub secrets: &'static [&'static str],
+    pub prefixes: &'static [&'static str],
+}
+
+impl Rule {
+    pub fn allows(&self, key: &str) -> bool {
+        self.required.contains(&key)
+            || self.optional.contains(&key)
+            || self.prefixes.iter().any(|x| key.starts_with(x))
+    }
+
+    pub fn allows_secret(&self, key: &str) -> bool {
+        self.secrets.contains(&key) || self.prefixes.iter().any(|x| key.starts_with(x))
+    }
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        command: "schedule",
+        required: &["kind", "image"],
+        optional: &[
+            "cpu",
+            "memory",
+            "strategy",
+            "max-unavailable",
+            "max-surge",
+            "health-exec",
+            "health-tcp",
+            "health-http",
+            "restart",
+            "restart-backoff",
+            "restart-threshold",
+        ],
+        secrets: &["image", "health-exec", "health-tcp", "health-http"],
+        prefixes: &[ENV_PREFIX],
+    },
+    Rule {
+        command: "network",
+        required: &["kind"],
+        optional: &["ipv4-props", "gateway-phy"],
+        secrets: &[],
+        prefixes: &[],
+    },
+];
+
+pub fn rule(command: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|x| x.command == command)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Problem {
+    pub file: Option<PathBuf>,
+    pub position: Option<Position>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+
+        if let Some(position) = &self.position {
+            write!(f, "{}: ", position)?;
+        }
+
+        f.write_str(&self.message)
+    }
+}
+
+fn ipv4_props(value: &str) -> Result<(), String> {
+    for prop in value.split(',') {
+        let (key, value) = prop
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not a key=value pair", prop))?;
+
+        if !IPV4_PROPS.contains(&key) {
+            return Err(format!("unknown property '{}'", key));
+        }
+
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) if key == "address" => (addr, Some(prefix)),
+            _ => (value, None),
+        };
+
+        if addr.parse::<Ipv4Addr>().is_err()
+            || prefix.is_some_and(|x| x.parse::<u8>().map_or(true, |x| x > 32))
+        {
+            return Err(format!("'{}' is not a valid ipv4 {}", value, key));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_arg(command: &SchedulingCommand, key: &str, value: &str) -> Result<()> {
+    match (command.command(), key) {
+        ("schedule", "kind") => command.kind().map(drop),
+        ("schedule", "cpu" | "memory") => command
+            .resources()
+            .map(drop)
+            .map_err(|e| protocol_error!(InvalidArgument, "{}", e)),
+        ("schedule", "strategy" | "max-unavailable" | "max-surge") => command.strategy().map(drop),
+        ("schedule", "health-exec" | "health-tcp" | "health-http") => {
+            command.health_check().map(drop)
+        }
+        ("schedule", "restart" | "restart-backoff" | "restart-threshold") => {
+            command.restart_policy().map(drop)
+        }
+        ("schedule", key) if key.starts_with(ENV_PREFIX) => command.environment().map(drop),
+        ("network", "kind") if !NETWORK_KINDS.contains(&value) => Err(protocol_error!(
+            InvalidArgument,
+            "invalid kind '{}' in command '{}'; expected one of {}",
+            value,
+            command.name(),
+            NETWORK_KINDS.join(", ")
+        )),
+        ("network", "ipv4-props") => ipv4_props(value).map_err(|e| {
+            protocol_error!(
+                InvalidArgument,
+                "invalid ipv4-props value '{}' in command '{}': {}",
+                value,
+                command.name(),
+                e
+            )
+        }),
+        _ => Ok(()),
+    }
+}
+
+pub fn check_command(command: &SchedulingCommand) -> Vec<(Option<String>, String)> {
+    let Some(rule) = rule(command.command()) else {
+        return vec![(
+            None,
+            format!(
+                "unknown command '{}' in '{}'; expected one of {}",
+                command.command(),
+                command.name(),
+                RULES
+                    .iter()
+                    .map(|x| x.command)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ),
+        )];
+    };
+
+    let mut problems: Vec<(Option<String>, String)> = Vec::new();
+
+    for key in rule.required {
+        if !command.args().contains_key(*key) {
+            problems.push((
+                None,
+                format!(
+                    "{} cannot be omitted in {} command '{}'",
+                    key,
+                    command.command(),
+                    command.name()
+                ),
+            ));
+        }
+    }
+
+    for (key, value) in command.args() {
+        let message = if !rule.allows(key) {
+            format!(
+                "unknown argument '{}' in {} command '{}'",
+                key,
+                command.command(),
+                command.name()
+            )
+        } else if let Some(name) = secrets::reference(value) {
+            if !rule.allows_secret(key) {
+                format!(
+                    "argument '{}' in {} command '{}' cannot refer to a secret",
+                    key,
+                    command.command(),
+                    command.name()
+                )
+            } else if !secrets::valid(name) {
+                format!(
+                    "invalid secret name '{}' in command '{}'",
+                    name,
+                    command.name()
+                )
+            } else {
+                continue;
+            }
+        } else {
+            match check_arg(command, key, value) {
+                Ok(()) => continue,
+                Err(e) => e.to_string(),
+            }
+        };
+
+        if !problems.iter().any(|(_, x)| *x == message) {
+            problems.push((Some(key.clone()), message));
+        }
+    }
+
+    problems
+}
+
+fn check(loaded: Result<Loaded>) -> (Option<Manifest>, Vec<Problem>) {
+    let loaded = match loaded {
+        Ok(x) => x,
+        Err(e) => {
+            return (
+                None,
+                vec![Problem {
+                    file: None,
+                    position: None,
+                    message: e.to_string(),
+                }],
+            )
+        }
+    };
+
+    let mut problems = loaded.problems;
+
+    for (command, origin) in loaded.manifest.commands().iter().zip(&loaded.origins) {
+        for (key, message) in check_command(command) {
+            problems.push(Problem {
+                file: origin.file.clone(),
+                position: key
+                    .and_then(|x| origin.args.get(&x).copied())
+                    .or(origin.position),
+                message,
+            });
+        }
+    }
+
+    if problems.is_empty() {
+        if let Err((index, e)) = loaded.manifest.check() {
+            let origin = index.and_then(|x| loaded.origins.get(x));
+
+            problems.push(Problem {
+                file: origin.and_then(|x| x.file.clone()),
+                position: origin.and_then(|x| x.position),
+                message: e.to_string(),
+            });
+        }
+    }
+
+    problems.sort();
+    (Some(loaded.manifest), problems)
+}
+
+fn report(checked: (Option<Manifest>, Vec<Problem>)) -> Result<Manifest> {
+    match checked {
+        (Some(manifest), problems) if problems.is_empty() => Ok(manifest),
+        (_, problems) => Err(protocol_error!(
+            InvalidArgument,
+            "{}",
+            problems
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
+        )),
+    }
+}
+
+pub fn problems(text: &str) -> (Option<Manifest>, Vec<Problem>) {
+    check(loader::read_str(text))
+}
+
+pub fn validate_str(text: &str) -> Result<Manifest> {
+    report(problems(text))
+}
+
+pub fn validate_file(filename: &Path) -> Result<Manifest> {
+    report(check(loader::read(filename)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+
+    #[test]
+    fn test_testdata() -> Result<()> {
+        for file in [
+            "testdata/combined-one.yaml",
+            "testdata/resources-one.yaml",
+            "testdata/resources-two.yaml",
+            "testdata/replicas-one.yaml",
+            "testdata/health-one.yaml",
+            "testdata/secrets-one.yaml",
+        ] {
+            validate_file(Path::new(file))?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_problems() {
+        let table = vec![
+            (
+                "testdata/invalid-one.yaml",
+                vec![
+                    "line 6, column 5: image cannot be omitted in schedule command 'foo'",
+                    "line 9, column 7: unknown argument 'imgae' in schedule command 'foo'",
+                    "line 11, column 7: invalid cpu value 'two' in command 'foo'",
+                    "line 15, column 7: invalid kind 'tun' in command 'foo-network'; expected one of veth, bridge, macvlan, ipvlan",
+                    "line 16, column 7: invalid ipv4-props value 'address=192.168.1.300' in command 'foo-network': '192.168.1.300' is not a valid ipv4 address",
+                    "line 17, column 5: unknown command 'shedule' in 'bar'; expected one of schedule, network",
+                ],
+                "argument problems",
+            ),
+            (
+                "testdata/invalid-two.yaml",
+                vec!["line 11, column 5: duplicate command name 'foo', first defined at line 6, column 5"],
+                "semantic problems",
+            ),
+            (
+                "testdata/invalid-three.yaml",
+                vec![
+                    "line 10, column 7: invalid secret name 'bad/name' in command 'foo'",
+                    "line 11, column 7: argument 'cpu' in schedule command 'foo' cannot refer to a secret",
+                    "line 12, column 7: invalid environment variable '1BAD' in command 'foo'",
+                ],
+                "secret references",
+            ),
+        ];
+
+        for (file, messages, annotation) in table {
+            let text = std::fs::read_to_string(file).unwrap();
+            let (manifest, problems) = problems(&text);
+            assert!(manifest.is_some(), "{}", annotation);
+            assert_eq!(
+                problems
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>(),
+                messages,
+                "{}",
+                annotation
+            );
+
+            let err = validate_str(&text).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::InvalidArgument);
+            assert_eq!(err.to_string(), messages.join("\n"));
+        }
+
+        let (_, replicated) = problems(
+            "location:\n  kind: systemd\n  filter: {}\ncommands:\n  - name: bar-2\n    command: schedule\n    args:\n      kind: nspawn\n      image: nginx\n  - name: bar\n    command: schedule\n    replicas: 2\n    args:\n      kind: nspawn\n      image: nginx\n",
+        );
+        assert_eq!(
+            replicated
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>(),
+            vec!["line 10, column 5: replica 'bar-2' of command 'bar' conflicts with another command"]
+        );
+
+        let (manifest, problems) = problems("location:\n  kind: systemd\n  filter: [\n");
+        assert!(manifest.is_none());
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("line 3"));
+
+        let table = vec![
+            ("address=10.0.0.1/24,gateway=10.0.0.254", true),
+            ("netmask=255.255.255.0", true),
+            ("address=10.0.0.1/33", false),
+            ("gateway=10.0.0.1/24", false),
+            ("mtu=1500", false),
+            ("10.0.0.1", false),
+        ];
+
+        for (value, valid) in table {
+            assert_eq!(ipv4_props(value).is_ok(), valid, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,1299 @@
+pub mod rollout;
+
+use crate::db::types::{Schedule, User};
+use crate::db::Store;
+use crate::manifest::{Manifest, SchedulingCommand};
+use crate::protocol::{
+    CommandResult, ErrorCode, NodeSummary, Payload, PlanAction, PlanEntry, Response,
+};
+use crate::protocol_error;
+use anyhow::Result;
+use rollout::Rollout;
+use std::collections::HashMap;
+
+struct Candidate<'a> {
+    node: &'a NodeSummary,
+    cpu: u64,
+    mem: u64,
+}
+
+impl<'a> Candidate<'a> {
+    fn fits(&self, cpu: u64, mem: u64) -> bool {
+        self.cpu >= cpu && self.mem >= mem
+    }
+
+    fn take(&mut self, cpu: u64, mem: u64) -> String {
+        self.cpu -= cpu;
+        self.mem -= mem;
+        self.node.name.clone()
+    }
+}
+
+fn colocate(
+    name: &str,
+    with: &[String],
+    placed: &HashMap<String, Result<String, String>>,
+) -> Option<Result<String, String>> {
+    let mut node: Option<&String> = None;
+
+    for target in with {
+        match placed.get(target)? {
+            Ok(x) if node.is_none() || node == Some(x) => node = Some(x),
+            Ok(x) => {
+                return Some(Err(format!(
+                    "'{}' is scheduled with commands on different nodes ('{}' and '{}')",
+                    name,
+                    node.unwrap(),
+                    x
+                )))
+            }
+            Err(_) => {
+                return Some(Err(format!(
+                    "'{}' is scheduled with '{}', which could not be placed",
+                    name, target
+                )))
+            }
+        }
+    }
+
+    node.cloned().map(Ok)
+}
+
+pub fn place(manifest: &Manifest, nodes: &[NodeSummary]) -> Vec<CommandResult> {
+    place_with(manifest, nodes, &HashMap::default(), &HashMap::default())
+}
+
+pub fn place_with(
+    manifest: &Manifest,
+    nodes: &[NodeSummary],
+    fixed: &HashMap<String, String>,
+    preferred: &HashMap<String, String>,
+) -> Vec<CommandResult> {
+    let mut candidates = nodes
+        .iter()
+        .filter(|x| x.alive && manifest.location().matches(&x.labels))
+        .map(|node| Candidate {
+            node,
+            cpu: node.resources.cpu,
+            mem: node.resources.mem,
+        })
+        .collect::<Vec<Candidate>>();
+
+    let mut placed: HashMap<String, Result<String, String>> = fixed
+        .iter()
+        .map(|(k, v)| (k.clone(), Ok(v.clone())))
+        .collect();
+
+    let mut spread: HashMap<(&str, String), u64> = HashMap::default();
+
+    for (command, instance) in manifest.instances() {
+        if let Some(node) = fixed.get(&instance) {
+            *spread.entry((command.name(), node.clone())).or_default() += 1;
+        }
+    }
+
+    for (command, instance) in manifest.instances() {
+        if !command.schedule_with().is_empty() || placed.contains_key(&instance) {
+            continue;
+        }
+
+        let preferred = preferred.get(&instance);
+        let count = |node: &NodeSummary| {
+            spread
+                .get(&(command.name(), node.name.clone()))
+                .copied()
+                .unwrap_or_default()
+        };
+
+        let result = command
+            .resources()
+            .map_err(|e| e.to_string())
+            .and_then(|(cpu, mem)| {
+                candidates
+                    .iter_mut()
+                    .filter(|x| x.fits(cpu, mem))
+                    .max_by(|a, b| {
+                        (Some(&a.node.name) == preferred)
+                            .cmp(&(Some(&b.node.name) == preferred))
+                            .then_with(|| count(b.node).cmp(&count(a.node)))
+                            .then_with(|| (a.cpu, a.mem).cmp(&(b.cpu, b.mem)))
+                            .then_with(|| b.node.name.cmp(&a.node.name))
+                    })
+                    .map(|x| x.take(cpu, mem))
+                    .ok_or_else(|| {
+                        format!(
+                            "no eligible node has {} cpu and {} memory available for '{}'",
+                            cpu, mem, instance
+                        )
+                    })
+            });
+
+        if let Ok(node) = &result {
+            *spread.entry((command.name(), node.clone())).or_default() += 1;
+        }
+
+        placed.insert(instance, result);
+    }
+
+    loop {
+        let mut progress = false;
+
+        for command in manifest.commands() {
+            if placed.contains_key(command.name()) || command.schedule_with().is_empty() {
+                continue;
+            }
+
+            if let Some(result) = colocate(command.name(), command.schedule_with(), &placed) {
+                let result = result.and_then(|node| {
+                    let (cpu, mem) = command.resources().map_err(|e| e.to_string())?;
+                    match candidates.iter_mut().find(|x| x.node.name == node) {
+                        Some(x) if x.fits(cpu, mem) => Ok(x.take(cpu, mem)),
+                        _ => Err(format!(
+                            "node '{}' does not have {} cpu and {} memory available for '{}'",
+                            node,
+                            cpu,
+                            mem,
+                            command.name()
+                        )),
+                    }
+                });
+
+                placed.insert(command.name().to_string(), result);
+                progress = true;
+            }
+        }
+
+        if !progress {
+            break;
+        }
+    }
+
+    manifest
+        .instances()
+        .into_iter()
+        .map(|(command, instance)| {
+            let result = placed
+                .remove(&instance)
+                .unwrap_or_else(|| Err(format!("'{}' is part of a schedule-with cycle", instance)));
+
+            CommandResult {
+                name: instance,
+                command: command.command().to_string(),
+                status: result.is_ok(),
+                node: result.as_ref().ok().cloned(),
+                error: result.err(),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Applied {
+    pub schedule: Option<Schedule>,
+    pub results: Vec<CommandResult>,
+    pub halted: Option<String>,
+}
+
+impl Applied {
+    pub fn response(&self) -> Response {
+        let payload = Payload::Apply(self.results.clone());
+
+        match (&self.schedule, &self.halted) {
+            (Some(_), _) => Response::ok(payload),
+            (None, Some(halted)) => Response {
+                payload,
+                ..Response::error(ErrorCode::Execution, halted)
+            },
+            (None, None) => Response {
+                payload,
+                ..Response::error(
+                    ErrorCode::Execution,
+                    "manifest could not be applied; no changes were made",
+                )
+            },
+        }
+    }
+}
+
+fn check_owner(schedule: &Schedule, user: &User) -> Result<()> {
+    if schedule.user().username() != user.username() {
+        return Err(protocol_error!(
+            PermissionDenied,
+            "schedule is owned by '{}', not '{}'",
+            schedule.user().username(),
+            user.username()
+        ));
+    }
+
+    Ok(())
+}
+
+fn placements(results: &[CommandResult]) -> HashMap<String, String> {
+    results
+        .iter()
+        .filter_map(|x| Some((x.name.clone(), x.node.clone()?)))
+        .collect()
+}
+
+// replaces the running instances of every schedule command whose arguments changed
+// between the current and the new revision, using the command's update strategy.
+// a halt reverts the instances of every command rolled so far.
+fn roll(
+    current: &Schedule,
+    schedule: &Schedule,
+    rollout: &mut dyn Rollout,
+) -> Result<Option<(String, String)>> {
+    let mut steps = Vec::new();
+
+    for command in schedule.manifest().commands() {
+        let Some(old) = current.manifest().command(command.name()) else {
+            continue;
+        };
+
+        if command.command() != "schedule" || unchanged(old, command) {
+            continue;
+        }
+
+        let instances = |command: &SchedulingCommand, revision: u64| {
+            command
+                .instances()
+                .iter()
+                .map(|x| rollout::instance(x, revision))
+                .collect::<Vec<String>>()
+        };
+
+        let report = rollout::update(
+            command,
+            &instances(old, current.current_revision()),
+            &instances(command, schedule.current_revision()),
+            rollout,
+        )?;
+
+        steps.extend(report.steps);
+
+        if let Some(halted) = report.halted {
+            // nothing is committed for a halted apply, so put every instance back
+            // the way the current revision left it.
+            rollout::undo(&steps, rollout)?;
+            return Ok(Some((command.name().to_string(), halted)));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn apply(
+    db: &mut dyn Store,
+    rollout: &mut dyn Rollout,
+    current: Option<&Schedule>,
+    user: &User,
+    manifest: Manifest,
+    nodes: &[NodeSummary],
+) -> Result<Applied> {
+    if let Some(current) = current {
+        check_owner(current, user)?;
+    }
+
+    manifest.validate()?;
+
+    let schedule = match current {
+        Some(current) => {
+            let mut schedule = current.clone();
+            schedule.revise(manifest, user.clone());
+            schedule
+        }
+        None => Schedule::new(manifest, user.clone()),
+    };
+
+    if let Some(quota) = db.quota(user)? {
+        let replaced = current.and_then(|x| x.id());
+        let others = db
+            .schedules()?
+            .into_iter()
+            .filter(|x| replaced.is_none() || x.id() != replaced)
+            .collect::<Vec<Schedule>>();
+
+        quota.check_schedules(&others, &schedule.usage()?)?;
+    }
+
+    let placed = match current.and_then(|x| x.id()) {
+        Some(id) => db.placements(id)?,
+        None => HashMap::default(),
+    };
+    let mut results = place_around(
+        current.map(|x| x.manifest()),
+        &placed,
+        schedule.manifest(),
+        nodes,
+    );
+
+    if results.iter().all(|x| x.status) {
+        if let Some((name, halted)) = match current {
+            Some(current) => roll(current, &schedule, rollout)?,
+            None => None,
+        } {
+            for result in &mut results {
+                if schedule
+                    .manifest()
+                    .command(&name)
+                    .is_some_and(|x| x.instances().contains(&result.name))
+                {
+                    result.status = false;
+                    result.error = Some(format!("rollout halted: {}", halted));
+                }
+            }
+
+            return Ok(Applied {
+                schedule: None,
+                results,
+                halted: Some(format!("rollout of '{}' halted: {}", name, halted)),
+            });
+        }
+
+        let mut tx = db.begin()?;
+        let id = tx.save_schedule(&schedule)?;
+        tx.save_placements(id, &placements(&results))?;
+        tx.commit()?;
+
+        return Ok(Applied {
+            schedule: Some(schedule.with_id(id)),
+            results,
+            halted: None,
+        });
+    }
+
+    for result in &mut results {
+        if result.status {
+            result.status = false;
+            result.error = Some("rolled back: manifest could not be fully planned".to_string());
+        }
+    }
+
+    Ok(Applied {
+        schedule: None,
+        results,
+        halted: None,
+    })
+}
+
+pub fn rollback(
+    db: &mut dyn Store,
+    rollout: &mut dyn Rollout,
+    schedule: &Schedule,
+    revision: u64,
+    user: &User,
+    nodes: &[NodeSummary],
+) -> Result<Applied> {
+    let manifest = schedule.revision(revision)?.manifest().clone();
+    apply(db, rollout, Some(schedule), user, manifest, nodes)
+}
+
+pub fn scale(
+    db: &mut dyn Store,
+    rollout: &mut dyn Rollout,
+    schedule: &Schedule,
+    name: &str,
+    replicas: u64,
+    user: &User,
+    nodes: &[NodeSummary],
+) -> Result<Applied> {
+    let mut manifest = schedule.manifest().clone();
+    manifest.scale(name, replicas)?;
+    apply(db, rollout, Some(schedule), user, manifest, nodes)
+}
+
+// moves one instance of a schedule off the node it failed on, keeping every other
+// placement. placements are only saved when a new node was found.
+pub fn reschedule(
+    db: &mut dyn Store,
+    schedule: &Schedule,
+    placements: &HashMap<String, String>,
+    instance: &str,
+    nodes: &[NodeSummary],
+) -> Result<CommandResult> {
+    let id = schedule
+        .id()
+        .ok_or_else(|| protocol_error!(NotFound, "schedule has not been saved"))?;
+    let failed = placements.get(instance);
+    let nodes = nodes
+        .iter()
+        .filter(|x| Some(&x.name) != failed)
+        .cloned()
+        .collect::<Vec<NodeSummary>>();
+
+    let mut fixed = placements.clone();
+    fixed.remove(instance);
+
+    let result = place_with(schedule.manifest(), &nodes, &fixed, &HashMap::default())
+        .into_iter()
+        .find(|x| x.name == instance)
+        .ok_or_else(|| protocol_error!(NotFound, "'{}' is not part of the schedule", instance))?;
+
+    if let Some(node) = &result.node {
+        fixed.insert(instance.to_string(), node.clone());
+
+        let mut tx = db.begin()?;
+        tx.save_placements(id, &fixed)?;
+        tx.commit()?;
+    }
+
+    Ok(result)
+}
+
+pub fn owning<'a>(
+    schedules: &'a [Schedule],
+    user: &User,
+    manifest: &Manifest,
+) -> Option<&'a Schedule> {
+    schedules.iter().find(|x| {
+        x.user().username() == user.username()
+            && manifest
+                .commands()
+                .iter()
+                .any(|command| x.manifest().command(command.name()).is_some())
+    })
+}
+
+pub fn select<'a>(schedules: &'a [Schedule], user: &User, id: Option<i64>) -> Result<&'a Schedule> {
+    let owned = schedules
+        .iter()
+        .filter(|x| x.user().username() == user.username())
+        .collect::<Vec<&Schedule>>();
+
+    match id {
+        Some(id) => {
+            let schedule = schedules
+                .iter()
+                .find(|x| x.id() == Some(id))
+                .ok_or_else(|| protocol_error!(NotFound, "no schedule with id {}", id))?;
+            check_owner(schedule, user)?;
+            Ok(schedule)
+        }
+        None if owned.len() == 1 => Ok(owned[0]),
+        None if owned.is_empty() => Err(protocol_error!(
+            NotFound,
+            "user '{}' has no schedules",
+            user.username()
+        )),
+        None => Err(protocol_error!(
+            InvalidArgument,
+            "user '{}' has {} schedules; schedule must be given",
+            user.username(),
+            owned.len()
+        )),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Planned {
+    pub entries: Vec<PlanEntry>,
+}
+
+impl Planned {
+    pub fn response(&self) -> Response {
+        let payload = Payload::Plan(self.entries.clone());
+
+        if self.entries.iter().all(|x| x.error.is_none()) {
+            return Response::ok(payload);
+        }
+
+        Response {
+            payload,
+            ..Response::error(
+                ErrorCode::Execution,
+                "manifest cannot be fully placed; applying it would fail",
+            )
+        }
+    }
+}
+
+fn unchanged(old: &SchedulingCommand, new: &SchedulingCommand) -> bool {
+    old.command() == new.command()
+        && old.args() == new.args()
+        && old.schedule_with() == new.schedule_with()
+}
+
+// places the manifest around the current placements: unchanged instances stay
+// pinned to their node while it remains eligible, everything else prefers it.
+fn place_around(
+    previous: Option<&Manifest>,
+    placements: &HashMap<String, String>,
+    manifest: &Manifest,
+    nodes: &[NodeSummary],
+) -> Vec<CommandResult> {
+    let eligible = |name: &str| {
+        nodes
+            .iter()
+            .any(|x| x.name == name && x.alive && manifest.location().matches(&x.labels))
+    };
+
+    let mut fixed = HashMap::default();
+    let mut preferred = HashMap::default();
+
+    for (command, instance) in manifest.instances() {
+        let old = previous.and_then(|x| x.command(command.name()));
+
+        if let (Some(old), Some(node)) = (old, placements.get(&instance)) {
+            if unchanged(old, command) && command.schedule_with().is_empty() && eligible(node) {
+                fixed.insert(instance, node.clone());
+            } else {
+                preferred.insert(instance, node.clone());
+            }
+        }
+    }
+
+    place_with(manifest, nodes, &fixed, &preferred)
+}
+
+pub fn diff(
+    current: Option<&Schedule>,
+    placements: &HashMap<String, String>,
+    manifest: &Manifest,
+    nodes: &[NodeSummary],
+) -> Result<Planned> {
+    manifest.validate()?;
+
+    let previous = current.map(|x| x.manifest());
+    let mut entries = place_around(previous, placements, manifest, nodes)
+        .into_iter()
+        .zip(manifest.instances())
+        .map(|(result, (command, instance))| {
+            let old = previous
+                .and_then(|x| x.command(command.name()))
+                .filter(|x| x.instances().contains(&instance));
+            let previous = placements.get(&instance).cloned();
+
+            let action = match (old, &previous) {
+                (Some(old), Some(node)) => {
+                    if result.node.as_ref() != Some(node) {
+                        PlanAction::Move
+                    } else if unchanged(old, command) {
+                        PlanAction::Unchanged
+                    } else {
+                        PlanAction::Update
+                    }
+                }
+                _ => PlanAction::Create,
+            };
+
+            PlanEntry {
+                name: result.name,
+                command: result.command,
+                action,
+                node: result.node,
+                previous,
+                error: result.error,
+            }
+        })
+        .collect::<Vec<PlanEntry>>();
+
+    if let Some(previous) = previous {
+        let current = manifest
+            .instances()
+            .into_iter()
+            .map(|(_, x)| x)
+            .collect::<Vec<String>>();
+
+        for (command, instance) in previous.instances() {
+            if !current.contains(&instance) {
+                entries.push(PlanEntry {
+                    previous: placements.get(&instance).cloned(),
+                    name: instance,
+                    command: command.command().to_string(),
+                    action: PlanAction::Delete,
+                    node: None,
+                    error: None,
+                });
+            }
+        }
+    }
+
+    Ok(Planned { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory::MemoryDB;
+    use crate::db::types::{Node, Quota, Secret, Status};
+    use crate::db::Transaction;
+    use crate::protocol::Resources;
+    use std::path::Path;
+
+    fn node(name: &str, alive: bool, cpu: u64, mem: u64, dc: &str) -> NodeSummary {
+        let mut labels = HashMap::default();
+        labels.insert("datacenter".to_string(), dc.to_string());
+
+        NodeSummary {
+            name: name.to_string(),
+            address: format!("{}.example.org", name),
+            alive,
+            resources: Resources {
+                cpu,
+                mem,
+                storage: 0,
+            },
+            last_queried: None,
+            labels,
+        }
+    }
+
+    struct Broken;
+
+    impl Transaction for Broken {
+        fn save_schedule(&mut self, _schedule: &Schedule) -> Result<i64> {
+            Ok(1)
+        }
+
+        fn save_placements(
+            &mut self,
+            _schedule: i64,
+            _placements: &HashMap<String, String>,
+        ) -> Result<()> {
+            Err(protocol_error!(Database, "could not write placements"))
+        }
+
+        fn save_secret(&mut self, _secret: &Secret) -> Result<()> {
+            Ok(())
+        }
+
+        fn commit(self: Box<Self>) -> Result<()> {
+            panic!("transaction committed after a failed write")
+        }
+    }
+
+    impl Store for Broken {
+        fn schedules(&self) -> Result<Vec<Schedule>> {
+            Ok(Vec::new())
+        }
+
+        fn placements(&self, _schedule: i64) -> Result<HashMap<String, String>> {
+            Ok(HashMap::default())
+        }
+
+        fn quota(&self, _user: &User) -> Result<Option<Quota>> {
+            Ok(None)
+        }
+
+        fn secrets(&self) -> Result<Vec<Secret>> {
+            Ok(Vec::new())
+        }
+
+        fn begin(&mut self) -> Result<Box<dyn Transaction + '_>> {
+            Ok(Box::new(Broken))
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        steps: Vec<String>,
+        unhealthy: Vec<String>,
+    }
+
+    impl Rollout for Recorder {
+        fn start(&mut self, instance: &str) -> Result<()> {
+            self.steps.push(format!("start {}", instance));
+            Ok(())
+        }
+
+        fn stop(&mut self, instance: &str) -> Result<()> {
+            self.steps.push(format!("stop {}", instance));
+            Ok(())
+        }
+
+        fn healthy(&mut self, instance: &str) -> Result<bool> {
+            Ok(!self.unhealthy.iter().any(|x| x == instance))
+        }
+    }
+
+    fn nodes(result: &[CommandResult]) -> Vec<Option<&str>> {
+        result.iter().map(|x| x.node.as_deref()).collect()
+    }
+
+    #[test]
+    fn test_place() -> Result<()> {
+        let manifest = Manifest::from_file(Path::new("testdata/resources-one.yaml"))?;
+
+        let table = vec![
+            (
+                vec![node("node1", true, 8, 4096, "xo")],
+                vec![Some("node1"), Some("node1"), Some("node1")],
+                "single node",
+            ),
+            (
+                vec![
+                    node("node1", true, 4, 4096, "xo"),
+                    node("node2", true, 4, 4096, "xo"),
+                ],
+                vec![Some("node1"), Some("node1"), Some("node2")],
+                "spread by free resources",
+            ),
+            (
+                vec![
+                    node("node1", false, 16, 16384, "xo"),
+                    node("node2", true, 16, 16384, "elsewhere"),
+                    node("node3", true, 8, 4096, "xo"),
+                ],
+                vec![Some("node3"), Some("node3"), Some("node3")],
+                "dead and filtered nodes are skipped",
+            ),
+            (
+                vec![node("node1", true, 4, 4096, "xo")],
+                vec![Some("node1"), Some("node1"), None],
+                "insufficient resources",
+            ),
+            (vec![], vec![None, None, None], "no nodes"),
+        ];
+
+        for (nodes_in, result, annotation) in table {
+            assert_eq!(
+                nodes(&place(&manifest, &nodes_in)),
+                result,
+                "{}",
+                annotation
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let user = User::new("erikh", "");
+        let manifest = Manifest::from_file(Path::new("testdata/resources-one.yaml"))?;
+
+        let mut db = MemoryDB::new();
+        let mut rollout = Recorder::default();
+        let applied = apply(
+            &mut db,
+            &mut rollout,
+            None,
+            &user,
+            manifest.clone(),
+            &[node("node1", true, 8, 4096, "xo")],
+        )?;
+        let schedule = applied.schedule.as_ref().unwrap();
+        assert_eq!(schedule.id(), Some(1));
+        assert_eq!(db.schedules().len(), 1);
+        assert_eq!(db.schedules()[0].manifest(), &manifest);
+        assert_eq!(db.placements(1), placements(&applied.results));
+        assert_eq!(db.placements(1)["foo"], "node1");
+        assert_eq!(schedule.user().username(), "erikh");
+        assert_eq!(schedule.manifest(), &manifest);
+        assert_eq!(schedule.usage()?.workloads, 2);
+        let response = applied.response();
+        assert!(response.status);
+        assert_eq!(response.payload, Payload::Apply(applied.results.clone()));
+
+        let applied = apply(
+            &mut db,
+            &mut rollout,
+            None,
+            &user,
+            manifest.clone(),
+            &[node("node1", true, 4, 4096, "xo")],
+        )?;
+        assert!(applied.schedule.is_none());
+        assert_eq!(db.schedules().len(), 1);
+        assert!(applied.results.iter().all(|x| !x.status));
+        assert!(applied.results[0]
+            .error
+            .as_ref()
+            .unwrap()
+            .starts_with("rolled back"));
+        assert!(applied.results[2]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("no eligible node"));
+        let response = applied.response();
+        assert!(!response.status);
+        assert_eq!(response.code, Some(ErrorCode::Execution));
+        assert_eq!(response.payload, Payload::Apply(applied.results));
+
+        let err = apply(
+            &mut Broken,
+            &mut rollout,
+            None,
+            &user,
+            manifest,
+            &[node("node1", true, 8, 4096, "xo")],
+        )
+        .unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::Database);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota() -> Result<()> {
+        let user = User::new("erikh", "");
+        let one = Manifest::from_file(Path::new("testdata/resources-one.yaml"))?;
+        let replicas = Manifest::from_file(Path::new("testdata/replicas-one.yaml"))?;
+        let nodes = vec![node("node1", true, 64, 65536, "xo")];
+        let mut db = MemoryDB::new();
+        let mut rollout = Recorder::default();
+        db.set_quota(Quota::new(user.clone(), 5, 64, 65536));
+
+        let schedule = apply(&mut db, &mut rollout, None, &user, one.clone(), &nodes)?
+            .schedule
+            .unwrap();
+
+        let err = apply(&mut db, &mut rollout, None, &user, replicas.clone(), &nodes).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::QuotaExceeded);
+        assert!(err.to_string().contains("workloads would be 6"), "{}", err);
+        assert_eq!(db.schedules().len(), 1);
+
+        let revised = apply(
+            &mut db,
+            &mut rollout,
+            Some(&schedule),
+            &user,
+            replicas.clone(),
+            &nodes,
+        )?;
+        assert_eq!(revised.schedule.unwrap().id(), schedule.id());
+        assert_eq!(db.schedules().len(), 1);
+
+        let other = User::new("other", "");
+        assert!(
+            apply(&mut db, &mut rollout, None, &other, replicas, &nodes)?
+                .schedule
+                .is_some()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reschedule() -> Result<()> {
+        let user = User::new("erikh", "");
+        let manifest = Manifest::from_file(Path::new("testdata/resources-one.yaml"))?;
+        let mut db = MemoryDB::new();
+        let schedule = apply(
+            &mut db,
+            &mut Recorder::default(),
+            None,
+            &user,
+            manifest,
+            &[node("node1", true, 8, 4096, "xo")],
+        )?
+        .schedule
+        .unwrap();
+        let nodes = vec![
+            node("node1", true, 8, 4096, "xo"),
+            node("node2", true, 8, 4096, "xo"),
+        ];
+
+        let placements = db.placements(1);
+        let result = reschedule(&mut db, &schedule, &placements, "foo", &nodes)?;
+        assert_eq!(result.node.as_deref(), Some("node2"));
+        assert_eq!(db.placements(1)["foo"], "node2");
+        assert_eq!(
+            db.placements(1).len(),
+            placements.len(),
+            "other placements are kept"
+        );
+
+        let placements = db.placements(1);
+        let result = reschedule(&mut db, &schedule, &placements, "foo", &nodes[1..])?;
+        assert!(!result.status);
+        assert_eq!(db.placements(1), placements);
+
+        let err = reschedule(&mut db, &schedule, &placements, "quux", &nodes).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollout() -> Result<()> {
+        let user = User::new("erikh", "");
+        let nodes = vec![node("node1", true, 8, 4096, "xo")];
+        let manifest = |image: &str, strategy: &str| {
+            Manifest::from_io(
+                format!(
+                    "location:\n  kind: systemd\n  filter: {{}}\ncommands:\n  - name: web\n    command: schedule\n    replicas: 2\n    args:\n      kind: nspawn\n      image: {}\n      strategy: {}\n",
+                    image, strategy
+                )
+                .as_bytes(),
+            )
+        };
+        let mut db = MemoryDB::new();
+        let mut rollout = Recorder::default();
+
+        let schedule = apply(
+            &mut db,
+            &mut rollout,
+            None,
+            &user,
+            manifest("nginx:1", "recreate")?,
+            &nodes,
+        )?
+        .schedule
+        .unwrap();
+        assert!(rollout.steps.is_empty());
+
+        let schedule = apply(
+            &mut db,
+            &mut rollout,
+            Some(&schedule),
+            &user,
+            manifest("nginx:2", "recreate")?,
+            &nodes,
+        )?
+        .schedule
+        .unwrap();
+        assert_eq!(
+            rollout.steps,
+            vec![
+                "stop web-r1",
+                "stop web-2-r1",
+                "start web-r2",
+                "start web-2-r2",
+            ]
+        );
+
+        rollout.steps.clear();
+        let placed = db.placements(schedule.id().unwrap());
+        let unchanged = apply(
+            &mut db,
+            &mut rollout,
+            Some(&schedule),
+            &user,
+            manifest("nginx:2", "recreate")?,
+            &[node("node2", true, 16, 8192, "xo"), nodes[0].clone()],
+        )?;
+        assert!(unchanged.schedule.is_some());
+        assert!(rollout.steps.is_empty());
+        assert_eq!(db.placements(schedule.id().unwrap()), placed);
+
+        rollout.unhealthy.push("web-r4".to_string());
+        let applied = apply(
+            &mut db,
+            &mut rollout,
+            unchanged.schedule.as_ref(),
+            &user,
+            manifest("nginx:3", "blue-green")?,
+            &nodes,
+        )?;
+        assert_eq!(rollout.steps, vec!["start web-r4", "stop web-r4"]);
+        assert!(applied.schedule.is_none());
+        assert!(applied.results.iter().all(|x| !x.status));
+        let response = applied.response();
+        assert_eq!(response.code, Some(ErrorCode::Execution));
+        assert_eq!(
+            response.error.as_deref(),
+            Some("rollout of 'web' halted: 'web-r4' failed its health check")
+        );
+        assert_eq!(db.schedules()[0].current_revision(), 3);
+        assert_eq!(
+            db.schedules()[0].manifest(),
+            &manifest("nginx:2", "recreate")?
+        );
+
+        rollout.steps.clear();
+        rollout.unhealthy = vec!["web-2-r4".to_string()];
+        let applied = apply(
+            &mut db,
+            &mut rollout,
+            unchanged.schedule.as_ref(),
+            &user,
+            manifest("nginx:3", "recreate")?,
+            &nodes,
+        )?;
+        assert!(applied.schedule.is_none());
+        assert_eq!(
+            rollout.steps,
+            vec![
+                "stop web-r3",
+                "stop web-2-r3",
+                "start web-r4",
+                "start web-2-r4",
+                "stop web-2-r4",
+                "stop web-r4",
+                "start web-2-r3",
+                "start web-r3",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff() -> Result<()> {
+        let current = Schedule::new(
+            Manifest::from_file(Path::new("testdata/resources-one.yaml"))?,
+            User::new("erikh", ""),
+        );
+        let changed = Manifest::from_file(Path::new("testdata/resources-two.yaml"))?;
+
+        let mut placements = HashMap::default();
+        for (name, node) in [("foo-network", "node1"), ("foo", "node1"), ("bar", "node2")] {
+            placements.insert(name.to_string(), node.to_string());
+        }
+
+        let nodes = vec![
+            node("node1", true, 8, 4096, "xo"),
+            node("node2", true, 8, 4096, "xo"),
+        ];
+
+        let planned = diff(Some(&current), &placements, current.manifest(), &nodes)?;
+        assert!(planned
+            .entries
+            .iter()
+            .all(|x| x.action == PlanAction::Unchanged && x.node == x.previous));
+
+        let planned = diff(Some(&current), &placements, &changed, &nodes)?;
+        let actions = planned
+            .entries
+            .iter()
+            .map(|x| (x.name.as_str(), x.action, x.node.as_deref()))
+            .collect::<Vec<(&str, PlanAction, Option<&str>)>>();
+        assert_eq!(
+            actions,
+            vec![
+                ("foo-network", PlanAction::Unchanged, Some("node1")),
+                ("foo", PlanAction::Update, Some("node1")),
+                ("baz", PlanAction::Create, Some("node2")),
+                ("bar", PlanAction::Delete, None),
+            ]
+        );
+        assert!(planned.response().status);
+
+        let nodes = vec![
+            node("node1", false, 8, 4096, "xo"),
+            node("node2", true, 8, 4096, "xo"),
+        ];
+        let planned = diff(Some(&current), &placements, current.manifest(), &nodes)?;
+        let actions = planned
+            .entries
+            .iter()
+            .map(|x| (x.action, x.node.as_deref()))
+            .collect::<Vec<(PlanAction, Option<&str>)>>();
+        assert_eq!(
+            actions,
+            vec![
+                (PlanAction::Move, Some("node2")),
+                (PlanAction::Move, Some("node2")),
+                (PlanAction::Unchanged, Some("node2")),
+            ]
+        );
+
+        let labels = |dc: &str| -> HashMap<String, String> {
+            [("datacenter".to_string(), dc.to_string())].into()
+        };
+        let nodes = [("node1", "elsewhere"), ("node2", "xo")]
+            .iter()
+            .map(|(name, dc)| {
+                Status::new(Node::new(name, "", "erikh", &labels(dc)), 8, 4096, 0).summary()
+            })
+            .collect::<Vec<NodeSummary>>();
+        let planned = diff(Some(&current), &placements, current.manifest(), &nodes)?;
+        assert_eq!(
+            planned
+                .entries
+                .iter()
+                .map(|x| (x.action, x.node.as_deref()))
+                .collect::<Vec<(PlanAction, Option<&str>)>>(),
+            vec![
+                (PlanAction::Move, Some("node2")),
+                (PlanAction::Move, Some("node2")),
+                (PlanAction::Unchanged, Some("node2")),
+            ]
+        );
+
+        let planned = diff(None, &HashMap::default(), &changed, &[])?;
+        assert!(planned
+            .entries
+            .iter()
+            .all(|x| x.action == PlanAction::Create && x.error.is_some()));
+        let response = planned.response();
+        assert!(!response.status);
+        assert_eq!(response.payload, Payload::Plan(planned.entries));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback() -> Result<()> {
+        let user = User::new("erikh", "");
+        let one = Manifest::from_file(Path::new("testdata/resources-one.yaml"))?;
+        let two = Manifest::from_file(Path::new("testdata/resources-two.yaml"))?;
+        let nodes = vec![node("node1", true, 16, 16384, "xo")];
+        let mut db = MemoryDB::new();
+        let mut rollout = Recorder::default();
+
+        let schedule = apply(&mut db, &mut rollout, None, &user, one.clone(), &nodes)?
+            .schedule
+            .unwrap();
+        let schedule = apply(
+            &mut db,
+            &mut rollout,
+            Some(&schedule),
+            &user,
+            two.clone(),
+            &nodes,
+        )?
+        .schedule
+        .unwrap()
+        .with_id(7);
+        assert_eq!(schedule.manifest(), &two);
+        assert_eq!(schedule.revisions().len(), 2);
+
+        let rolled = rollback(&mut db, &mut rollout, &schedule, 1, &user, &nodes)?
+            .schedule
+            .unwrap();
+        assert_eq!(rolled.manifest(), &one);
+        assert_eq!(
+            rolled
+                .revisions()
+                .iter()
+                .map(|x| x.revision())
+                .collect::<Vec<u64>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            rolled.revision(3)?.manifest(),
+            rolled.revision(1)?.manifest()
+        );
+
+        let other = User::new("other", "");
+        assert_eq!(
+            ErrorCode::of(
+                &rollback(&mut db, &mut rollout, &schedule, 1, &other, &nodes).unwrap_err()
+            ),
+            ErrorCode::PermissionDenied
+        );
+        assert_eq!(
+            ErrorCode::of(
+                &rollback(&mut db, &mut rollout, &schedule, 9, &user, &nodes).unwrap_err()
+            ),
+            ErrorCode::NotFound
+        );
+
+        let failed = rollback(&mut db, &mut rollout, &schedule, 1, &user, &[])?;
+        assert!(failed.schedule.is_none());
+
+        let schedules = vec![schedule.clone()];
+        assert_eq!(select(&schedules, &user, None)?.id(), Some(7));
+        assert_eq!(select(&schedules, &user, Some(7))?.id(), Some(7));
+        assert_eq!(
+            ErrorCode::of(&select(&schedules, &user, Some(8)).unwrap_err()),
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            ErrorCode::of(&select(&schedules, &other, Some(7)).unwrap_err()),
+            ErrorCode::PermissionDenied
+        );
+
+        let schedules = vec![schedule.clone(), schedule.with_id(8)];
+        assert_eq!(
+            ErrorCode::of(&select(&schedules, &user, None).unwrap_err()),
+            ErrorCode::InvalidArgument
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replicas() -> Result<()> {
+        let user = User::new("erikh", "");
+        let manifest = Manifest::from_file(Path::new("testdata/replicas-one.yaml"))?;
+        let nodes = vec![
+            node("node1", true, 8, 4096, "xo"),
+            node("node2", true, 4, 2048, "xo"),
+            node("node3", true, 8, 4096, "elsewhere"),
+        ];
+
+        let results = place(&manifest, &nodes);
+        assert_eq!(
+            results
+                .iter()
+                .map(|x| (x.name.as_str(), x.node.as_deref()))
+                .collect::<Vec<(&str, Option<&str>)>>(),
+            vec![
+                ("web", Some("node1")),
+                ("web-2", Some("node2")),
+                ("web-3", Some("node1")),
+                ("db", Some("node1")),
+            ]
+        );
+
+        let mut db = MemoryDB::new();
+        let mut rollout = Recorder::default();
+        let schedule = apply(&mut db, &mut rollout, None, &user, manifest, &nodes)?
+            .schedule
+            .unwrap();
+        assert_eq!(schedule.usage()?.workloads, 4);
+
+        let placements = results
+            .iter()
+            .map(|x| (x.name.clone(), x.node.clone().unwrap()))
+            .collect::<HashMap<String, String>>();
+
+        let scaled = scale(&mut db, &mut rollout, &schedule, "web", 4, &user, &nodes)?
+            .schedule
+            .unwrap();
+        assert_eq!(scaled.usage()?.workloads, 5);
+        assert_eq!(scaled.revisions().len(), 2);
+
+        let planned = diff(Some(&schedule), &placements, scaled.manifest(), &nodes)?;
+        assert_eq!(
+            planned
+                .entries
+                .iter()
+                .map(|x| (x.name.as_str(), x.action, x.node.as_deref()))
+                .collect::<Vec<(&str, PlanAction, Option<&str>)>>(),
+            vec![
+                ("web", PlanAction::Unchanged, Some("node1")),
+                ("web-2", PlanAction::Unchanged, Some("node2")),
+                ("web-3", PlanAction::Unchanged, Some("node1")),
+                ("web-4", PlanAction::Create, Some("node2")),
+                ("db", PlanAction::Unchanged, Some("node1")),
+            ]
+        );
+
+        let scaled = scale(&mut db, &mut rollout, &schedule, "web", 2, &user, &nodes)?
+            .schedule
+            .unwrap();
+        let planned = diff(Some(&schedule), &placements, scaled.manifest(), &nodes)?;
+        assert_eq!(
+            planned
+                .entries
+                .iter()
+                .map(|x| (x.name.as_str(), x.action))
+                .collect::<Vec<(&str, PlanAction)>>(),
+            vec![
+                ("web", PlanAction::Unchanged),
+                ("web-2", PlanAction::Unchanged),
+                ("db", PlanAction::Unchanged),
+                ("web-3", PlanAction::Delete),
+            ]
+        );
+
+        let mut single = scaled.manifest().clone();
+        single.scale("web", 1)?;
+        let single = Schedule::new(single, user.clone());
+        let planned = diff(Some(&single), &placements, scaled.manifest(), &nodes)?;
+        assert_eq!(
+            planned
+                .entries
+                .iter()
+                .filter(|x| x.action != PlanAction::Unchanged)
+                .map(|x| (x.name.as_str(), x.action))
+                .collect::<Vec<(&str, PlanAction)>>(),
+            vec![("web-2", PlanAction::Create)],
+            "scaling 1 to 2 keeps the first instance"
+        );
+
+        assert_eq!(
+            ErrorCode::of(
+                &scale(&mut db, &mut rollout, &schedule, "cache", 2, &user, &nodes).unwrap_err()
+            ),
+            ErrorCode::NotFound
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+use crate::manifest::{SchedulingCommand, Strategy};
+use anyhow::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Start(String),
+    Stop(String),
+    Healthy(String),
+    Unhealthy(String),
+}
+
+// instances of different revisions run side by side during a rollout, so the
+// driver addresses them by instance and revision.
+pub fn instance(name: &str, revision: u64) -> String {
+    format!("{}-r{}", name, revision)
+}
+
+// the inverse of instance: the name an instance was started under.
+pub fn workload(instance: &str) -> &str {
+    instance
+        .rsplit_once("-r")
+        .filter(|(_, x)| x.parse::<u64>().is_ok())
+        .map_or(instance, |(x, _)| x)
+}
+
+pub trait Rollout {
+    fn start(&mut self, instance: &str) -> Result<()>;
+    fn stop(&mut self, instance: &str) -> Result<()>;
+    fn healthy(&mut self, instance: &str) -> Result<bool>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub steps: Vec<Step>,
+    pub halted: Option<String>,
+}
+
+struct Driver<'a> {
+    rollout: &'a mut dyn Rollout,
+    steps: Vec<Step>,
+}
+
+impl<'a> Driver<'a> {
+    fn start(&mut self, instance: &str) -> Result<bool> {
+        self.rollout.start(instance)?;
+        self.steps.push(Step::Start(instance.to_string()));
+
+        if self.rollout.healthy(instance)? {
+            self.steps.push(Step::Healthy(instance.to_string()));
+            Ok(true)
+        } else {
+            self.steps.push(Step::Unhealthy(instance.to_string()));
+            Ok(false)
+        }
+    }
+
+    fn stop(&mut self, instance: &str) -> Result<()> {
+        self.rollout.stop(instance)?;
+        self.steps.push(Step::Stop(instance.to_string()));
+        Ok(())
+    }
+
+    fn rolling(
+        &mut self,
+        old: &[String],
+        new: &[String],
+        max_unavailable: u64,
+        max_surge: u64,
+    ) -> Result<Option<String>> {
+        let floor = (new.len() as u64).saturating_sub(max_unavailable);
+        let ceiling = new.len() as u64 + max_surge;
+
+        let mut old = old.iter();
+        let mut new = new.iter();
+        let mut running = old.len() as u64;
+        let mut started = 0;
+
+        loop {
+            let mut progress = false;
+
+            while running + started < ceiling {
+                let Some(instance) = new.next() else {
+                    break;
+                };
+
+                if !self.start(instance)? {
+                    return Ok(Some(format!("'{}' failed its health check", instance)));
+                }
+
+                started += 1;
+                progress = true;
+            }
+
+            while running > 0 && running + started > floor {
+                self.stop(old.next().unwrap())?;
+                running -= 1;
+                progress = true;
+            }
+
+            if new.len() == 0 && old.len() == 0 {
+                return Ok(None);
+            }
+
+            if !progress {
+                return Ok(Some(format!(
+                    "rollout cannot make progress with max-unavailable {} and max-surge {}",
+                    max_unavailable, max_surge
+                )));
+            }
+        }
+    }
+
+    fn recreate(&mut self, old: &[String], new: &[String]) -> Result<Option<String>> {
+        for instance in old {
+            self.stop(instance)?;
+        }
+
+        for instance in new {
+            if !self.start(instance)? {
+                return Ok(Some(format!("'{}' failed its health check", instance)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn blue_green(&mut self, old: &[String], new: &[String]) -> Result<Option<String>> {
+        for (i, instance) in new.iter().enumerate() {
+            if !self.start(instance)? {
+                for instance in new[..=i].iter() {
+                    self.stop(instance)?;
+                }
+
+                return Ok(Some(format!("'{}' failed its health check", instance)));
+            }
+        }
+
+        for instance in old {
+            self.stop(instance)?;
+        }
+
+        Ok(None)
+    }
+}
+
+// reverts the net effect of the steps: instances left running are stopped and
+// instances that were stopped are started again, latest first.
+pub fn undo(steps: &[Step], rollout: &mut dyn Rollout) -> Result<()> {
+    let mut started: Vec<&String> = Vec::new();
+    let mut stopped: Vec<&String> = Vec::new();
+
+    for step in steps {
+        match step {
+            Step::Start(instance) => match stopped.iter().position(|x| *x == instance) {
+                Some(i) => {
+                    stopped.remove(i);
+                }
+                None => started.push(instance),
+            },
+            Step::Stop(instance) => match started.iter().position(|x| *x == instance) {
+                Some(i) => {
+                    started.remove(i);
+                }
+                None => stopped.push(instance),
+            },
+            Step::Healthy(_) | Step::Unhealthy(_) => {}
+        }
+    }
+
+    for instance in started.into_iter().rev() {
+        rollout.stop(instance)?;
+    }
+
+    for instance in stopped.into_iter().rev() {
+        rollout.start(instance)?;
+    }
+
+    Ok(())
+}
+
+pub fn execute(
+    strategy: Strategy,
+    old: &[String],
+    new: &[String],
+    rollout: &mut dyn Rollout,
+) -> Report {
+    let mut driver = Driver {
+        rollout,
+        steps: Vec::new(),
+    };
+
+    let result = match strategy {
+        Strategy::Rolling {
+            max_unavailable,
+            max_surge,
+        } => driver.rolling(old, new, max_unavailable, max_surge),
+        Strategy::Recreate => driver.recreate(old, new),
+        Strategy::BlueGreen => driver.blue_green(old, new),
+    };
+
+    Report {
+        steps: driver.steps,
+        halted: result.unwrap_or_else(|e| Some(e.to_string())),
+    }
+}
+
+pub fn update(
+    command: &SchedulingCommand,
+    old: &[String],
+    new: &[String],
+    rollout: &mut dyn Rollout,
+) -> Result<Report> {
+    Ok(execute(command.strategy()?, old, new, rollout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::collections::HashSet;
+
+    struct Canned {
+        running: HashSet<String>,
+        unhealthy: HashSet<String>,
+        broken: HashSet<String>,
+        floor: usize,
+    }
+
+    impl Canned {
+        fn new(running: &[&str], unhealthy: &[&str], broken: &[&str]) -> Self {
+            let set = |x: &[&str]| x.iter().map(|x| x.to_string()).collect();
+
+            Self {
+                running: set(running),
+                unhealthy: set(unhealthy),
+                broken: set(broken),
+                floor: running.len(),
+            }
+        }
+    }
+
+    impl Rollout for Canned {
+        fn start(&mut self, instance: &str) -> Result<()> {
+            if self.broken.contains(instance) {
+                return Err(anyhow!("'{}' could not be started", instance));
+            }
+
+            self.running.insert(instance.to_string());
+            Ok(())
+        }
+
+        fn stop(&mut self, instance: &str) -> Result<()> {
+            self.running.remove(instance);
+            self.floor = self.floor.min(self.running.len());
+            Ok(())
+        }
+
+        fn healthy(&mut self, instance: &str) -> Result<bool> {
+            Ok(!self.unhealthy.contains(instance))
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_execute() {
+        use Step::*;
+
+        let old = names(&["web-1", "web-2", "web-3"]);
+        let new = names(&["web-4", "web-5", "web-6"]);
+        let s = |x: &str| x.to_string();
+
+        let table = vec![
+            (
+                Strategy::Rolling {
+                    max_unavailable: 1,
+                    max_surge: 0,
+                },
+                vec![],
+                vec![
+                    Stop(s("web-1")),
+                    Start(s("web-4")),
+                    Healthy(s("web-4")),
+                    Stop(s("web-2")),
+                    Start(s("web-5")),
+                    Healthy(s("web-5")),
+                    Stop(s("web-3")),
+                    Start(s("web-6")),
+                    Healthy(s("web-6")),
+                ],
+                None,
+                2,
+                "rolling one at a time",
+            ),
+            (
+                Strategy::Rolling {
+                    max_unavailable: 0,
+                    max_surge: 2,
+                },
+                vec![],
+                vec![
+                    Start(s("web-4")),
+                    Healthy(s("web-4")),
+                    Start(s("web-5")),
+                    Healthy(s("web-5")),
+                    Stop(s("web-1")),
+                    Stop(s("web-2")),
+                    Start(s("web-6")),
+                    Healthy(s("web-6")),
+                    Stop(s("web-3")),
+                ],
+                None,
+                3,
+                "rolling with surge",
+            ),
+            (
+                Strategy::Rolling {
+                    max_unavailable: 1,
+                    max_surge: 0,
+                },
+                vec!["web-5"],
+                vec![
+                    Stop(s("web-1")),
+                    Start(s("web-4")),
+                    Healthy(s("web-4")),
+                    Stop(s("web-2")),
+                    Start(s("web-5")),
+                    Unhealthy(s("web-5")),
+                ],
+                Some("'web-5' failed its health check"),
+                2,
+                "rolling halts on failed health check",
+            ),
+            (
+                Strategy::Recreate,
+                vec![],
+                vec![
+                    Stop(s("web-1")),
+                    Stop(s("web-2")),
+                    Stop(s("web-3")),
+                    Start(s("web-4")),
+                    Healthy(s("web-4")),
+                    Start(s("web-5")),
+                    Healthy(s("web-5")),
+                    Start(s("web-6")),
+                    Healthy(s("web-6")),
+                ],
+                None,
+                0,
+                "recreate",
+            ),
+            (
+                Strategy::BlueGreen,
+                vec![],
+                vec![
+                    Start(s("web-4")),
+                    Healthy(s("web-4")),
+                    Start(s("web-5")),
+                    Healthy(s("web-5")),
+                    Start(s("web-6")),
+                    Healthy(s("web-6")),
+                    Stop(s("web-1")),
+                    Stop(s("web-2")),
+                    Stop(s("web-3")),
+                ],
+                None,
+                3,
+                "blue-green",
+            ),
+            (
+                Strategy::BlueGreen,
+                vec!["web-5"],
+                vec![
+                    Start(s("web-4")),
+                    Healthy(s("web-4")),
+                    Start(s("web-5")),
+                    Unhealthy(s("web-5")),
+                    Stop(s("web-4")),
+                    Stop(s("web-5")),
+                ],
+                Some("'web-5' failed its health check"),
+                3,
+                "blue-green keeps the old instances on failure",
+            ),
+        ];
+
+        for (strategy, unhealthy, steps, halted, floor, annotation) in table {
+            let mut canned = Canned::new(&["web-1", "web-2", "web-3"], &unhealthy, &[]);
+            let report = execute(strategy, &old, &new, &mut canned);
+            assert_eq!(report.steps, steps, "{}", annotation);
+            assert_eq!(report.halted.as_deref(), halted, "{}", annotation);
+            assert_eq!(canned.floor, floor, "{}", annotation);
+
+            undo(&report.steps, &mut canned).unwrap();
+            assert_eq!(
+                canned.running,
+                old.iter().cloned().collect::<HashSet<String>>(),
+                "{}",
+                annotation
+            );
+        }
+
+        let mut canned = Canned::new(&["web-1"], &[], &["web-4"]);
+        let report = execute(Strategy::Recreate, &old[..1], &new[..1], &mut canned);
+        assert_eq!(report.steps, vec![Stop(s("web-1"))]);
+        assert_eq!(
+            report.halted.as_deref(),
+            Some("'web-4' could not be started")
+        );
+    }
+
+    #[test]
+    fn test_instance() {
+        assert_eq!(instance("web-1", 2), "web-1-r2");
+        assert_eq!(workload(&instance("web-1", 2)), "web-1");
+        assert_eq!(workload("web-r1x"), "web-r1x");
+        assert_eq!(workload("web"), "web");
+    }
+}
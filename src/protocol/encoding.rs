@@ -0,0 +1,160 @@
+use super::instruction::{Arguments, Instruction};
+use super::tokenizer::{strip_command, tokenize};
+use crate::protocol_error;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Text,
+    Json,
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        })
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(protocol_error!(InvalidArgument, "invalid encoding '{}'", s)),
+        }
+    }
+}
+
+impl Encoding {
+    pub fn negotiation(&self) -> String {
+        format!(r#"encoding mode="{}""#, self)
+    }
+
+    pub fn parse_negotiation(line: &str) -> Option<Result<Self>> {
+        let (rest, offset) = strip_command(line, "encoding")?;
+        Some(Self::parse_mode(rest, offset))
+    }
+
+    fn parse_mode(rest: &str, offset: usize) -> Result<Self> {
+        let mut args = Arguments::new("encoding", tokenize(rest, offset)?, &["mode"])?;
+        args.required("mode")?.parse()
+    }
+
+    pub fn encode(&self, instruction: &Instruction) -> Result<String> {
+        match self {
+            Self::Text => Ok(instruction.to_string()),
+            Self::Json => Ok(serde_json::to_string(instruction)?),
+        }
+    }
+
+    pub fn decode(&self, line: &str) -> Result<Instruction> {
+        match self {
+            Self::Text => line.parse(),
+            // json skips the argument rules of the text parser, so the decoded
+            // instruction is run back through it.
+            Self::Json => serde_json::from_str::<Instruction>(line)?
+                .to_string()
+                .parse(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::*;
+
+    #[test]
+    fn test_negotiation() -> Result<()> {
+        for encoding in [Encoding::Text, Encoding::Json] {
+            assert_eq!(
+                Encoding::parse_negotiation(&encoding.negotiation()).unwrap()?,
+                encoding
+            );
+        }
+
+        assert_eq!(
+            Encoding::parse_negotiation("  encoding mode=JSON").unwrap()?,
+            Encoding::Json
+        );
+        assert!(Encoding::parse_negotiation(r#"status name="encoding""#).is_none());
+        assert!(Encoding::parse_negotiation(r#"encodings mode="json""#).is_none());
+
+        for line in [
+            "encoding",
+            r#"encoding mode="xml""#,
+            r#"encoding name="json""#,
+            r#"encoding mode="json" mode="text""#,
+            r#"encoding mode="json" name="text""#,
+        ] {
+            assert!(
+                Encoding::parse_negotiation(line).unwrap().is_err(),
+                "{}",
+                line
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_decode() -> Result<()> {
+        for (text, instruction, annotation, _, _, _) in &*GREEN_TABLE {
+            assert_eq!(Encoding::Text.encode(instruction)?, *text, "{}", annotation);
+
+            for encoding in [Encoding::Text, Encoding::Json] {
+                let line = encoding.encode(instruction)?;
+                assert!(!line.contains('\n'), "{}", annotation);
+                assert_eq!(encoding.decode(&line)?, *instruction, "{}", annotation);
+            }
+        }
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&Encoding::Json.encode(&GREEN_TABLE[0].1)?)?,
+            serde_json::json!({
+                "command": "schedule",
+                "args": ["test", "linux", {"systemd": "nspawn"}],
+                "tags": {"one": "foo", "two": "bar"},
+            })
+        );
+
+        let table = vec![
+            (
+                r#"{"command":"terminate","args":[null,false],"tags":{"one":"foo"}}"#,
+                "terminating by tags requires all=\"true\" in terminate command",
+            ),
+            (
+                r#"{"command":"schedule","args":["","linux",{"systemd":"nspawn"}],"tags":{}}"#,
+                "name cannot be omitted in schedule command",
+            ),
+            (
+                r#"{"command":"schedule","args":["test","",{"systemd":"nspawn"}],"tags":{}}"#,
+                "image cannot be omitted in schedule command",
+            ),
+            (
+                r#"{"command":"exec","args":["test",""],"tags":{}}"#,
+                "cmd cannot be omitted in exec command",
+            ),
+            (
+                r#"{"command":"status","args":"","tags":{}}"#,
+                "name cannot be empty in status command",
+            ),
+        ];
+
+        for (line, error) in table {
+            let err = Encoding::Json.decode(line).unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", line, err);
+        }
+
+        Ok(())
+    }
+}
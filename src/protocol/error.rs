@@ -0,0 +1,152 @@
+use super::ParseError;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Parse,
+    InvalidCommand,
+    InvalidArgument,
+    Unsupported,
+    IncompatibleVersion,
+    NotFound,
+    PermissionDenied,
+    QuotaExceeded,
+    Database,
+    Execution,
+    Internal,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Parse => "parse",
+            Self::InvalidCommand => "invalid_command",
+            Self::InvalidArgument => "invalid_argument",
+            Self::Unsupported => "unsupported",
+            Self::IncompatibleVersion => "incompatible_version",
+            Self::NotFound => "not_found",
+            Self::PermissionDenied => "permission_denied",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::Database => "database",
+            Self::Execution => "execution",
+            Self::Internal => "internal",
+        })
+    }
+}
+
+impl ErrorCode {
+    pub fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<ProtocolError>() {
+                return e.code;
+            }
+
+            if cause.is::<ParseError>() || cause.is::<serde_json::Error>() {
+                return Self::Parse;
+            }
+
+            if let Some(e) = cause.downcast_ref::<sqlx::Error>() {
+                return match e {
+                    sqlx::Error::RowNotFound => Self::NotFound,
+                    _ => Self::Database,
+                };
+            }
+        }
+
+        Self::Internal
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[macro_export]
+macro_rules! protocol_error {
+    ($code:ident, $($arg:tt)*) => {
+        anyhow::Error::from($crate::protocol::ProtocolError::new(
+            $crate::protocol::ErrorCode::$code,
+            format!($($arg)*),
+        ))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_error_code() {
+        let table = vec![
+            (
+                crate::protocol_error!(NotFound, "no workload named '{}'", "foo"),
+                ErrorCode::NotFound,
+                "protocol error",
+            ),
+            (
+                crate::protocol_error!(QuotaExceeded, "quota exceeded")
+                    .context("could not schedule"),
+                ErrorCode::QuotaExceeded,
+                "protocol error with context",
+            ),
+            (
+                anyhow::Error::from(ParseError {
+                    position: 0,
+                    message: "bad".to_string(),
+                }),
+                ErrorCode::Parse,
+                "tokenizer error",
+            ),
+            (
+                anyhow::Error::from(serde_json::from_str::<u32>("x").unwrap_err()),
+                ErrorCode::Parse,
+                "json error",
+            ),
+            (
+                anyhow::Error::from(sqlx::Error::RowNotFound),
+                ErrorCode::NotFound,
+                "missing row",
+            ),
+            (
+                anyhow::Error::from(sqlx::Error::PoolClosed),
+                ErrorCode::Database,
+                "database error",
+            ),
+            (
+                anyhow!("something broke"),
+                ErrorCode::Internal,
+                "plain error",
+            ),
+        ];
+
+        for (error, code, annotation) in table {
+            assert_eq!(ErrorCode::of(&error), code, "{}", annotation);
+        }
+
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::InvalidArgument).unwrap(),
+            format!("\"{}\"", ErrorCode::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use super::{Payload, Response};
+use crate::protocol_error;
+use anyhow::{anyhow, Result};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+pub const POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Scheduled,
+    Started,
+    Failed,
+    Terminated,
+    NodeUp,
+    NodeDown,
+}
+
+impl EventKind {
+    pub fn node(alive: bool) -> Self {
+        if alive {
+            Self::NodeUp
+        } else {
+            Self::NodeDown
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub sequence: u64,
+    pub kind: EventKind,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tags: HashMap<String, String>,
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WatchFilter {
+    pub name: Option<String>,
+    pub tags: HashMap<String, String>,
+}
+
+impl WatchFilter {
+    pub fn new(name: Option<String>, tags: HashMap<String, String>) -> Self {
+        Self { name, tags }
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        if self.name.as_ref().is_some_and(|x| *x != event.name) {
+            return false;
+        }
+
+        self.tags
+            .iter()
+            .all(|(k, v)| event.tags.get(k).is_some_and(|x| x == v))
+    }
+}
+
+pub struct EventLog {
+    events: VecDeque<Event>,
+    capacity: usize,
+    sequence: u64,
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            sequence: 0,
+            sender,
+        }
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn publish(
+        &mut self,
+        kind: EventKind,
+        name: impl ToString,
+        node: Option<String>,
+        tags: HashMap<String, String>,
+    ) -> Event {
+        self.sequence += 1;
+
+        let event = Event {
+            sequence: self.sequence,
+            kind,
+            name: name.to_string(),
+            node,
+            tags,
+            timestamp: chrono::Local::now().naive_local(),
+        };
+
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+
+        if self.capacity > 0 {
+            self.events.push_back(event.clone());
+        }
+
+        let _ = self.sender.send(event.clone());
+        event
+    }
+
+    pub fn replay(&self, since: u64, filter: &WatchFilter) -> Result<Vec<Event>> {
+        if since > self.sequence {
+            return Err(protocol_error!(
+                InvalidArgument,
+                "sequence {} is ahead of the event log (last sequence is {})",
+                since,
+                self.sequence
+            ));
+        }
+
+        let oldest = self
+            .events
+            .front()
+            .map_or(self.sequence + 1, |x| x.sequence);
+
+        if since + 1 < oldest {
+            return Err(protocol_error!(
+                NotFound,
+                "events after sequence {} are no longer available (oldest is {})",
+                since,
+                oldest
+            ));
+        }
+
+        Ok(self
+            .events
+            .iter()
+            .filter(|x| x.sequence > since && filter.matches(x))
+            .cloned()
+            .collect())
+    }
+
+    pub fn watch(
+        &self,
+        since: Option<u64>,
+        filter: &WatchFilter,
+    ) -> Result<(Vec<Event>, broadcast::Receiver<Event>)> {
+        let backlog = match since {
+            Some(since) => self.replay(since, filter)?,
+            None => Vec::new(),
+        };
+
+        Ok((backlog, self.sender.subscribe()))
+    }
+}
+
+// sends the backlog after since and then every live event matching the filter until
+// send reports the subscriber is gone or idle, polled between events, says to stop.
+pub fn stream(
+    log: &Mutex<EventLog>,
+    since: Option<u64>,
+    filter: &WatchFilter,
+    send: &mut dyn FnMut(Response) -> bool,
+    idle: &mut dyn FnMut() -> bool,
+) -> Result<Response> {
+    let (backlog, mut live) = log
+        .lock()
+        .map_err(|_| anyhow!("event log is poisoned"))?
+        .watch(since, filter)?;
+
+    let mut last = since.unwrap_or_default();
+
+    for event in backlog {
+        last = event.sequence;
+
+        if !send(Response::partial(Payload::Event(event))) {
+            return Ok(Response::ok(Payload::default()));
+        }
+    }
+
+    loop {
+        match live.try_recv() {
+            Ok(event) => {
+                last = event.sequence;
+
+                if filter.matches(&event) && !send(Response::partial(Payload::Event(event))) {
+                    break;
+                }
+            }
+            Err(TryRecvError::Empty) => {
+                if !idle() {
+                    break;
+                }
+
+                std::thread::sleep(POLL);
+            }
+            Err(TryRecvError::Lagged(count)) => {
+                return Err(protocol_error!(
+                    NotFound,
+                    "watch fell {} events behind; resume with since=\"{}\"",
+                    count,
+                    last
+                ))
+            }
+            Err(TryRecvError::Closed) => break,
+        }
+    }
+
+    Ok(Response::ok(Payload::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+
+    fn tags(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_watch_filter() {
+        let mut log = EventLog::new(10);
+        let event = log.publish(
+            EventKind::Scheduled,
+            "foo",
+            Some("node1".to_string()),
+            tags(&[("team", "infra"), ("tier", "web")]),
+        );
+
+        let table = vec![
+            (WatchFilter::default(), true, "empty filter"),
+            (
+                WatchFilter::new(Some("foo".to_string()), Default::default()),
+                true,
+                "matching name",
+            ),
+            (
+                WatchFilter::new(Some("bar".to_string()), Default::default()),
+                false,
+                "other name",
+            ),
+            (
+                WatchFilter::new(None, tags(&[("team", "infra")])),
+                true,
+                "matching tag subset",
+            ),
+            (
+                WatchFilter::new(None, tags(&[("team", "web")])),
+                false,
+                "mismatched tag value",
+            ),
+            (
+                WatchFilter::new(Some("foo".to_string()), tags(&[("owner", "erikh")])),
+                false,
+                "missing tag",
+            ),
+        ];
+
+        for (filter, result, annotation) in table {
+            assert_eq!(filter.matches(&event), result, "{}", annotation);
+        }
+    }
+
+    #[test]
+    fn test_event_log() -> Result<()> {
+        let mut log = EventLog::new(3);
+        assert!(log.replay(0, &WatchFilter::default())?.is_empty());
+
+        log.publish(EventKind::Scheduled, "foo", None, Default::default());
+        log.publish(EventKind::Started, "foo", None, Default::default());
+        log.publish(EventKind::NodeDown, "node1", None, Default::default());
+        assert_eq!(log.sequence(), 3);
+
+        let filter = WatchFilter::new(Some("foo".to_string()), Default::default());
+        let events = log.replay(0, &filter)?;
+        assert_eq!(
+            events.iter().map(|x| x.sequence).collect::<Vec<u64>>(),
+            vec![1, 2]
+        );
+        assert_eq!(log.replay(1, &filter)?.len(), 1);
+        assert!(log.replay(3, &filter)?.is_empty());
+
+        let (backlog, mut live) = log.watch(Some(2), &WatchFilter::default())?;
+        assert_eq!(backlog.len(), 1);
+        assert_eq!(backlog[0].kind, EventKind::NodeDown);
+
+        log.publish(EventKind::Failed, "foo", None, Default::default());
+        let event = live.try_recv()?;
+        assert_eq!(event.sequence, 4);
+        assert_eq!(event.kind, EventKind::Failed);
+
+        assert_eq!(
+            ErrorCode::of(&log.replay(0, &filter).unwrap_err()),
+            ErrorCode::NotFound
+        );
+        assert!(log.replay(1, &filter).is_ok());
+        assert_eq!(
+            ErrorCode::of(&log.replay(5, &filter).unwrap_err()),
+            ErrorCode::InvalidArgument
+        );
+
+        let log = Mutex::new(log);
+        let mut sent = Vec::new();
+        let mut polls = 0;
+        let response = stream(
+            &log,
+            Some(2),
+            &filter,
+            &mut |response| {
+                sent.push(response);
+                true
+            },
+            &mut || {
+                polls += 1;
+
+                if polls == 1 {
+                    let mut log = log.lock().unwrap();
+                    log.publish(EventKind::Started, "bar", None, Default::default());
+                    log.publish(EventKind::Terminated, "foo", None, Default::default());
+                }
+
+                polls < 3
+            },
+        )?;
+        assert!(response.status && !response.more);
+        assert_eq!(
+            sent.iter()
+                .map(|x| match &x.payload {
+                    Payload::Event(event) => (x.more, event.sequence, event.kind),
+                    x => panic!("unexpected payload {:?}", x),
+                })
+                .collect::<Vec<(bool, u64, EventKind)>>(),
+            vec![
+                (true, 4, EventKind::Failed),
+                (true, 6, EventKind::Terminated)
+            ]
+        );
+
+        let mut sent = 0;
+        stream(
+            &log,
+            Some(4),
+            &WatchFilter::default(),
+            &mut |_| {
+                sent += 1;
+                false
+            },
+            &mut || panic!("polled after the subscriber went away"),
+        )?;
+        assert_eq!(sent, 1);
+
+        assert_eq!(EventKind::node(true), EventKind::NodeUp);
+        assert_eq!(EventKind::node(false), EventKind::NodeDown);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+use super::instruction::{Arguments, Command};
+use super::payload::Payload;
+use super::tokenizer::{quote, strip_command, tokenize};
+use crate::protocol_error;
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: BTreeSet<String>,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        let mut capabilities = Command::NAMES
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<BTreeSet<String>>();
+        capabilities.insert("json".to_string());
+
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+}
+
+impl std::fmt::Display for Hello {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "hello version={} capabilities={}",
+            quote(&self.version.to_string()),
+            quote(&self.capabilities_string()),
+        ))
+    }
+}
+
+impl FromStr for Hello {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
+        match Self::parse_hello(s) {
+            Some(res) => res,
+            None => Err(protocol_error!(
+                InvalidCommand,
+                "not a hello message: {:?}",
+                s
+            )),
+        }
+    }
+}
+
+fn parse_version(version: &str) -> Result<u32> {
+    version
+        .parse()
+        .map_err(|_| protocol_error!(InvalidArgument, "invalid protocol version '{}'", version))
+}
+
+fn parse_capabilities(capabilities: &str) -> BTreeSet<String> {
+    capabilities
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+        .collect()
+}
+
+impl Hello {
+    pub fn parse_hello(line: &str) -> Option<Result<Self>> {
+        let (rest, offset) = strip_command(line, "hello")?;
+        Some(Self::parse_args(rest, offset))
+    }
+
+    fn parse_args(rest: &str, offset: usize) -> Result<Self> {
+        let mut args = Arguments::new(
+            "hello",
+            tokenize(rest, offset)?,
+            &["version", "capabilities"],
+        )?;
+
+        Ok(Self {
+            version: parse_version(&args.required("version")?)?,
+            capabilities: parse_capabilities(
+                &args.parsed::<String>("capabilities")?.unwrap_or_default(),
+            ),
+        })
+    }
+
+    pub fn check_version(&self) -> Result<()> {
+        if self.version < MIN_PROTOCOL_VERSION || self.version > PROTOCOL_VERSION {
+            return Err(protocol_error!(
+                IncompatibleVersion,
+                "incompatible protocol version {}: supported versions are {} through {}",
+                self.version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.contains(&capability.to_lowercase())
+    }
+
+    fn capabilities_string(&self) -> String {
+        self.capabilities
+            .iter()
+            .cloned()
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    pub fn to_payload(&self) -> Payload {
+        let mut payload = HashMap::default();
+        payload.insert("version".to_string(), self.version.to_string());
+        payload.insert("capabilities".to_string(), self.capabilities_string());
+        payload.into()
+    }
+
+    pub fn from_payload(payload: &Payload) -> Result<Self> {
+        Ok(Self {
+            version: parse_version(payload.get("version").ok_or_else(|| {
+                protocol_error!(InvalidArgument, "version missing from hello response")
+            })?)?,
+            capabilities: parse_capabilities(
+                payload
+                    .get("capabilities")
+                    .map(|x| x.as_str())
+                    .unwrap_or_default(),
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello() -> Result<()> {
+        let hello = Hello::default();
+        assert_eq!(
+            hello.to_string(),
+            format!(
+                r#"hello version="{}" capabilities="apply,cancel,exec,history,json,logs,plan,quota,restart,rollback,scale,schedule,secret,start,status,stop,terminate,watch""#,
+                PROTOCOL_VERSION
+            )
+        );
+        assert_eq!(hello.to_string().parse::<Hello>()?, hello);
+        assert_eq!(Hello::from_payload(&hello.to_payload())?, hello);
+        assert!(hello.check_version().is_ok());
+        assert!(hello.supports("schedule"));
+        assert!(hello.supports("JSON"));
+        assert!(!hello.supports("reboot"));
+
+        let hello: Hello = "hello version=1".parse()?;
+        assert!(hello.capabilities.is_empty());
+
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..Default::default()
+        };
+        assert!(hello.check_version().is_err());
+
+        for line in [
+            "hello",
+            r#"hello version="one""#,
+            r#"hello version="1" color="red""#,
+            r#"status version="1""#,
+        ] {
+            assert!(line.parse::<Hello>().is_err(), "{}", line);
+        }
+
+        assert!(Hello::parse_hello(r#"status name="hello""#).is_none());
+
+        Ok(())
+    }
+}
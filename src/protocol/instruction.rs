@@ -1,10 +1,16 @@
+use super::event::WatchFilter;
+use super::tokenizer::{quote, tokenize};
 use crate::common::*;
+use crate::manifest::Manifest;
+use crate::protocol_error;
 use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str::FromStr;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Instruction {
+    #[serde(flatten)]
     pub command: Command,
     pub tags: HashMap<String, String>,
 }
@@ -14,155 +20,559 @@ impl std::fmt::Display for Instruction {
         let tags = if self.tags.is_empty() {
             Default::default()
         } else {
-            let mut tags = self
-                .tags
-                .iter()
-                .map(|(k, v)| format!("{}={}", k, v))
-                .collect::<Vec<String>>();
-
-            tags.sort();
-
-            format!(r#" tags="{}""#, tags.join(","))
+            format!(" tags={}", quote(&format_tags(&self.tags)))
         };
         match &self.command {
             Command::Schedule(name, image, kind) => f.write_str(&format!(
-                r#"schedule name="{}" image="{}" kind="{}"{}"#,
-                name, image, kind, tags,
+                "schedule name={} image={} kind={}{}",
+                quote(name),
+                quote(image),
+                quote(&kind.to_string()),
+                tags,
+            )),
+            Command::Terminate(name, all) => f.write_str(&format!(
+                "terminate{}{}{}",
+                name.as_ref()
+                    .map_or_else(Default::default, |x| format!(" name={}", quote(x))),
+                if *all { r#" all="true""# } else { "" },
+                tags,
+            )),
+            Command::Start(name) | Command::Stop(name) | Command::Restart(name) => f.write_str(
+                &format!("{} name={}{}", self.command.name(), quote(name), tags),
+            ),
+            Command::Exec(name, cmd) => f.write_str(&format!(
+                "exec name={} cmd={}{}",
+                quote(name),
+                quote(cmd),
+                tags,
+            )),
+            Command::Logs(name, since, lines, follow) => f.write_str(&format!(
+                "logs name={}{}{}{}{}",
+                quote(name),
+                since
+                    .as_ref()
+                    .map_or_else(Default::default, |x| format!(" since={}", quote(x))),
+                lines.map_or_else(Default::default, |x| format!(
+                    " lines={}",
+                    quote(&x.to_string())
+                )),
+                if *follow { r#" follow="true""# } else { "" },
+                tags,
+            )),
+            Command::Apply(manifest) | Command::Plan(manifest) => f.write_str(&format!(
+                "{} manifest={}{}",
+                self.command.name(),
+                quote(&serde_yaml::to_string(manifest).map_err(|_| std::fmt::Error)?),
+                tags,
+            )),
+            Command::History(schedule) => f.write_str(&format!(
+                "history{}{}",
+                schedule.map_or_else(Default::default, |x| format!(
+                    " schedule={}",
+                    quote(&x.to_string())
+                )),
+                tags,
+            )),
+            Command::Rollback(revision, schedule) => f.write_str(&format!(
+                "rollback revision={}{}{}",
+                quote(&revision.to_string()),
+                schedule.map_or_else(Default::default, |x| format!(
+                    " schedule={}",
+                    quote(&x.to_string())
+                )),
+                tags,
+            )),
+            Command::Scale(name, replicas, schedule) => f.write_str(&format!(
+                "scale name={} replicas={}{}{}",
+                quote(name),
+                quote(&replicas.to_string()),
+                schedule.map_or_else(Default::default, |x| format!(
+                    " schedule={}",
+                    quote(&x.to_string())
+                )),
+                tags,
             )),
-            Command::Terminate(name) => {
-                f.write_str(&format!(r#"terminate name="{}"{}"#, name, tags))
-            }
             Command::Status(name) => f.write_str(&format!(
                 "status{}{}",
-                name.to_owned()
-                    .map_or_else(|| Default::default(), |x| format!(r#" name="{}""#, x)),
+                name.as_ref()
+                    .map_or_else(Default::default, |x| format!(" name={}", quote(x))),
+                tags,
+            )),
+            Command::Quota(user) => f.write_str(&format!(
+                "quota{}{}",
+                user.as_ref()
+                    .map_or_else(Default::default, |x| format!(" user={}", quote(x))),
+                tags,
+            )),
+            Command::Secret(name, value) => f.write_str(&format!(
+                "secret name={} value={}{}",
+                quote(name),
+                quote(value),
+                tags,
+            )),
+            Command::Watch(name, since) => f.write_str(&format!(
+                "watch{}{}{}",
+                name.as_ref()
+                    .map_or_else(Default::default, |x| format!(" name={}", quote(x))),
+                since.map_or_else(Default::default, |x| format!(
+                    " since={}",
+                    quote(&x.to_string())
+                )),
                 tags,
             )),
+            Command::Cancel => f.write_str(&format!("cancel{}", tags)),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(tag = "command", content = "args", rename_all = "lowercase")]
 pub enum Command {
     Schedule(String, String, Kind),
-    Terminate(String),
+    Terminate(Option<String>, bool),
+    Start(String),
+    Stop(String),
+    Restart(String),
+    Exec(String, String),
+    Logs(String, Option<String>, Option<u64>, bool),
+    Apply(Manifest),
+    Plan(Manifest),
+    History(Option<i64>),
+    Rollback(u64, Option<i64>),
+    Scale(String, u64, Option<i64>),
     Status(Option<String>),
+    Quota(Option<String>),
+    Secret(String, String),
+    Watch(Option<String>, Option<u64>),
+    Cancel,
+}
+
+impl Command {
+    pub const NAMES: &'static [&'static str] = &[
+        "schedule",
+        "terminate",
+        "start",
+        "stop",
+        "restart",
+        "exec",
+        "logs",
+        "apply",
+        "plan",
+        "history",
+        "rollback",
+        "scale",
+        "status",
+        "quota",
+        "secret",
+        "watch",
+        "cancel",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Schedule(..) => "schedule",
+            Self::Terminate(..) => "terminate",
+            Self::Start(..) => "start",
+            Self::Stop(..) => "stop",
+            Self::Restart(..) => "restart",
+            Self::Exec(..) => "exec",
+            Self::Logs(..) => "logs",
+            Self::Apply(..) => "apply",
+            Self::Plan(..) => "plan",
+            Self::History(..) => "history",
+            Self::Rollback(..) => "rollback",
+            Self::Scale(..) => "scale",
+            Self::Status(..) => "status",
+            Self::Quota(..) => "quota",
+            Self::Secret(..) => "secret",
+            Self::Watch(..) => "watch",
+            Self::Cancel => "cancel",
+        }
+    }
 }
 
 lazy_static::lazy_static! {
     static ref PARSE_INSTRUCTION: regex::Regex =
-        regex::Regex::new(r#"^\s*([^\s]+)\s*(.*)$"#).unwrap();
-    static ref PARSE_KV_PAIR: regex::Regex =
-        regex::Regex::new(r#"([^=\s]+)\s*=\s*\"(\\"|[^\"]+)\""#).unwrap();
+        regex::Regex::new(r#"(?s)^\s*([^\s]+)\s*(.*)$"#).unwrap();
 }
 
-fn parse_tags(tags: &str) -> Result<HashMap<String, String>> {
+fn escape_tag(s: &str, special: &str) -> String {
+    let mut escaped = String::new();
+
+    for c in s.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+pub fn format_tags(tags: &HashMap<String, String>) -> String {
+    let mut tags = tags
+        .iter()
+        .map(|(k, v)| format!("{}={}", escape_tag(k, "\\,="), escape_tag(v, "\\,")))
+        .collect::<Vec<String>>();
+
+    tags.sort();
+    tags.join(",")
+}
+
+pub fn parse_tags(tags: &str) -> Result<HashMap<String, String>> {
     let mut map = HashMap::default();
 
-    for tag in tags.split(',') {
-        let parts = tag
-            .splitn(2, '=')
-            .map(|x| x.to_string())
-            .collect::<Vec<String>>();
+    if tags.is_empty() {
+        return Ok(map);
+    }
 
-        if parts.len() != 2 {
-            return Err(anyhow!("invalid key=value pair in tags"));
-        }
+    let mut key = String::new();
+    let mut value: Option<String> = None;
+    let mut chars = tags.chars();
+
+    loop {
+        let c = chars.next();
 
-        map.insert(parts[0].clone(), parts[1].clone());
+        match c {
+            Some('\\') => {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| anyhow!("unterminated escape sequence in tags"))?;
+                value.as_mut().unwrap_or(&mut key).push(c);
+            }
+            Some('=') if value.is_none() => value = Some(String::new()),
+            Some(',') | None => {
+                match value.take() {
+                    Some(value) => map.insert(std::mem::take(&mut key), value),
+                    None => return Err(anyhow!("invalid key=value pair in tags")),
+                };
+
+                if c.is_none() {
+                    break;
+                }
+            }
+            Some(c) => value.as_mut().unwrap_or(&mut key).push(c),
+        }
     }
 
     Ok(map)
 }
 
-fn parse_name_only(pairs: Vec<(String, String)>) -> Result<(String, HashMap<String, String>)> {
-    let mut name = String::new();
-    let mut tags = HashMap::default();
+pub(crate) struct Arguments {
+    command: &'static str,
+    args: HashMap<String, String>,
+}
+
+impl Arguments {
+    pub(crate) fn new(
+        command: &'static str,
+        pairs: Vec<(String, String)>,
+        allowed: &[&str],
+    ) -> Result<Self> {
+        let mut args = HashMap::default();
+        let mut seen: HashMap<String, String> = HashMap::default();
+
+        for (key, value) in pairs {
+            let lower = key.to_lowercase();
+
+            if !allowed.contains(&lower.as_str()) {
+                return Err(protocol_error!(
+                    InvalidArgument,
+                    "invalid argument '{}' in {} command",
+                    key,
+                    command
+                ));
+            }
+
+            if let Some(previous) = seen.get(&lower) {
+                return Err(if *previous == key {
+                    protocol_error!(
+                        InvalidArgument,
+                        "duplicate argument '{}' in {} command",
+                        key,
+                        command
+                    )
+                } else {
+                    protocol_error!(
+                        InvalidArgument,
+                        "duplicate argument '{}' in {} command (already given as '{}')",
+                        key,
+                        command,
+                        previous
+                    )
+                });
+            }
 
-    for pair in pairs {
-        let (key, value) = pair;
-        match key.to_lowercase().as_str() {
-            "name" => name = value,
-            "tags" => tags = parse_tags(&value)?,
-            _ => return Err(anyhow!("invalid argument in schedule command")),
+            seen.insert(lower.clone(), key);
+            args.insert(lower, value);
         }
+
+        Ok(Self { command, args })
     }
-    Ok((name, tags))
-}
 
-fn parse_kv_pairs(mut pairs: &str) -> Result<Vec<(String, String)>> {
-    let mut v = Vec::new();
+    pub(crate) fn has(&self, key: &str) -> bool {
+        self.args.contains_key(key)
+    }
 
-    while let Some(captures) = PARSE_KV_PAIR.captures(pairs) {
-        let key = captures.get(1).unwrap();
-        let value = captures.get(2).unwrap();
+    pub(crate) fn optional(&mut self, key: &str) -> Result<Option<String>> {
+        match self.args.remove(key) {
+            Some(value) if value.is_empty() => Err(protocol_error!(
+                InvalidArgument,
+                "{} cannot be empty in {} command",
+                key,
+                self.command
+            )),
+            value => Ok(value),
+        }
+    }
+
+    pub(crate) fn required(&mut self, key: &str) -> Result<String> {
+        match self.args.remove(key) {
+            Some(value) if !value.is_empty() => Ok(value),
+            _ => Err(protocol_error!(
+                InvalidArgument,
+                "{} cannot be omitted in {} command",
+                key,
+                self.command
+            )),
+        }
+    }
 
-        v.push((key.as_str().to_string(), value.as_str().to_string()));
-        let m = captures.get(0).unwrap();
-        pairs = &pairs[m.end()..]
+    pub(crate) fn parsed<T: FromStr>(&mut self, key: &str) -> Result<Option<T>> {
+        match self.args.remove(key) {
+            Some(value) => match value.parse() {
+                Ok(parsed) => Ok(Some(parsed)),
+                Err(_) => Err(protocol_error!(
+                    InvalidArgument,
+                    "invalid value for argument '{}' in {} command: {:?}",
+                    key,
+                    self.command,
+                    value
+                )),
+            },
+            None => Ok(None),
+        }
     }
 
-    Ok(v)
+    pub(crate) fn tags(&mut self) -> Result<HashMap<String, String>> {
+        match self.args.remove("tags") {
+            Some(tags) => parse_tags(&tags).map_err(|e| {
+                protocol_error!(
+                    InvalidArgument,
+                    "invalid value for argument 'tags' in {} command: {}",
+                    self.command,
+                    e
+                )
+            }),
+            None => Ok(HashMap::default()),
+        }
+    }
 }
 
 impl Instruction {
-    fn parse_status(pairs: &str) -> Result<Self> {
-        let (name, tags) = parse_name_only(parse_kv_pairs(pairs)?)?;
+    pub fn cancel() -> Self {
+        Self {
+            command: Command::Cancel,
+            tags: HashMap::default(),
+        }
+    }
+
+    pub fn is_cancel(&self) -> bool {
+        self.command == Command::Cancel
+    }
+
+    pub fn watch_filter(&self) -> Option<WatchFilter> {
+        match &self.command {
+            Command::Watch(name, _) => Some(WatchFilter::new(name.clone(), self.tags.clone())),
+            _ => None,
+        }
+    }
+
+    fn parse_watch(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("watch", pairs, &["name", "since", "tags"])?;
 
         Ok(Self {
-            command: Command::Status(if name.is_empty() { None } else { Some(name) }),
-            tags,
+            command: Command::Watch(args.optional("name")?, args.parsed("since")?),
+            tags: args.tags()?,
         })
     }
 
-    fn parse_terminate(pairs: &str) -> Result<Self> {
-        let (name, tags) = parse_name_only(parse_kv_pairs(pairs)?)?;
+    fn parse_cancel(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("cancel", pairs, &["tags"])?;
 
-        if name.is_empty() {
-            return Err(anyhow!("name cannot be omitted"));
-        }
+        Ok(Self {
+            command: Command::Cancel,
+            tags: args.tags()?,
+        })
+    }
+
+    fn parse_quota(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("quota", pairs, &["user", "tags"])?;
 
         Ok(Self {
-            command: Command::Terminate(name),
-            tags,
+            command: Command::Quota(args.optional("user")?),
+            tags: args.tags()?,
         })
     }
 
-    fn parse_schedule(pairs: &str) -> Result<Self> {
-        let mut name = String::new();
-        let mut image = String::new();
-        let mut kind = String::new();
-        let mut tags = HashMap::default();
+    fn parse_secret(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("secret", pairs, &["name", "value", "tags"])?;
 
-        let pairs = parse_kv_pairs(pairs)?;
+        Ok(Self {
+            command: Command::Secret(args.required("name")?, args.required("value")?),
+            tags: args.tags()?,
+        })
+    }
 
-        for pair in pairs {
-            let (key, value) = pair;
+    fn parse_status(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("status", pairs, &["name", "tags"])?;
 
-            match key.as_str().to_lowercase().as_str() {
-                "name" => name = value.as_str().to_string(),
-                "image" => image = value.as_str().to_string(),
-                "kind" => kind = value.as_str().to_string(),
-                "tags" => tags = parse_tags(value.as_str())?,
-                _ => return Err(anyhow!("invalid argument in schedule command")),
-            }
-        }
+        Ok(Self {
+            command: Command::Status(args.optional("name")?),
+            tags: args.tags()?,
+        })
+    }
 
-        if name.is_empty() {
-            return Err(anyhow!("name cannot be omitted"));
-        }
+    fn parse_terminate(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("terminate", pairs, &["name", "all", "tags"])?;
 
-        if image.is_empty() {
-            return Err(anyhow!("image cannot be omitted"));
-        }
+        let all = args.parsed("all")?.unwrap_or_default();
+        let tagged = args.has("tags");
 
-        if kind.is_empty() {
-            return Err(anyhow!("kind cannot be omitted"));
+        let instruction = Self {
+            command: Command::Terminate(args.optional("name")?, all),
+            tags: args.tags()?,
+        };
+
+        if tagged && instruction.tags.is_empty() {
+            return Err(protocol_error!(
+                InvalidArgument,
+                "tags cannot be empty in terminate command"
+            ));
         }
 
+        instruction.selector().transpose()?;
+        Ok(instruction)
+    }
+
+    fn parse_lifecycle(
+        command: &'static str,
+        pairs: Vec<(String, String)>,
+        f: fn(String) -> Command,
+    ) -> Result<Self> {
+        let mut args = Arguments::new(command, pairs, &["name", "tags"])?;
+
+        Ok(Self {
+            command: f(args.required("name")?),
+            tags: args.tags()?,
+        })
+    }
+
+    fn parse_exec(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("exec", pairs, &["name", "cmd", "tags"])?;
+
+        Ok(Self {
+            command: Command::Exec(args.required("name")?, args.required("cmd")?),
+            tags: args.tags()?,
+        })
+    }
+
+    fn parse_logs(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args =
+            Arguments::new("logs", pairs, &["name", "since", "lines", "follow", "tags"])?;
+
+        Ok(Self {
+            command: Command::Logs(
+                args.required("name")?,
+                args.optional("since")?,
+                args.parsed("lines")?,
+                args.parsed("follow")?.unwrap_or_default(),
+            ),
+            tags: args.tags()?,
+        })
+    }
+
+    fn parse_manifest(
+        command: &'static str,
+        pairs: Vec<(String, String)>,
+        f: fn(Manifest) -> Command,
+    ) -> Result<Self> {
+        let mut args = Arguments::new(command, pairs, &["manifest", "tags"])?;
+
+        let manifest = serde_yaml::from_str(&args.required("manifest")?).map_err(|e| {
+            protocol_error!(
+                InvalidArgument,
+                "invalid value for argument 'manifest' in {} command: {}",
+                command,
+                e
+            )
+        })?;
+
+        Ok(Self {
+            command: f(manifest),
+            tags: args.tags()?,
+        })
+    }
+
+    fn parse_history(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("history", pairs, &["schedule", "tags"])?;
+
+        Ok(Self {
+            command: Command::History(args.parsed("schedule")?),
+            tags: args.tags()?,
+        })
+    }
+
+    fn parse_rollback(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("rollback", pairs, &["revision", "schedule", "tags"])?;
+
+        let revision = args.parsed("revision")?.ok_or_else(|| {
+            protocol_error!(
+                InvalidArgument,
+                "revision cannot be omitted in rollback command"
+            )
+        })?;
+
+        Ok(Self {
+            command: Command::Rollback(revision, args.parsed("schedule")?),
+            tags: args.tags()?,
+        })
+    }
+
+    fn parse_scale(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("scale", pairs, &["name", "replicas", "schedule", "tags"])?;
+
+        let name = args.required("name")?;
+        let replicas = args.parsed("replicas")?.ok_or_else(|| {
+            protocol_error!(
+                InvalidArgument,
+                "replicas cannot be omitted in scale command"
+            )
+        })?;
+
+        Ok(Self {
+            command: Command::Scale(name, replicas, args.parsed("schedule")?),
+            tags: args.tags()?,
+        })
+    }
+
+    fn parse_schedule(pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut args = Arguments::new("schedule", pairs, &["name", "image", "kind", "tags"])?;
+
+        let name = args.required("name")?;
+        let image = args.required("image")?;
+        let kind = args.required("kind")?;
+        let kind = Kind::from_str(&kind).map_err(|e| {
+            protocol_error!(
+                InvalidArgument,
+                "invalid value for argument 'kind' in schedule command: {}",
+                e
+            )
+        })?;
+
         Ok(Self {
-            command: Command::Schedule(name, image, Kind::from_str(&kind)?),
-            tags,
+            command: Command::Schedule(name, image, kind),
+            tags: args.tags()?,
         })
     }
 }
@@ -172,14 +582,39 @@ impl FromStr for Instruction {
 
     fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
         if let Some(captures) = PARSE_INSTRUCTION.captures(s) {
+            let args = captures.get(2).unwrap();
+            let pairs = tokenize(args.as_str(), s[..args.start()].chars().count())?;
+
             match captures.get(1).unwrap().as_str().to_lowercase().as_str() {
-                "schedule" => Self::parse_schedule(captures.get(2).unwrap().as_str()),
-                "terminate" => Self::parse_terminate(captures.get(2).unwrap().as_str()),
-                "status" => Self::parse_status(captures.get(2).unwrap().as_str()),
-                x => Err(anyhow!("invalid command in request: {:?}", x)),
+                "schedule" => Self::parse_schedule(pairs),
+                "terminate" => Self::parse_terminate(pairs),
+                "start" => Self::parse_lifecycle("start", pairs, Command::Start),
+                "stop" => Self::parse_lifecycle("stop", pairs, Command::Stop),
+                "restart" => Self::parse_lifecycle("restart", pairs, Command::Restart),
+                "exec" => Self::parse_exec(pairs),
+                "logs" => Self::parse_logs(pairs),
+                "apply" => Self::parse_manifest("apply", pairs, Command::Apply),
+                "plan" => Self::parse_manifest("plan", pairs, Command::Plan),
+                "history" => Self::parse_history(pairs),
+                "rollback" => Self::parse_rollback(pairs),
+                "scale" => Self::parse_scale(pairs),
+                "status" => Self::parse_status(pairs),
+                "quota" => Self::parse_quota(pairs),
+                "secret" => Self::parse_secret(pairs),
+                "watch" => Self::parse_watch(pairs),
+                "cancel" => Self::parse_cancel(pairs),
+                x => Err(protocol_error!(
+                    InvalidCommand,
+                    "invalid command in request: {:?}",
+                    x
+                )),
             }
         } else {
-            Err(anyhow!("no command specified. Input: {:?}", s))
+            Err(protocol_error!(
+                Parse,
+                "no command specified. Input: {:?}",
+                s
+            ))
         }
     }
 }
@@ -187,7 +622,9 @@ impl FromStr for Instruction {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::{ErrorCode, ParseError};
     use crate::testdata::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_display_methods() -> Result<()> {
@@ -204,10 +641,305 @@ mod tests {
             assert_eq!(text.parse::<Instruction>()?, *result, "{}", annotation);
         }
 
+        assert_eq!("cancel".parse::<Instruction>()?, Instruction::cancel());
+        assert_eq!(Instruction::cancel().to_string(), "cancel");
+
         for (text, annotation) in &*RED_TABLE {
             assert!(text.parse::<Instruction>().is_err(), "{}", annotation);
         }
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_escapes() -> Result<()> {
+        let instruction: Instruction =
+            r#"schedule name="a\"b" image="c\\d\ne" kind=nspawn tags="k\\=ey=v\\,alue,x=""#
+                .parse()?;
+
+        let mut tags = HashMap::default();
+        tags.insert("k=ey".to_string(), "v,alue".to_string());
+        tags.insert("x".to_string(), String::new());
+
+        assert_eq!(
+            instruction,
+            Instruction {
+                command: Command::Schedule(
+                    "a\"b".to_string(),
+                    "c\\d\ne".to_string(),
+                    Kind::Systemd(SystemdKind::NSpawn)
+                ),
+                tags,
+            }
+        );
+
+        assert_eq!(instruction.to_string().parse::<Instruction>()?, instruction);
+
+        let err = r#"terminate name="foo"#.parse::<Instruction>().unwrap_err();
+        assert_eq!(err.downcast_ref::<ParseError>().unwrap().position, 15);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let table = vec![
+            (
+                r#"schedule name="a" garbage image="b" kind="nspawn""#,
+                "expected '=' after key 'garbage'",
+            ),
+            (
+                r#"schedule name="a" image="b" kind="nspawn" name="c""#,
+                "duplicate argument 'name' in schedule command",
+            ),
+            (
+                r#"schedule name="a" image="b" kind="nspawn" NAME="c""#,
+                "duplicate argument 'NAME' in schedule command (already given as 'name')",
+            ),
+            (
+                r#"schedule name="a" image="b" kind="nspawn" color="red""#,
+                "invalid argument 'color' in schedule command",
+            ),
+            (
+                r#"schedule name="a" image="b" kind="bogus""#,
+                "invalid value for argument 'kind' in schedule command",
+            ),
+            (
+                r#"schedule name="a" kind="nspawn""#,
+                "image cannot be omitted in schedule command",
+            ),
+            (
+                r#"status image="b""#,
+                "invalid argument 'image' in status command",
+            ),
+            (
+                r#"terminate name="a" Name="b""#,
+                "duplicate argument 'Name' in terminate command",
+            ),
+            (
+                r#"terminate tags="one=foo""#,
+                "terminating by tags requires all=\"true\" in terminate command",
+            ),
+            (
+                r#"terminate tags="one=foo" all="yes""#,
+                "invalid value for argument 'all' in terminate command",
+            ),
+            (
+                r#"terminate tags="" all="true""#,
+                "tags cannot be empty in terminate command",
+            ),
+            (
+                r#"terminate name="""#,
+                "name cannot be empty in terminate command",
+            ),
+            (
+                r#"status name="""#,
+                "name cannot be empty in status command",
+            ),
+            (r#"quota user="""#, "user cannot be empty in quota command"),
+            (
+                r#"logs name="a" since="""#,
+                "since cannot be empty in logs command",
+            ),
+            (r#"watch name="""#, "name cannot be empty in watch command"),
+            (
+                r#"terminate name="a" all="true""#,
+                "name cannot be combined with all=\"true\" in terminate command",
+            ),
+            (
+                r#"terminate name="a" tags="one=foo""#,
+                "name cannot be combined with tags in terminate command",
+            ),
+            (
+                r#"terminate all="false""#,
+                "name or tags must be given in terminate command",
+            ),
+            (
+                r#"terminate name="a" tags="one""#,
+                "invalid value for argument 'tags' in terminate command",
+            ),
+            (
+                r#"quota user="a" name="b""#,
+                "invalid argument 'name' in quota command",
+            ),
+            (
+                r#"restart tags="one=foo""#,
+                "name cannot be omitted in restart command",
+            ),
+            (
+                r#"stop name="a" all="true""#,
+                "invalid argument 'all' in stop command",
+            ),
+            (r#"exec name="a""#, "cmd cannot be omitted in exec command"),
+            (
+                r#"secret name="a""#,
+                "value cannot be omitted in secret command",
+            ),
+            (
+                r#"logs name="a" lines="ten""#,
+                "invalid value for argument 'lines' in logs command",
+            ),
+            (
+                r#"logs name="a" follow="1""#,
+                "invalid value for argument 'follow' in logs command",
+            ),
+            (
+                r#"apply manifest="commands: 3""#,
+                "invalid value for argument 'manifest' in apply command",
+            ),
+            (
+                r#"plan manifest="""#,
+                "manifest cannot be omitted in plan command",
+            ),
+            (
+                r#"rollback schedule="1""#,
+                "revision cannot be omitted in rollback command",
+            ),
+            (
+                r#"rollback revision="latest""#,
+                "invalid value for argument 'revision' in rollback command",
+            ),
+            (
+                r#"scale replicas="3""#,
+                "name cannot be omitted in scale command",
+            ),
+            (
+                r#"scale name="web""#,
+                "replicas cannot be omitted in scale command",
+            ),
+            (
+                r#"scale name="web" replicas="-1""#,
+                "invalid value for argument 'replicas' in scale command",
+            ),
+            (
+                r#"cancel name="a""#,
+                "invalid argument 'name' in cancel command",
+            ),
+            (
+                r#"watch since="-1""#,
+                "invalid value for argument 'since' in watch command",
+            ),
+            (
+                r#"watch user="erikh""#,
+                "invalid argument 'user' in watch command",
+            ),
+        ];
+
+        for (text, message) in table {
+            let err = text.parse::<Instruction>().unwrap_err().to_string();
+            assert!(err.contains(message), "{:?}: {}", text, err);
+        }
+
+        let table = vec![
+            (r#"schedule name="a"#, ErrorCode::Parse),
+            ("", ErrorCode::Parse),
+            (r#"frobnicate name="a""#, ErrorCode::InvalidCommand),
+            (r#"status image="b""#, ErrorCode::InvalidArgument),
+            (r#"status name="a" name="b""#, ErrorCode::InvalidArgument),
+            (r#"terminate tags="one=foo""#, ErrorCode::InvalidArgument),
+            (
+                r#"schedule name="a" image="b" kind="bogus""#,
+                ErrorCode::InvalidArgument,
+            ),
+        ];
+
+        for (text, code) in table {
+            let err = text.parse::<Instruction>().unwrap_err();
+            assert_eq!(ErrorCode::of(&err), code, "{:?}: {}", text, err);
+        }
+    }
+
+    #[test]
+    fn test_parse_apply() -> Result<()> {
+        let manifest = Manifest::from_file(std::path::Path::new("testdata/combined-one.yaml"))?;
+        let yaml = std::fs::read_to_string("testdata/combined-one.yaml")?;
+        let json = serde_json::to_string(&manifest)?;
+
+        for (command, body) in [("apply", yaml), ("plan", json)] {
+            let instruction: Instruction =
+                format!("{} manifest={}", command, quote(&body)).parse()?;
+            assert_eq!(instruction.command.name(), command);
+            assert!(matches!(
+                &instruction.command,
+                Command::Apply(x) | Command::Plan(x) if *x == manifest
+            ));
+            assert_eq!(instruction.to_string().parse::<Instruction>()?, instruction);
+            assert_eq!(
+                serde_json::from_str::<Instruction>(&serde_json::to_string(&instruction)?)?,
+                instruction
+            );
+        }
+
+        Ok(())
+    }
+
+    fn kind_strategy() -> impl Strategy<Value = Kind> {
+        prop_oneof![
+            Just(Kind::Systemd(SystemdKind::Timer)),
+            Just(Kind::Systemd(SystemdKind::NSpawn)),
+            Just(Kind::Systemd(SystemdKind::Machine)),
+            Just(Kind::Systemd(SystemdKind::OneShot)),
+            Just(Kind::Systemd(SystemdKind::Service)),
+            Just(Kind::Other),
+        ]
+    }
+
+    fn command_strategy() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            ("(?s).+", "(?s).+", kind_strategy())
+                .prop_map(|(name, image, kind)| Command::Schedule(name, image, kind)),
+            "(?s).+".prop_map(|name| Command::Terminate(Some(name), false)),
+            "(?s).+".prop_map(Command::Start),
+            "(?s).+".prop_map(Command::Stop),
+            "(?s).+".prop_map(Command::Restart),
+            ("(?s).+", "(?s).+").prop_map(|(name, cmd)| Command::Exec(name, cmd)),
+            (
+                "(?s).+",
+                proptest::option::of("(?s).+"),
+                proptest::option::of(any::<u64>()),
+                any::<bool>()
+            )
+                .prop_map(|(name, since, lines, follow)| Command::Logs(name, since, lines, follow)),
+            proptest::option::of(any::<i64>()).prop_map(Command::History),
+            (any::<u64>(), proptest::option::of(any::<i64>()))
+                .prop_map(|(revision, schedule)| Command::Rollback(revision, schedule)),
+            ("(?s).+", any::<u64>(), proptest::option::of(any::<i64>()))
+                .prop_map(|(name, replicas, schedule)| Command::Scale(name, replicas, schedule)),
+            proptest::option::of("(?s).+").prop_map(Command::Status),
+            proptest::option::of("(?s).+").prop_map(Command::Quota),
+            ("(?s).+", "(?s).+").prop_map(|(name, value)| Command::Secret(name, value)),
+            (
+                proptest::option::of("(?s).+"),
+                proptest::option::of(any::<u64>())
+            )
+                .prop_map(|(name, since)| Command::Watch(name, since)),
+            Just(Command::Cancel),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip(
+            command in command_strategy(),
+            tags in proptest::collection::hash_map("(?s).*", "(?s).*", 0..4),
+        ) {
+            prop_assume!(!matches!(command, Command::Terminate(..)) || tags.is_empty());
+
+            let instruction = Instruction { command, tags };
+            let text = instruction.to_string();
+            prop_assert_eq!(text.parse::<Instruction>().ok(), Some(instruction.clone()), "{:?}", text);
+
+            let json = serde_json::to_string(&instruction).unwrap();
+            prop_assert_eq!(serde_json::from_str::<Instruction>(&json).ok(), Some(instruction), "{:?}", json);
+        }
+
+        #[test]
+        fn test_bulk_terminate_roundtrip(
+            tags in proptest::collection::hash_map("(?s).*", "(?s).*", 0..4),
+        ) {
+            let instruction = Instruction { command: Command::Terminate(None, true), tags };
+            let text = instruction.to_string();
+            prop_assert_eq!(text.parse::<Instruction>().ok(), Some(instruction), "{:?}", text);
+        }
+    }
 }
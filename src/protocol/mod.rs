@@ -1,5 +1,19 @@
+pub mod encoding;
+pub mod error;
+pub mod event;
+pub mod handshake;
 pub mod instruction;
+pub mod payload;
 pub mod response;
+pub mod selector;
+pub mod tokenizer;
 
+pub use encoding::*;
+pub use error::*;
+pub use event::*;
+pub use handshake::*;
 pub use instruction::*;
+pub use payload::*;
 pub use response::*;
+pub use selector::*;
+pub use tokenizer::ParseError;
@@ -0,0 +1,379 @@
+use super::event::Event;
+use crate::common::Kind;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Workloads(Vec<WorkloadStatus>),
+    Nodes(Vec<NodeSummary>),
+    Receipt(ScheduleReceipt),
+    Quota(QuotaReport),
+    Event(Event),
+    Output(OutputChunk),
+    Exit(ExecResult),
+    Logs(Vec<LogEntry>),
+    Apply(Vec<CommandResult>),
+    Plan(Vec<PlanEntry>),
+    History(Vec<RevisionSummary>),
+    #[serde(untagged)]
+    Map(HashMap<String, String>),
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self::Map(Default::default())
+    }
+}
+
+impl From<OutputChunk> for Payload {
+    fn from(value: OutputChunk) -> Self {
+        Self::Output(value)
+    }
+}
+
+impl From<ExecResult> for Payload {
+    fn from(value: ExecResult) -> Self {
+        Self::Exit(value)
+    }
+}
+
+impl From<HashMap<String, String>> for Payload {
+    fn from(value: HashMap<String, String>) -> Self {
+        Self::Map(value)
+    }
+}
+
+impl Payload {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        match self {
+            Self::Map(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Workloads(x) => x.is_empty(),
+            Self::Nodes(x) => x.is_empty(),
+            Self::Logs(x) => x.is_empty(),
+            Self::Apply(x) => x.is_empty(),
+            Self::Plan(x) => x.is_empty(),
+            Self::History(x) => x.is_empty(),
+            Self::Map(x) => x.is_empty(),
+            Self::Receipt(_)
+            | Self::Quota(_)
+            | Self::Event(_)
+            | Self::Output(_)
+            | Self::Exit(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkloadState {
+    Pending,
+    Running,
+    Stopped,
+    Failed,
+    Terminated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkloadStatus {
+    pub name: String,
+    pub kind: Kind,
+    pub image: String,
+    pub state: WorkloadState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tags: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<Health>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Healthy,
+    Unhealthy(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resources {
+    pub cpu: u64,
+    pub mem: u64,
+    pub storage: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeSummary {
+    pub name: String,
+    pub address: String,
+    pub alive: bool,
+    pub resources: Resources,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_queried: Option<NaiveDateTime>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleReceipt {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    pub scheduled: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limit {
+    pub used: u64,
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaReport {
+    pub user: String,
+    pub workloads: Limit,
+    pub cpu: Limit,
+    pub mem: Limit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecResult {
+    pub code: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub timestamp: NaiveDateTime,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandResult {
+    pub name: String,
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    pub status: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    Create,
+    Update,
+    Move,
+    Delete,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub name: String,
+    pub command: String,
+    pub action: PlanAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevisionSummary {
+    pub revision: u64,
+    pub author: String,
+    pub timestamp: NaiveDateTime,
+    pub current: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SystemdKind;
+    use crate::protocol::EventKind;
+    use anyhow::Result;
+    use chrono::DateTime;
+
+    #[test]
+    fn test_payload_json() -> Result<()> {
+        let mut map = HashMap::default();
+        map.insert("encoding".to_string(), "json".to_string());
+
+        let table = vec![
+            (Payload::default(), "{}", "empty map"),
+            (
+                Payload::from(map),
+                "{\"encoding\":\"json\"}",
+                "flat map",
+            ),
+            (
+                Payload::Workloads(vec![WorkloadStatus {
+                    name: "foo".to_string(),
+                    kind: Kind::Systemd(SystemdKind::NSpawn),
+                    image: "nginx".to_string(),
+                    state: WorkloadState::Running,
+                    node: Some("node1".to_string()),
+                    tags: Default::default(),
+                    health: Some(Health::Healthy),
+                }]),
+                "{\"workloads\":[{\"name\":\"foo\",\"kind\":{\"systemd\":\"nspawn\"},\"image\":\"nginx\",\"state\":\"running\",\"node\":\"node1\",\"health\":\"healthy\"}]}",
+                "workload list",
+            ),
+            (Payload::Workloads(vec![]), "{\"workloads\":[]}", "empty workload list"),
+            (
+                Payload::Workloads(vec![WorkloadStatus {
+                    name: "foo".to_string(),
+                    kind: Kind::Systemd(SystemdKind::NSpawn),
+                    image: "nginx".to_string(),
+                    state: WorkloadState::Failed,
+                    node: None,
+                    tags: Default::default(),
+                    health: Some(Health::Unhealthy("connection refused".to_string())),
+                }]),
+                "{\"workloads\":[{\"name\":\"foo\",\"kind\":{\"systemd\":\"nspawn\"},\"image\":\"nginx\",\"state\":\"failed\",\"health\":{\"unhealthy\":\"connection refused\"}}]}",
+                "unhealthy workload",
+            ),
+            (Payload::Nodes(vec![]), "{\"nodes\":[]}", "empty node list"),
+            (
+                Payload::Nodes(vec![NodeSummary {
+                    name: "node1".to_string(),
+                    address: "10.0.0.1".to_string(),
+                    alive: true,
+                    resources: Resources {
+                        cpu: 4,
+                        mem: 8192,
+                        storage: 100,
+                    },
+                    last_queried: None,
+                    labels: Default::default(),
+                }]),
+                "{\"nodes\":[{\"name\":\"node1\",\"address\":\"10.0.0.1\",\"alive\":true,\"resources\":{\"cpu\":4,\"mem\":8192,\"storage\":100}}]}",
+                "node list",
+            ),
+            (
+                Payload::Receipt(ScheduleReceipt {
+                    name: "foo".to_string(),
+                    node: None,
+                    scheduled: true,
+                }),
+                "{\"receipt\":{\"name\":\"foo\",\"scheduled\":true}}",
+                "schedule receipt",
+            ),
+            (
+                Payload::Quota(QuotaReport {
+                    user: "erikh".to_string(),
+                    workloads: Limit { used: 1, limit: 2 },
+                    cpu: Limit { used: 3, limit: 4 },
+                    mem: Limit { used: 5, limit: 6 },
+                }),
+                "{\"quota\":{\"user\":\"erikh\",\"workloads\":{\"used\":1,\"limit\":2},\"cpu\":{\"used\":3,\"limit\":4},\"mem\":{\"used\":5,\"limit\":6}}}",
+                "quota report",
+            ),
+            (
+                Payload::Event(Event {
+                    sequence: 7,
+                    kind: EventKind::NodeDown,
+                    name: "node1".to_string(),
+                    node: None,
+                    tags: Default::default(),
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                }),
+                "{\"event\":{\"sequence\":7,\"kind\":\"node_down\",\"name\":\"node1\",\"timestamp\":\"1970-01-01T00:00:00\"}}",
+                "event",
+            ),
+            (
+                Payload::Output(OutputChunk {
+                    stream: OutputStream::Stderr,
+                    data: "oops".to_string(),
+                }),
+                "{\"output\":{\"stream\":\"stderr\",\"data\":\"oops\"}}",
+                "exec output",
+            ),
+            (
+                Payload::Exit(ExecResult { code: Some(3) }),
+                "{\"exit\":{\"code\":3}}",
+                "exec exit code",
+            ),
+            (
+                Payload::Logs(vec![LogEntry {
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    message: "started".to_string(),
+                }]),
+                "{\"logs\":[{\"timestamp\":\"1970-01-01T00:00:00\",\"message\":\"started\"}]}",
+                "log page",
+            ),
+            (
+                Payload::Apply(vec![CommandResult {
+                    name: "foo".to_string(),
+                    command: "schedule".to_string(),
+                    node: Some("node1".to_string()),
+                    status: true,
+                    error: None,
+                }]),
+                "{\"apply\":[{\"name\":\"foo\",\"command\":\"schedule\",\"node\":\"node1\",\"status\":true}]}",
+                "apply results",
+            ),
+            (
+                Payload::Plan(vec![PlanEntry {
+                    name: "foo".to_string(),
+                    command: "schedule".to_string(),
+                    action: PlanAction::Move,
+                    node: Some("node2".to_string()),
+                    previous: Some("node1".to_string()),
+                    error: None,
+                }]),
+                "{\"plan\":[{\"name\":\"foo\",\"command\":\"schedule\",\"action\":\"move\",\"node\":\"node2\",\"previous\":\"node1\"}]}",
+                "plan diff",
+            ),
+            (
+                Payload::History(vec![RevisionSummary {
+                    revision: 2,
+                    author: "erikh".to_string(),
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    current: true,
+                }]),
+                "{\"history\":[{\"revision\":2,\"author\":\"erikh\",\"timestamp\":\"1970-01-01T00:00:00\",\"current\":true}]}",
+                "revision history",
+            ),
+        ];
+
+        for (payload, json, annotation) in table {
+            assert_eq!(serde_json::to_string(&payload)?, json, "{}", annotation);
+            assert_eq!(
+                serde_json::from_str::<Payload>(json)?,
+                payload,
+                "{}",
+                annotation
+            );
+        }
+
+        let legacy: Payload = serde_json::from_str("{\"workloads\":\"3\"}")?;
+        assert_eq!(legacy.get("workloads").unwrap(), "3");
+
+        Ok(())
+    }
+}
@@ -1,14 +1,66 @@
+use super::{ErrorCode, Payload, ProtocolError};
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Response {
     pub status: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
     pub timestamp: NaiveDateTime,
-    pub payload: HashMap<String, String>,
+    pub payload: Payload,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub more: bool,
+}
+
+impl Response {
+    pub fn ok(payload: impl Into<Payload>) -> Self {
+        Self {
+            status: true,
+            error: None,
+            code: None,
+            timestamp: chrono::Local::now().naive_local(),
+            payload: payload.into(),
+            more: false,
+        }
+    }
+
+    pub fn partial(payload: impl Into<Payload>) -> Self {
+        Self {
+            more: true,
+            ..Self::ok(payload)
+        }
+    }
+
+    pub fn error(code: ErrorCode, error: impl ToString) -> Self {
+        Self {
+            status: false,
+            error: Some(error.to_string()),
+            code: Some(code),
+            timestamp: chrono::Local::now().naive_local(),
+            payload: Default::default(),
+            more: false,
+        }
+    }
+
+    pub fn into_result(self) -> Result<Self, ProtocolError> {
+        if self.status {
+            return Ok(self);
+        }
+
+        Err(ProtocolError::new(
+            self.code.unwrap_or(ErrorCode::Internal),
+            self.error.unwrap_or_default(),
+        ))
+    }
+}
+
+impl From<&anyhow::Error> for Response {
+    fn from(error: &anyhow::Error) -> Self {
+        Self::error(ErrorCode::of(error), error)
+    }
 }
 
 impl std::fmt::Display for Response {
@@ -21,6 +73,8 @@ impl std::fmt::Display for Response {
 mod tests {
     use super::*;
     use anyhow::Result;
+    use chrono::DateTime;
+    use std::collections::HashMap;
 
     #[test]
     fn test_to_string() -> Result<()> {
@@ -33,8 +87,10 @@ mod tests {
                 Response {
                     status: true,
                     error: None,
-                    timestamp: NaiveDateTime::UNIX_EPOCH,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
                     payload: Default::default(),
+                    more: false,
                 },
                 "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}",
                 "basic response",
@@ -43,8 +99,10 @@ mod tests {
                 Response {
                     status: true,
                     error: Some(String::from("this is an error")),
-                    timestamp: NaiveDateTime::UNIX_EPOCH,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
                     payload: Default::default(),
+                    more: false,
                 },
                 "{\"status\":true,\"error\":\"this is an error\",\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}",
                 "basic response with error",
@@ -53,8 +111,10 @@ mod tests {
                 Response {
                     status: true,
                     error: None,
-                    timestamp: NaiveDateTime::UNIX_EPOCH,
-                    payload: payload.clone(),
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: payload.clone().into(),
+                    more: false,
                 },
                 "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{\"testing\":\"payload\"}}",
                 "basic response with payload",
@@ -63,18 +123,64 @@ mod tests {
                 Response {
                     status: true,
                     error: Some(String::from("this is an error")),
-                    timestamp: NaiveDateTime::UNIX_EPOCH,
-                    payload,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: payload.into(),
+                    more: false,
                 },
                 "{\"status\":true,\"error\":\"this is an error\",\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{\"testing\":\"payload\"}}",
                 "basic response with error and payload",
             ),
+            (
+                Response {
+                    status: false,
+                    error: Some(String::from("no workload named 'foo'")),
+                    code: Some(ErrorCode::NotFound),
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: false,
+                },
+                "{\"status\":false,\"error\":\"no workload named 'foo'\",\"code\":\"not_found\",\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{}}",
+                "error response with code",
+            ),
+            (
+                Response {
+                    status: true,
+                    error: None,
+                    code: None,
+                    timestamp: DateTime::UNIX_EPOCH.naive_utc(),
+                    payload: Default::default(),
+                    more: true,
+                },
+                "{\"status\":true,\"timestamp\":\"1970-01-01T00:00:00\",\"payload\":{},\"more\":true}",
+                "streamed response",
+            ),
         ];
 
         for (input, result, annotation) in table {
             assert_eq!(input.to_string(), result, "{}", annotation);
+            assert_eq!(
+                serde_json::from_str::<Response>(result)?,
+                input,
+                "{}",
+                annotation
+            );
         }
 
         Ok(())
     }
+
+    #[test]
+    fn test_error_response() {
+        let response = Response::from(&crate::protocol_error!(NotFound, "no workload named 'foo'"));
+        assert!(!response.status);
+        assert_eq!(response.code, Some(ErrorCode::NotFound));
+        assert_eq!(response.error.as_deref(), Some("no workload named 'foo'"));
+
+        let err = response.into_result().unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert!(Response::ok(Payload::default()).into_result().is_ok());
+        assert!(Response::partial(Payload::default()).more);
+        assert!(!Response::ok(Payload::default()).more);
+    }
 }
@@ -0,0 +1,172 @@
+use super::instruction::{Command, Instruction};
+use super::payload::WorkloadStatus;
+use crate::protocol_error;
+use anyhow::Result;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    Name(String),
+    Tags(HashMap<String, String>),
+    All,
+}
+
+impl Selector {
+    pub fn is_bulk(&self) -> bool {
+        !matches!(self, Self::Name(_))
+    }
+
+    pub fn matches(&self, workload: &WorkloadStatus) -> bool {
+        match self {
+            Self::Name(name) => workload.name == *name,
+            Self::Tags(tags) => tags
+                .iter()
+                .all(|(k, v)| workload.tags.get(k).is_some_and(|x| x == v)),
+            Self::All => true,
+        }
+    }
+
+    pub fn select<'a>(&self, workloads: &'a [WorkloadStatus]) -> Vec<&'a WorkloadStatus> {
+        workloads.iter().filter(|x| self.matches(x)).collect()
+    }
+}
+
+impl Instruction {
+    pub fn selector(&self) -> Option<Result<Selector>> {
+        match &self.command {
+            Command::Status(name) => Some(Ok(match name {
+                Some(name) => Selector::Name(name.clone()),
+                None if !self.tags.is_empty() => Selector::Tags(self.tags.clone()),
+                None => Selector::All,
+            })),
+            Command::Terminate(name, all) => Some(match name {
+                Some(_) if *all => Err(protocol_error!(
+                    InvalidArgument,
+                    "name cannot be combined with all=\"true\" in terminate command"
+                )),
+                Some(_) if !self.tags.is_empty() => Err(protocol_error!(
+                    InvalidArgument,
+                    "name cannot be combined with tags in terminate command"
+                )),
+                Some(name) => Ok(Selector::Name(name.clone())),
+                None if !*all && self.tags.is_empty() => Err(protocol_error!(
+                    InvalidArgument,
+                    "name or tags must be given in terminate command"
+                )),
+                None if !*all => Err(protocol_error!(
+                    InvalidArgument,
+                    "terminating by tags requires all=\"true\" in terminate command"
+                )),
+                None if !self.tags.is_empty() => Ok(Selector::Tags(self.tags.clone())),
+                None => Ok(Selector::All),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::*;
+    use crate::protocol::{ErrorCode, WorkloadState};
+
+    fn workload(name: &str, tags: &[(&str, &str)]) -> WorkloadStatus {
+        WorkloadStatus {
+            name: name.to_string(),
+            kind: Kind::Systemd(SystemdKind::NSpawn),
+            image: "linux".to_string(),
+            state: WorkloadState::Running,
+            node: None,
+            tags: tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            health: None,
+        }
+    }
+
+    #[test]
+    fn test_selector() -> Result<()> {
+        let workloads = vec![
+            workload("web", &[("team", "infra"), ("tier", "web")]),
+            workload("db", &[("team", "infra"), ("tier", "db")]),
+            workload("app", &[("team", "product")]),
+        ];
+
+        let table = vec![
+            ("status", vec!["web", "db", "app"], "status of everything"),
+            (r#"status name="db""#, vec!["db"], "status by name"),
+            (
+                r#"status tags="team=infra""#,
+                vec!["web", "db"],
+                "status by tag",
+            ),
+            (
+                r#"status tags="team=infra,tier=web""#,
+                vec!["web"],
+                "status by several tags",
+            ),
+            (r#"status tags="team=ops""#, vec![], "status with no match"),
+            (r#"terminate name="app""#, vec!["app"], "terminate by name"),
+            (
+                r#"terminate tags="team=infra" all="true""#,
+                vec!["web", "db"],
+                "terminate by tag",
+            ),
+            (
+                r#"terminate all="true""#,
+                vec!["web", "db", "app"],
+                "terminate everything",
+            ),
+        ];
+
+        for (text, names, annotation) in table {
+            let selector = text.parse::<Instruction>()?.selector().unwrap()?;
+            assert_eq!(
+                selector
+                    .select(&workloads)
+                    .iter()
+                    .map(|x| x.name.as_str())
+                    .collect::<Vec<&str>>(),
+                names,
+                "{}",
+                annotation
+            );
+        }
+
+        assert!(!Selector::Name("web".to_string()).is_bulk());
+        assert!(Selector::All.is_bulk());
+        assert!(r#"quota"#.parse::<Instruction>()?.selector().is_none());
+
+        let ambiguous = vec![
+            (
+                Command::Terminate(None, false),
+                workloads[0].tags.clone(),
+                "tags without all",
+            ),
+            (
+                Command::Terminate(Some("web".to_string()), true),
+                HashMap::default(),
+                "name with all",
+            ),
+            (
+                Command::Terminate(Some("web".to_string()), false),
+                workloads[0].tags.clone(),
+                "name with tags",
+            ),
+        ];
+
+        for (command, tags, annotation) in ambiguous {
+            let instruction = Instruction { command, tags };
+            assert_eq!(
+                ErrorCode::of(&instruction.selector().unwrap().unwrap_err()),
+                ErrorCode::InvalidArgument,
+                "{}",
+                annotation
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(position: usize, message: String) -> Self {
+        Self { position, message }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{} at position {}", self.message, self.position))
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Tokenizer<'a> {
+    chars: Peekable<Chars<'a>>,
+    position: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn new(input: &'a str, offset: usize) -> Self {
+        Self {
+            chars: input.chars().peekable(),
+            position: offset,
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c.is_some() {
+            self.position += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        let start = self.position;
+        let mut key = String::new();
+
+        while let Some(c) = self.peek() {
+            match c {
+                '=' => break,
+                '"' => {
+                    return Err(ParseError::new(
+                        self.position,
+                        "unexpected '\"' in key".to_string(),
+                    ))
+                }
+                c if c.is_whitespace() => break,
+                c => {
+                    key.push(c);
+                    self.next();
+                }
+            }
+        }
+
+        if key.is_empty() {
+            return Err(ParseError::new(start, "missing key before '='".to_string()));
+        }
+
+        Ok(key)
+    }
+
+    fn escape(&mut self) -> Result<char, ParseError> {
+        let position = self.position;
+        self.next();
+
+        match self.next() {
+            Some('"') => Ok('"'),
+            Some('\\') => Ok('\\'),
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some(c) => Err(ParseError::new(
+                position,
+                format!("invalid escape sequence '\\{}'", c),
+            )),
+            None => Err(ParseError::new(
+                position,
+                "unterminated escape sequence".to_string(),
+            )),
+        }
+    }
+
+    fn quoted(&mut self, key: &str) -> Result<String, ParseError> {
+        let start = self.position;
+        let mut value = String::new();
+        self.next();
+
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.next();
+                    break;
+                }
+                Some('\\') => value.push(self.escape()?),
+                Some(c) => {
+                    value.push(c);
+                    self.next();
+                }
+                None => {
+                    return Err(ParseError::new(
+                        start,
+                        format!("unterminated quoted value for key '{}'", key),
+                    ))
+                }
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn unquoted(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+
+        while let Some(c) = self.peek() {
+            match c {
+                '"' => {
+                    return Err(ParseError::new(
+                        self.position,
+                        "unexpected '\"' in unquoted value".to_string(),
+                    ))
+                }
+                '\\' => value.push(self.escape()?),
+                c if c.is_whitespace() => break,
+                c => {
+                    value.push(c);
+                    self.next();
+                }
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn pair(&mut self) -> Result<(String, String), ParseError> {
+        let key = self.key()?;
+
+        self.skip_whitespace();
+
+        let position = self.position;
+        if self.next() != Some('=') {
+            return Err(ParseError::new(
+                position,
+                format!("expected '=' after key '{}'", key),
+            ));
+        }
+
+        self.skip_whitespace();
+
+        let value = match self.peek() {
+            Some('"') => self.quoted(&key)?,
+            Some(_) => self.unquoted()?,
+            None => {
+                return Err(ParseError::new(
+                    self.position,
+                    format!("missing value for key '{}'", key),
+                ))
+            }
+        };
+
+        if self.peek().is_some_and(|c| !c.is_whitespace()) {
+            return Err(ParseError::new(
+                self.position,
+                format!("expected whitespace after value for key '{}'", key),
+            ));
+        }
+
+        Ok((key, value))
+    }
+}
+
+pub(crate) fn tokenize(input: &str, offset: usize) -> Result<Vec<(String, String)>, ParseError> {
+    let mut tokenizer = Tokenizer::new(input, offset);
+    let mut pairs = Vec::new();
+
+    loop {
+        tokenizer.skip_whitespace();
+
+        if tokenizer.peek().is_none() {
+            return Ok(pairs);
+        }
+
+        pairs.push(tokenizer.pair()?);
+    }
+}
+
+pub(crate) fn strip_command<'a>(line: &'a str, command: &str) -> Option<(&'a str, usize)> {
+    let trimmed = line.trim_start();
+    let rest = trimmed.strip_prefix(command)?;
+
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    Some((rest, line.chars().count() - rest.chars().count()))
+}
+
+pub(crate) fn quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str(r#"\""#),
+            '\\' => quoted.push_str(r"\\"),
+            '\n' => quoted.push_str(r"\n"),
+            '\r' => quoted.push_str(r"\r"),
+            '\t' => quoted.push_str(r"\t"),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() -> Result<(), ParseError> {
+        let table = vec![
+            ("", vec![], "empty input"),
+            (
+                r#"name="foo""#,
+                vec![("name", "foo")],
+                "single quoted value",
+            ),
+            ("name=foo", vec![("name", "foo")], "single unquoted value"),
+            (r#"name="""#, vec![("name", "")], "empty quoted value"),
+            (
+                r#"  name = "foo"   image="bar"  "#,
+                vec![("name", "foo"), ("image", "bar")],
+                "whitespace around pairs",
+            ),
+            (
+                r#"name="a\"b" image="c\\d""#,
+                vec![("name", "a\"b"), ("image", "c\\d")],
+                "escaped quote and backslash",
+            ),
+            (
+                r#"name="a\nb\tc\rd""#,
+                vec![("name", "a\nb\tc\rd")],
+                "escaped control characters",
+            ),
+            (
+                "name=\"a\nb\"",
+                vec![("name", "a\nb")],
+                "literal newline in quoted value",
+            ),
+            (
+                r#"tags=one=foo,two=bar kind="nspawn""#,
+                vec![("tags", "one=foo,two=bar"), ("kind", "nspawn")],
+                "unquoted value containing '='",
+            ),
+            (r"name=a\\b", vec![("name", "a\\b")], "unquoted escape"),
+        ];
+
+        for (input, result, annotation) in table {
+            assert_eq!(
+                tokenize(input, 0)?,
+                result
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<Vec<(String, String)>>(),
+                "{}",
+                annotation
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let table = vec![
+            (r#"name="foo"#, 5, "unterminated quoted value"),
+            ("garbage", 7, "key without '='"),
+            (r#"name="foo" garbage image="bar""#, 19, "stray token"),
+            (r#"="foo""#, 0, "missing key"),
+            ("name=", 5, "missing value"),
+            (r#"name="a\qb""#, 7, "invalid escape"),
+            (
+                r#"name="a"image="b""#,
+                8,
+                "missing whitespace between pairs",
+            ),
+            (r#"name=a"b""#, 6, "quote in unquoted value"),
+            (r#"na"me="b""#, 2, "quote in key"),
+        ];
+
+        for (input, position, annotation) in table {
+            let err = tokenize(input, 0).unwrap_err();
+            assert_eq!(err.position, position, "{}: {}", annotation, err);
+        }
+
+        assert_eq!(
+            tokenize(r#"name="foo"#, 10).unwrap_err().position,
+            15,
+            "offset is applied to positions"
+        );
+    }
+
+    #[test]
+    fn test_strip_command() {
+        assert_eq!(
+            strip_command(r#"  hello version="1""#, "hello"),
+            Some((r#" version="1""#, 7))
+        );
+        assert_eq!(strip_command("hello", "hello"), Some(("", 5)));
+        assert_eq!(strip_command("hellos", "hello"), None);
+        assert_eq!(strip_command("status", "hello"), None);
+    }
+
+    #[test]
+    fn test_quote() -> Result<(), ParseError> {
+        for value in ["", "foo", "a\"b", "a\\b", "a\nb\r\tc", "\\\"", "ünïcødé"] {
+            let pairs = tokenize(&format!("name={}", quote(value)), 0)?;
+            assert_eq!(pairs, vec![("name".to_string(), value.to_string())]);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,493 @@
+use crate::manifest::{Manifest, SchedulingCommand};
+use crate::protocol::{Health, Payload, PlanEntry, WorkloadStatus};
+use crate::protocol_error;
+use anyhow::{anyhow, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const PREFIX: &str = "secret://";
+pub const KEY_LEN: usize = 32;
+
+pub fn reference(value: &str) -> Option<&str> {
+    value.strip_prefix(PREFIX)
+}
+
+pub fn valid(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || matches!(x, '_' | '-' | '.'))
+}
+
+pub fn references(command: &SchedulingCommand) -> Vec<&str> {
+    let mut names = command
+        .args()
+        .values()
+        .filter_map(|x| reference(x))
+        .collect::<Vec<&str>>();
+
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; KEY_LEN]);
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    pub fn generate() -> Result<Self> {
+        let mut key = [0; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| anyhow!("could not generate a secret key"))?;
+        Ok(Self(key))
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let invalid = || {
+            protocol_error!(
+                InvalidArgument,
+                "secret key must be {} hex-encoded bytes",
+                KEY_LEN
+            )
+        };
+
+        if text.len() != KEY_LEN * 2 {
+            return Err(invalid());
+        }
+
+        let mut key = [0; KEY_LEN];
+
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = text
+                .get(i * 2..i * 2 + 2)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .ok_or_else(invalid)?;
+        }
+
+        Ok(Self(key))
+    }
+
+    pub fn from_file(filename: &Path) -> Result<Self> {
+        Self::from_hex(&std::fs::read_to_string(filename)?)
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    fn aead(&self) -> Result<LessSafeKey> {
+        Ok(LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, &self.0)
+                .map_err(|_| anyhow!("invalid secret key"))?,
+        ))
+    }
+
+    pub fn seal(&self, name: &str, value: &str) -> Result<Sealed> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("could not generate a nonce for secret '{}'", name))?;
+
+        let mut ciphertext = value.as_bytes().to_vec();
+        self.aead()?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut ciphertext,
+            )
+            .map_err(|_| anyhow!("could not encrypt secret '{}'", name))?;
+
+        Ok(Sealed {
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    pub fn open(&self, name: &str, sealed: &Sealed) -> Result<String> {
+        let invalid =
+            || protocol_error!(PermissionDenied, "secret '{}' could not be decrypted", name);
+
+        let nonce = Nonce::try_assume_unique_for_key(&sealed.nonce).map_err(|_| invalid())?;
+        let mut data = sealed.ciphertext.clone();
+        let plaintext = self
+            .aead()?
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut data)
+            .map_err(|_| invalid())?;
+
+        String::from_utf8(plaintext.to_vec()).map_err(|_| invalid())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sealed {
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+pub trait Store {
+    fn sealed(&self, name: &str) -> Option<Sealed>;
+}
+
+impl Store for BTreeMap<String, Sealed> {
+    fn sealed(&self, name: &str) -> Option<Sealed> {
+        self.get(name).cloned()
+    }
+}
+
+fn lookup(store: &dyn Store, key: &Key, command: &SchedulingCommand, name: &str) -> Result<String> {
+    let sealed = store.sealed(name).ok_or_else(|| {
+        protocol_error!(
+            NotFound,
+            "secret '{}' referenced by command '{}' does not exist",
+            name,
+            command.name()
+        )
+    })?;
+
+    key.open(name, &sealed)
+}
+
+#[derive(Clone, Default)]
+pub struct Redactor {
+    values: Vec<(String, String)>,
+}
+
+impl std::fmt::Debug for Redactor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.values.iter().map(|(_, name)| name))
+            .finish()
+    }
+}
+
+impl Redactor {
+    fn new(mut values: Vec<(String, String)>) -> Self {
+        values.retain(|(value, _)| !value.is_empty());
+        values.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.cmp(b)));
+        values.dedup_by(|a, b| a.0 == b.0);
+        Self { values }
+    }
+
+    pub fn for_manifest(manifest: &Manifest, store: &dyn Store, key: &Key) -> Result<Self> {
+        let mut values = Vec::new();
+
+        for command in manifest.commands() {
+            for name in references(command) {
+                values.push((lookup(store, key, command, name)?, name.to_string()));
+            }
+        }
+
+        Ok(Self::new(values))
+    }
+
+    // covers every secret given, whether or not a command refers to it.
+    pub fn for_secrets<'a>(
+        secrets: impl IntoIterator<Item = (&'a str, Sealed)>,
+        key: &Key,
+    ) -> Result<Self> {
+        let mut values = Vec::new();
+
+        for (name, sealed) in secrets {
+            values.push((key.open(name, &sealed)?, name.to_string()));
+        }
+
+        Ok(Self::new(values))
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        self.values
+            .iter()
+            .fold(text.to_string(), |text, (value, name)| {
+                text.replace(value, &format!("{}{}", PREFIX, name))
+            })
+    }
+
+    pub fn workload(&self, mut workload: WorkloadStatus) -> WorkloadStatus {
+        workload.image = self.redact(&workload.image);
+
+        for value in workload.tags.values_mut() {
+            *value = self.redact(value);
+        }
+
+        if let Some(Health::Unhealthy(message)) = &mut workload.health {
+            *message = self.redact(message);
+        }
+
+        workload
+    }
+
+    pub fn plan(&self, mut entry: PlanEntry) -> PlanEntry {
+        entry.error = entry.error.map(|x| self.redact(&x));
+        entry
+    }
+
+    pub fn payload(&self, payload: Payload) -> Payload {
+        match payload {
+            Payload::Workloads(x) => {
+                Payload::Workloads(x.into_iter().map(|x| self.workload(x)).collect())
+            }
+            Payload::Plan(x) => Payload::Plan(x.into_iter().map(|x| self.plan(x)).collect()),
+            x => x,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    pub command: SchedulingCommand,
+    pub redactor: Redactor,
+}
+
+pub fn resolve(command: &SchedulingCommand, store: &dyn Store, key: &Key) -> Result<Resolved> {
+    let mut args = BTreeMap::new();
+    let mut values = Vec::new();
+
+    for (arg, value) in command.args() {
+        let value = match reference(value) {
+            Some(name) => {
+                let value = lookup(store, key, command, name)?;
+                values.push((value.clone(), name.to_string()));
+                value
+            }
+            None => value.clone(),
+        };
+
+        args.insert(arg.clone(), value);
+    }
+
+    Ok(Resolved {
+        command: command.with_args(args),
+        redactor: Redactor::new(values),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Kind, SystemdKind};
+    use crate::protocol::{ErrorCode, PlanAction, WorkloadState};
+
+    fn store(key: &Key, secrets: &[(&str, &str)]) -> Result<BTreeMap<String, Sealed>> {
+        secrets
+            .iter()
+            .map(|(name, value)| Ok((name.to_string(), key.seal(name, value)?)))
+            .collect()
+    }
+
+    #[test]
+    fn test_key() -> Result<()> {
+        let key = Key::generate()?;
+        assert_eq!(Key::from_hex(&key.to_hex())?, key);
+        assert_ne!(Key::generate()?, key);
+        assert_eq!(format!("{:?}", key), "Key(..)");
+
+        for text in ["", "abc", &"zz".repeat(KEY_LEN), &"00".repeat(KEY_LEN + 1)] {
+            assert_eq!(
+                ErrorCode::of(&Key::from_hex(text).unwrap_err()),
+                ErrorCode::InvalidArgument,
+                "{}",
+                text
+            );
+        }
+
+        let sealed = key.seal("db-password", "hunter2")?;
+        assert!(!sealed
+            .ciphertext
+            .windows(7)
+            .any(|x| x == "hunter2".as_bytes()));
+        assert_eq!(key.open("db-password", &sealed)?, "hunter2");
+        assert_ne!(key.seal("db-password", "hunter2")?, sealed);
+
+        let table = vec![
+            (Key::generate()?, "db-password", sealed.clone(), "wrong key"),
+            (key.clone(), "other", sealed.clone(), "wrong name"),
+            (
+                key.clone(),
+                "db-password",
+                Sealed {
+                    ciphertext: vec![0; sealed.ciphertext.len()],
+                    ..sealed.clone()
+                },
+                "tampered ciphertext",
+            ),
+            (
+                key.clone(),
+                "db-password",
+                Sealed {
+                    nonce: vec![0; 3],
+                    ..sealed
+                },
+                "short nonce",
+            ),
+        ];
+
+        for (key, name, sealed, annotation) in table {
+            let err = key.open(name, &sealed).unwrap_err();
+            assert_eq!(
+                ErrorCode::of(&err),
+                ErrorCode::PermissionDenied,
+                "{}",
+                annotation
+            );
+            assert!(!err.to_string().contains("hunter2"), "{}", annotation);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let key = Key::generate()?;
+        let manifest = Manifest::from_file(Path::new("testdata/secrets-one.yaml"))?;
+        let command = &manifest.commands()[0];
+        assert_eq!(
+            references(command),
+            vec!["db-password", "health-url", "private-image"]
+        );
+
+        let store = store(
+            &key,
+            &[
+                ("private-image", "registry.example.com/app:1.0"),
+                ("health-url", "http://127.0.0.1:8080/healthz?token=s3cr3t"),
+                ("db-password", "hunter2"),
+            ],
+        )?;
+
+        let resolved = resolve(command, &store, &key)?;
+        assert_eq!(
+            resolved.command.args().get("image"),
+            Some(&"registry.example.com/app:1.0".to_string())
+        );
+        assert_eq!(
+            resolved.command.args().get("kind"),
+            command.args().get("kind")
+        );
+        assert_eq!(
+            command.args().get("image"),
+            Some(&"secret://private-image".to_string())
+        );
+        assert_eq!(
+            resolved.command.health_check()?,
+            Some(crate::manifest::HealthCheck::Http(
+                "http://127.0.0.1:8080/healthz?token=s3cr3t".to_string()
+            ))
+        );
+        assert_eq!(
+            resolved.command.environment()?,
+            [
+                ("DB_PASSWORD".to_string(), "hunter2".to_string()),
+                ("MODE".to_string(), "production".to_string()),
+            ]
+            .into()
+        );
+        assert!(!format!("{:?}", resolved.redactor).contains("s3cr3t"));
+
+        let err = resolve(command, &BTreeMap::new(), &key).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "secret 'db-password' referenced by command 'app' does not exist"
+        );
+
+        let err = resolve(command, &store, &Key::generate()?).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::PermissionDenied);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact() -> Result<()> {
+        let key = Key::generate()?;
+        let manifest = Manifest::from_file(Path::new("testdata/secrets-one.yaml"))?;
+        let store = store(
+            &key,
+            &[
+                ("private-image", "registry.example.com/app:1.0"),
+                ("health-url", "http://127.0.0.1:8080/healthz?token=s3cr3t"),
+                ("db-health", "10.0.0.5:5432"),
+                ("db-password", "hunter2"),
+            ],
+        )?;
+        let redactor = Redactor::for_manifest(&manifest, &store, &key)?;
+
+        let workload = WorkloadStatus {
+            name: "app".to_string(),
+            kind: Kind::Systemd(SystemdKind::NSpawn),
+            image: "registry.example.com/app:1.0".to_string(),
+            state: WorkloadState::Running,
+            node: Some("node1".to_string()),
+            tags: [(
+                "image".to_string(),
+                "registry.example.com/app:1.0".to_string(),
+            )]
+            .into(),
+            health: Some(Health::Unhealthy(
+                "'http://127.0.0.1:8080/healthz?token=s3cr3t' returned status 503".to_string(),
+            )),
+        };
+
+        let plan = PlanEntry {
+            name: "app".to_string(),
+            command: "schedule".to_string(),
+            action: PlanAction::Create,
+            node: None,
+            previous: None,
+            error: Some("could not pull registry.example.com/app:1.0".to_string()),
+        };
+
+        let table = vec![
+            (
+                Payload::Workloads(vec![workload.clone()]),
+                Payload::Workloads(vec![WorkloadStatus {
+                    image: "secret://private-image".to_string(),
+                    tags: [("image".to_string(), "secret://private-image".to_string())].into(),
+                    health: Some(Health::Unhealthy(
+                        "'secret://health-url' returned status 503".to_string(),
+                    )),
+                    ..workload
+                }]),
+                "status",
+            ),
+            (
+                Payload::Plan(vec![plan.clone()]),
+                Payload::Plan(vec![PlanEntry {
+                    error: Some("could not pull secret://private-image".to_string()),
+                    ..plan
+                }]),
+                "plan",
+            ),
+            (
+                Payload::Logs(vec![]),
+                Payload::Logs(vec![]),
+                "other payloads",
+            ),
+        ];
+
+        for (payload, redacted, annotation) in table {
+            assert_eq!(redactor.payload(payload), redacted, "{}", annotation);
+        }
+
+        let redactor = Redactor::new(vec![
+            ("pass".to_string(), "short".to_string()),
+            ("password".to_string(), "long".to_string()),
+            (String::new(), "empty".to_string()),
+        ]);
+        assert_eq!(
+            redactor.redact("password pass"),
+            "secret://long secret://short"
+        );
+
+        Ok(())
+    }
+}
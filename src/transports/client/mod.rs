@@ -4,10 +4,26 @@ use crate::protocol::{Instruction, Response};
 use anyhow::Result;
 
 pub trait Client {
-    fn exchange(&mut self, instruction: Instruction) -> Result<Response>;
+    fn exchange(&mut self, instruction: Instruction) -> Result<Response> {
+        self.exchange_stream(instruction, &mut |_| true)
+    }
+
+    fn exchange_stream(
+        &mut self,
+        instruction: Instruction,
+        f: &mut dyn FnMut(&Response) -> bool,
+    ) -> Result<Response>;
 }
 
 #[async_trait::async_trait]
 pub trait AsyncClient {
-    async fn exchange(&mut self, instruction: Instruction) -> Result<Response>;
+    async fn exchange(&mut self, instruction: Instruction) -> Result<Response> {
+        self.exchange_stream(instruction, &mut |_| true).await
+    }
+
+    async fn exchange_stream(
+        &mut self,
+        instruction: Instruction,
+        f: &mut (dyn for<'r> FnMut(&'r Response) -> bool + Send),
+    ) -> Result<Response>;
 }
@@ -1,49 +1,144 @@
 use super::*;
-use anyhow::anyhow;
+use crate::protocol::{Encoding, Hello};
+use crate::protocol_error;
+use anyhow::{anyhow, Context};
 use std::io::prelude::*;
 use std::net::TcpStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream as AsyncTcpStream;
 
+fn server_hello(response: Response) -> Result<Hello> {
+    let response = response.into_result().context("server rejected hello")?;
+
+    let server = Hello::from_payload(&response.payload)?;
+    server.check_version()?;
+    Ok(server)
+}
+
+fn check_capability(server: &Option<Hello>, capability: &str) -> Result<()> {
+    match server {
+        Some(server) if capability != "text" && !server.supports(capability) => {
+            Err(protocol_error!(
+                Unsupported,
+                "server does not support '{}' (protocol version {})",
+                capability,
+                server.version
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn take_line(buf: &mut String) -> Option<String> {
+    loop {
+        let pos = buf.find('\n')?;
+        let line = buf[..pos].trim().to_string();
+        buf.drain(..=pos);
+
+        if !line.is_empty() {
+            return Some(line);
+        }
+    }
+}
+
 pub struct TcpClient {
     io: TcpStream,
+    buf: String,
+    encoding: Encoding,
+    server: Option<Hello>,
+    timeout: std::time::Duration,
 }
 
 impl TcpClient {
+    pub const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
     pub fn new(io: TcpStream) -> Self {
-        Self { io }
+        Self {
+            io,
+            buf: Default::default(),
+            encoding: Default::default(),
+            server: None,
+            timeout: Self::TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
 
     pub fn close(&mut self) -> Result<()> {
         Ok(self.io.shutdown(std::net::Shutdown::Both)?)
     }
-}
 
-impl Client for TcpClient {
-    fn exchange(&mut self, instruction: Instruction) -> Result<Response> {
-        let mut buf = instruction.to_string().as_bytes().to_vec();
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn server(&self) -> Option<&Hello> {
+        self.server.as_ref()
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.server.as_ref().is_some_and(|x| x.supports(capability))
+    }
+
+    pub fn hello(&mut self) -> Result<Hello> {
+        self.write_line(&Hello::default().to_string())?;
+
+        let response = self.read_response()?;
+        let server = server_hello(response)?;
+        self.server = Some(server.clone());
+        Ok(server)
+    }
+
+    pub fn negotiate(&mut self, encoding: Encoding) -> Result<()> {
+        check_capability(&self.server, &encoding.to_string())?;
+        self.write_line(&encoding.negotiation())?;
+
+        self.read_response()?
+            .into_result()
+            .with_context(|| format!("could not negotiate {} encoding", encoding))?;
+
+        self.encoding = encoding;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let mut buf = line.as_bytes().to_vec();
         buf.push(b'\n');
-        self.io.write_all(&buf)?;
+        Ok(self.io.write_all(&buf)?)
+    }
 
+    fn read_response(&mut self) -> Result<Response> {
+        self.read_response_within(Some(self.timeout))
+    }
+
+    // streams may stay quiet for as long as nothing happens, so reads between
+    // partial responses go without a timeout.
+    fn read_response_within(&mut self, timeout: Option<std::time::Duration>) -> Result<Response> {
         let mut buf = [0_u8; 4096];
-        let mut res = String::new();
+        let deadline = timeout.map(|x| std::time::Instant::now() + x);
 
         'retry: loop {
-            self.io
-                .set_read_timeout(Some(std::time::Duration::new(0, 100)))?;
-            match self.io.read(&mut buf) {
-                Ok(sz) => {
-                    if sz > 0 {
-                        res += &String::from_utf8(buf[..sz].to_vec())?;
-                    }
+            if let Some(line) = take_line(&mut self.buf) {
+                return Ok(serde_json::from_str(&line)?);
+            }
 
-                    match serde_json::from_str(&res) {
-                        Ok(res) => return Ok(res),
-                        Err(_) => continue 'retry,
-                    }
-                }
+            let remaining =
+                deadline.map(|x| x.saturating_duration_since(std::time::Instant::now()));
+            if remaining.is_some_and(|x| x.is_zero()) {
+                return Err(anyhow!("timed out waiting for a response"));
+            }
+
+            self.io.set_read_timeout(remaining)?;
+            match self.io.read(&mut buf) {
+                Ok(0) => return Err(anyhow!("connection closed")),
+                Ok(sz) => self.buf += &String::from_utf8(buf[..sz].to_vec())?,
                 Err(e) => match e.kind() {
-                    std::io::ErrorKind::WouldBlock => continue 'retry,
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                        continue 'retry
+                    }
                     _ => return Err(e.into()),
                 },
             }
@@ -51,55 +146,134 @@ impl Client for TcpClient {
     }
 }
 
+impl Client for TcpClient {
+    fn exchange_stream(
+        &mut self,
+        instruction: Instruction,
+        f: &mut dyn FnMut(&Response) -> bool,
+    ) -> Result<Response> {
+        check_capability(&self.server, instruction.command.name())?;
+        let line = self.encoding.encode(&instruction)?;
+        self.write_line(&line)?;
+
+        let mut cancelled = false;
+        let mut timeout = Some(self.timeout);
+
+        loop {
+            let response = self.read_response_within(timeout)?;
+            if !response.more {
+                return Ok(response);
+            }
+
+            if !cancelled && !f(&response) {
+                let line = self.encoding.encode(&Instruction::cancel())?;
+                self.write_line(&line)?;
+                cancelled = true;
+            }
+
+            // a cancelled stream should end promptly.
+            timeout = cancelled.then_some(self.timeout);
+        }
+    }
+}
+
 pub struct AsyncTcpClient {
     io: AsyncTcpStream,
+    buf: String,
+    encoding: Encoding,
+    server: Option<Hello>,
+    timeout: std::time::Duration,
 }
 
 impl AsyncTcpClient {
+    pub const TIMEOUT: std::time::Duration = TcpClient::TIMEOUT;
+
     pub fn new(io: AsyncTcpStream) -> Self {
-        Self { io }
+        Self {
+            io,
+            buf: Default::default(),
+            encoding: Default::default(),
+            server: None,
+            timeout: Self::TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
 
     pub async fn close(&mut self) -> Result<()> {
         Ok(self.io.shutdown().await?)
     }
-}
 
-#[async_trait::async_trait]
-impl AsyncClient for AsyncTcpClient {
-    async fn exchange(&mut self, instruction: Instruction) -> Result<Response> {
-        let mut buf = instruction.to_string().as_bytes().to_vec();
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn server(&self) -> Option<&Hello> {
+        self.server.as_ref()
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.server.as_ref().is_some_and(|x| x.supports(capability))
+    }
+
+    pub async fn hello(&mut self) -> Result<Hello> {
+        self.write_line(&Hello::default().to_string()).await?;
+
+        let response = self.read_response().await?;
+        let server = server_hello(response)?;
+        self.server = Some(server.clone());
+        Ok(server)
+    }
+
+    pub async fn negotiate(&mut self, encoding: Encoding) -> Result<()> {
+        check_capability(&self.server, &encoding.to_string())?;
+        self.write_line(&encoding.negotiation()).await?;
+
+        self.read_response()
+            .await?
+            .into_result()
+            .with_context(|| format!("could not negotiate {} encoding", encoding))?;
+
+        self.encoding = encoding;
+        Ok(())
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<()> {
+        let mut buf = line.as_bytes().to_vec();
         buf.push(b'\n');
-        self.io.write_all(&buf).await?;
+        Ok(self.io.write_all(&buf).await?)
+    }
 
-        let mut buf = [0_u8; 4096];
-        let mut res = String::new();
+    async fn read_response(&mut self) -> Result<Response> {
+        self.read_response_within(Some(self.timeout)).await
+    }
 
-        let start = std::time::Instant::now();
+    async fn read_response_within(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Response> {
+        let mut buf = [0_u8; 4096];
 
         'retry: loop {
-            match self.io.read(&mut buf).await {
-                Ok(sz) => {
-                    if sz > 0 {
-                        res += &String::from_utf8(buf[..sz].to_vec())?;
-                    } else {
-                        if std::time::Instant::now() - start > std::time::Duration::new(0, 100) {
-                            return Err(anyhow!("timed out"));
-                        }
-                    }
+            if let Some(line) = take_line(&mut self.buf) {
+                return Ok(serde_json::from_str(&line)?);
+            }
 
-                    match serde_json::from_str(&res) {
-                        Ok(res) => return Ok(res),
-                        Err(_) => continue 'retry,
-                    }
-                }
+            let read = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.io.read(&mut buf))
+                    .await
+                    .map_err(|_| anyhow!("timed out waiting for a response"))?,
+                None => self.io.read(&mut buf).await,
+            };
+
+            match read {
+                Ok(0) => return Err(anyhow!("connection closed")),
+                Ok(sz) => self.buf += &String::from_utf8(buf[..sz].to_vec())?,
                 Err(e) => match e.kind() {
-                    std::io::ErrorKind::WouldBlock => {
-                        if std::time::Instant::now() - start > std::time::Duration::new(0, 100) {
-                            return Err(anyhow!("timed out"));
-                        }
-                        continue 'retry;
-                    }
+                    std::io::ErrorKind::WouldBlock => continue 'retry,
                     _ => return Err(e.into()),
                 },
             }
@@ -107,13 +281,81 @@ impl AsyncClient for AsyncTcpClient {
     }
 }
 
+#[async_trait::async_trait]
+impl AsyncClient for AsyncTcpClient {
+    async fn exchange_stream(
+        &mut self,
+        instruction: Instruction,
+        f: &mut (dyn for<'r> FnMut(&'r Response) -> bool + Send),
+    ) -> Result<Response> {
+        check_capability(&self.server, instruction.command.name())?;
+        let line = self.encoding.encode(&instruction)?;
+        self.write_line(&line).await?;
+
+        let mut cancelled = false;
+        let mut timeout = Some(self.timeout);
+
+        loop {
+            let response = self.read_response_within(timeout).await?;
+            if !response.more {
+                return Ok(response);
+            }
+
+            if !cancelled && !f(&response) {
+                let line = self.encoding.encode(&Instruction::cancel())?;
+                self.write_line(&line).await?;
+                cancelled = true;
+            }
+
+            // a cancelled stream should end promptly.
+            timeout = cancelled.then_some(self.timeout);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::{Command, ErrorCode, Payload};
     use crate::testdata::*;
     use crate::transports::server::tcp::*;
     use std::sync::Arc;
 
+    #[test]
+    fn test_check_capability() -> Result<()> {
+        let mut server = Hello::default();
+        server.capabilities.remove("quota");
+        server.capabilities.remove("json");
+        let server = Some(server);
+
+        assert!(check_capability(&None, "quota").is_ok());
+        assert!(check_capability(&server, "schedule").is_ok());
+        assert!(check_capability(&server, "text").is_ok());
+        assert_eq!(
+            ErrorCode::of(&check_capability(&server, "quota").unwrap_err()),
+            ErrorCode::Unsupported
+        );
+        assert!(check_capability(&server, "json").is_err());
+
+        let hello = Hello {
+            version: 99,
+            ..Default::default()
+        };
+        assert!(server_hello(Response::ok(hello.to_payload())).is_err());
+        assert_eq!(
+            ErrorCode::of(
+                &server_hello(Response::error(
+                    ErrorCode::IncompatibleVersion,
+                    "incompatible"
+                ))
+                .unwrap_err()
+            ),
+            ErrorCode::IncompatibleVersion
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_client() -> Result<()> {
         use std::net::TcpListener;
@@ -135,30 +377,44 @@ mod tests {
         });
 
         let mut client = TcpClient::new(TcpStream::connect(addr)?);
-
-        for (
-            _input,
-            input_result,
-            input_annotation,
-            response,
-            _response_result,
-            _response_annotation,
-        ) in &*GREEN_TABLE
-        {
-            let s = resp_s.clone();
-            let r = ins_r.clone();
-            std::thread::spawn(move || -> Result<()> {
-                r.lock().unwrap().recv()?;
-                s.send(response.clone())?;
-                Ok(())
-            });
-
-            assert_eq!(
-                client.exchange(input_result.clone())?,
-                response.clone(),
-                "{}",
-                input_annotation
-            );
+        assert!(!client.supports("schedule"));
+        assert_eq!(client.hello()?, Hello::default());
+        assert_eq!(client.server(), Some(&Hello::default()));
+        assert!(client.supports("schedule"));
+        assert!(!client.supports("reboot"));
+
+        for encoding in [Encoding::Text, Encoding::Json] {
+            client.negotiate(encoding)?;
+            assert_eq!(client.encoding(), encoding);
+
+            for (
+                _input,
+                input_result,
+                input_annotation,
+                response,
+                _response_result,
+                _response_annotation,
+            ) in &*GREEN_TABLE
+            {
+                let s = resp_s.clone();
+                let r = ins_r.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    if r.lock().unwrap().recv()? == *input_result {
+                        s.send(response.clone())?;
+                    } else {
+                        s.send(Response::error(ErrorCode::Internal, "instruction mismatch"))?;
+                    }
+                    Ok(())
+                });
+
+                assert_eq!(
+                    client.exchange(input_result.clone())?,
+                    response.clone(),
+                    "{}: {}",
+                    encoding,
+                    input_annotation
+                );
+            }
         }
 
         client.close()?;
@@ -190,33 +446,283 @@ mod tests {
         });
 
         let mut client = AsyncTcpClient::new(AsyncTcpStream::connect(addr).await?);
-
-        for (
-            _input,
-            input_result,
-            input_annotation,
-            response,
-            _response_result,
-            _response_annotation,
-        ) in &*GREEN_TABLE
-        {
-            let s = resp_s.clone();
-            let r = ins_r.clone();
-            tokio::spawn(async move {
-                r.lock().await.recv().await;
-                s.send(response.clone()).await.unwrap();
-            });
-
-            assert_eq!(
-                client.exchange(input_result.clone()).await?,
-                response.clone(),
-                "{}",
-                input_annotation
-            );
+        assert!(!client.supports("schedule"));
+        assert_eq!(client.hello().await?, Hello::default());
+        assert_eq!(client.server(), Some(&Hello::default()));
+        assert!(client.supports("schedule"));
+        assert!(!client.supports("reboot"));
+
+        for encoding in [Encoding::Text, Encoding::Json] {
+            client.negotiate(encoding).await?;
+            assert_eq!(client.encoding(), encoding);
+
+            for (
+                _input,
+                input_result,
+                input_annotation,
+                response,
+                _response_result,
+                _response_annotation,
+            ) in &*GREEN_TABLE
+            {
+                let s = resp_s.clone();
+                let r = ins_r.clone();
+                tokio::spawn(async move {
+                    if r.lock().await.recv().await.as_ref() == Some(input_result) {
+                        s.send(response.clone()).await.unwrap();
+                    } else {
+                        s.send(Response::error(ErrorCode::Internal, "instruction mismatch"))
+                            .await
+                            .unwrap();
+                    }
+                });
+
+                assert_eq!(
+                    client.exchange(input_result.clone()).await?,
+                    response.clone(),
+                    "{}: {}",
+                    encoding,
+                    input_annotation
+                );
+            }
         }
 
         client.close().await?;
         close_s.send(()).await?;
         Ok(())
     }
+
+    fn sequence(x: usize) -> Response {
+        let mut payload = std::collections::HashMap::default();
+        payload.insert("sequence".to_string(), x.to_string());
+        Response::partial(payload)
+    }
+
+    #[test]
+    fn test_client_stream() -> Result<()> {
+        use std::net::TcpListener;
+        use std::sync::Mutex;
+
+        let server = TcpListener::bind("localhost:0")?;
+        let addr = server.local_addr()?;
+        let (ins_s, ins_r) = std::sync::mpsc::sync_channel(1000);
+        let (resp_s, resp_r) = std::sync::mpsc::sync_channel(1000);
+        let (close_s, close_r) = std::sync::mpsc::sync_channel(1);
+
+        let resp_r = Arc::new(Mutex::new(resp_r));
+
+        std::thread::spawn(move || -> Result<()> {
+            let (sock, _) = server.accept()?;
+            let mut server = TcpServer::new(sock);
+            server.run(ins_s.clone(), resp_r.clone(), close_r)
+        });
+
+        let handle = std::thread::spawn(move || -> Result<usize> {
+            assert_eq!(ins_r.recv()?.command, Command::Status(None));
+            for x in 0..3 {
+                resp_s.send(sequence(x))?;
+            }
+            resp_s.send(Response::ok(Payload::default()))?;
+
+            assert_eq!(ins_r.recv()?.command, Command::Status(None));
+            let mut sent = 0;
+            loop {
+                resp_s.send(sequence(sent))?;
+                sent += 1;
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                if let Ok(i) = ins_r.try_recv() {
+                    assert!(i.is_cancel());
+                    break;
+                }
+            }
+            resp_s.send(Response::ok(Payload::default()))?;
+            Ok(sent)
+        });
+
+        let mut client = TcpClient::new(TcpStream::connect(addr)?);
+        let status: Instruction = "status".parse()?;
+
+        let mut seen = Vec::new();
+        let response = client.exchange_stream(status.clone(), &mut |r| {
+            seen.push(r.payload.get("sequence").cloned().unwrap_or_default());
+            true
+        })?;
+        assert!(response.status && !response.more);
+        assert_eq!(seen, vec!["0", "1", "2"]);
+
+        let mut count = 0;
+        let response = client.exchange_stream(status, &mut |_| {
+            count += 1;
+            false
+        })?;
+        assert!(response.status && !response.more);
+        assert_eq!(count, 1);
+        assert!(handle.join().unwrap()? >= 1);
+
+        client.close()?;
+        close_s.send(())?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_stream_async() -> Result<()> {
+        use tokio::net::TcpListener;
+        use tokio::sync::Mutex;
+
+        let server = TcpListener::bind("localhost:0").await?;
+        let addr = server.local_addr()?;
+        let (ins_s, mut ins_r) = tokio::sync::mpsc::channel(1000);
+        let (resp_s, resp_r) = tokio::sync::mpsc::channel(1000);
+        let (close_s, close_r) = tokio::sync::mpsc::channel(1);
+
+        let resp_r = Arc::new(Mutex::new(resp_r));
+
+        tokio::spawn(async move {
+            let (sock, _) = server.accept().await.unwrap();
+            let mut server = AsyncTcpServer::new(sock);
+            server
+                .run(ins_s.clone(), resp_r.clone(), close_r)
+                .await
+                .unwrap();
+        });
+
+        let handle = tokio::spawn(async move {
+            assert_eq!(ins_r.recv().await.unwrap().command, Command::Status(None));
+            for x in 0..3 {
+                resp_s.send(sequence(x)).await.unwrap();
+            }
+            resp_s.send(Response::ok(Payload::default())).await.unwrap();
+
+            assert_eq!(ins_r.recv().await.unwrap().command, Command::Status(None));
+            let mut sent = 0;
+            loop {
+                resp_s.send(sequence(sent)).await.unwrap();
+                sent += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                if let Ok(i) = ins_r.try_recv() {
+                    assert!(i.is_cancel());
+                    break;
+                }
+            }
+            resp_s.send(Response::ok(Payload::default())).await.unwrap();
+            sent
+        });
+
+        let mut client = AsyncTcpClient::new(AsyncTcpStream::connect(addr).await?);
+        let status: Instruction = "status".parse()?;
+
+        let mut seen = Vec::new();
+        let response = client
+            .exchange_stream(status.clone(), &mut |r| {
+                seen.push(r.payload.get("sequence").cloned().unwrap_or_default());
+                true
+            })
+            .await?;
+        assert!(response.status && !response.more);
+        assert_eq!(seen, vec!["0", "1", "2"]);
+
+        let mut count = 0;
+        let response = client
+            .exchange_stream(status, &mut |_| {
+                count += 1;
+                false
+            })
+            .await?;
+        assert!(response.status && !response.more);
+        assert_eq!(count, 1);
+        assert!(handle.await? >= 1);
+
+        client.close().await?;
+        close_s.send(()).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_timeout() -> Result<()> {
+        use std::net::TcpListener;
+
+        let server = TcpListener::bind("localhost:0")?;
+        let addr = server.local_addr()?;
+
+        let handle = std::thread::spawn(move || {
+            let (sock, _) = server.accept().unwrap();
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            drop(sock);
+        });
+
+        let mut client = TcpClient::new(TcpStream::connect(addr)?)
+            .with_timeout(std::time::Duration::from_millis(50));
+        let err = client.hello().unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+
+        handle.join().unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_async_timeout() -> Result<()> {
+        use tokio::net::TcpListener;
+
+        let server = TcpListener::bind("localhost:0").await?;
+        let addr = server.local_addr()?;
+
+        let handle = tokio::spawn(async move {
+            let (sock, _) = server.accept().await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            drop(sock);
+        });
+
+        let mut client = AsyncTcpClient::new(AsyncTcpStream::connect(addr).await?)
+            .with_timeout(std::time::Duration::from_millis(50));
+        let err = client.hello().await.unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+
+        handle.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_stream_idle() -> Result<()> {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let server = TcpListener::bind("localhost:0")?;
+        let addr = server.local_addr()?;
+
+        let handle = std::thread::spawn(move || -> Result<()> {
+            let (mut sock, _) = server.accept()?;
+            let mut line = String::new();
+            BufReader::new(sock.try_clone()?).read_line(&mut line)?;
+
+            for response in [
+                Response::partial(Payload::default()),
+                Response::partial(Payload::default()),
+                Response::ok(Payload::default()),
+            ] {
+                sock.write_all(format!("{}\n", response).as_bytes())?;
+                std::thread::sleep(std::time::Duration::from_millis(200));
+            }
+
+            Ok(())
+        });
+
+        let mut client = TcpClient::new(TcpStream::connect(addr)?)
+            .with_timeout(std::time::Duration::from_millis(100));
+        let mut count = 0;
+        let response = client.exchange_stream(
+            Instruction {
+                command: Command::Watch(None, None),
+                tags: Default::default(),
+            },
+            &mut |_| {
+                count += 1;
+                true
+            },
+        )?;
+        assert!(response.status && !response.more);
+        assert_eq!(count, 2, "idle streams outlast the response timeout");
+
+        handle.join().unwrap()?;
+        Ok(())
+    }
 }
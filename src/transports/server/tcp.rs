@@ -1,8 +1,10 @@
-use crate::protocol::{Instruction, Response};
+use crate::protocol::{Encoding, Hello, Instruction, Payload, Response};
+use crate::protocol_error;
 use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::sync::mpsc::Receiver as SyncReceiver;
+use std::sync::mpsc::{Receiver as SyncReceiver, RecvTimeoutError};
 use std::sync::{mpsc::SyncSender, Arc, Mutex};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -10,13 +12,25 @@ use tokio::{
     sync::mpsc::{Receiver, Sender},
 };
 
-fn read_command(mut input: String) -> Result<(Option<Instruction>, String)> {
+enum Message {
+    Instruction(Instruction),
+    Encoding(Encoding),
+    Hello(Hello),
+}
+
+fn read_command(mut input: String, encoding: Encoding) -> (Option<Result<Message>>, String) {
     let mut res = None;
     if !input.is_empty() {
         let lines = input.trim().split('\n').collect::<Vec<&str>>();
         if !lines.is_empty() {
             let this_line = lines[0];
-            res = Some(this_line.parse()?);
+            res = Some(if let Some(hello) = Hello::parse_hello(this_line) {
+                hello.map(Message::Hello)
+            } else if let Some(encoding) = Encoding::parse_negotiation(this_line) {
+                encoding.map(Message::Encoding)
+            } else {
+                encoding.decode(this_line).map(Message::Instruction)
+            });
             if lines.len() > 1 {
                 input = lines[1..].join("\n").to_string()
             } else {
@@ -25,12 +39,47 @@ fn read_command(mut input: String) -> Result<(Option<Instruction>, String)> {
         }
     }
 
-    return Ok((res, input));
+    (res, input)
+}
+
+fn negotiated(encoding: Encoding) -> Response {
+    let mut payload = HashMap::default();
+    payload.insert("encoding".to_string(), encoding.to_string());
+    Response::ok(payload)
+}
+
+fn take_cancel(input: &str, encoding: Encoding) -> Option<String> {
+    let (complete, partial) = input.split_at(input.rfind('\n')? + 1);
+    let mut cancelled = false;
+    let mut rest = String::new();
+
+    for line in complete.split_inclusive('\n') {
+        match encoding.decode(line.trim()) {
+            Ok(i) if i.is_cancel() => cancelled = true,
+            _ => rest.push_str(line),
+        }
+    }
+
+    cancelled.then(|| rest + partial)
+}
+
+fn greet(hello: &Hello, started: bool) -> Result<Response> {
+    if started {
+        return Err(protocol_error!(
+            InvalidCommand,
+            "hello must be sent at connection start"
+        ));
+    }
+
+    hello.check_version()?;
+    Ok(Response::ok(Hello::default().to_payload()))
 }
 
 pub struct AsyncTcpServer {
     io: AsyncTcpStream,
     buf: String,
+    encoding: Encoding,
+    started: bool,
 }
 
 impl AsyncTcpServer {
@@ -38,6 +87,65 @@ impl AsyncTcpServer {
         Self {
             io,
             buf: Default::default(),
+            encoding: Default::default(),
+            started: false,
+        }
+    }
+
+    async fn write_response(&mut self, response: Response) -> Result<()> {
+        let mut buf = response.to_string().as_bytes().to_vec();
+        buf.push(b'\n');
+
+        Ok(self.io.write_all(&buf).await?)
+    }
+
+    async fn stream(
+        &mut self,
+        s: &Sender<Instruction>,
+        r: &Arc<tokio::sync::Mutex<Receiver<Response>>>,
+    ) -> Result<()> {
+        let mut r = r.lock().await;
+        let mut out = [0_u8; 4096];
+        let mut eof = false;
+
+        loop {
+            tokio::select! {
+                response = r.recv() => {
+                    let response = response.ok_or_else(|| anyhow!("response channel closed"))?;
+                    let more = response.more;
+                    self.write_response(response).await?;
+                    if !more {
+                        return Ok(());
+                    }
+                }
+                res = self.io.read(&mut out), if !eof => match res {
+                    Ok(0) => eof = true,
+                    Ok(sz) => {
+                        self.buf += &String::from_utf8(out[..sz].to_vec())?;
+                        if let Some(buf) = take_cancel(&self.buf, self.encoding) {
+                            self.buf = buf;
+                            s.send(Instruction::cancel()).await?;
+                        }
+                    }
+                    Err(e) => match e.kind() {
+                        std::io::ErrorKind::WouldBlock => {}
+                        _ => return Err(anyhow!("could not read: {:?}", e)),
+                    },
+                },
+            }
+        }
+    }
+
+    async fn next_message(&mut self, input: String) -> Result<Option<Message>> {
+        let (m, buf) = read_command(input, self.encoding);
+        self.buf = buf;
+
+        match m {
+            Some(Err(e)) => {
+                self.write_response(Response::from(&e)).await?;
+                Ok(None)
+            }
+            m => Ok(m.transpose()?),
         }
     }
 
@@ -52,56 +160,64 @@ impl AsyncTcpServer {
                 return Ok(());
             }
 
-            let (i, buf) = read_command(self.buf.clone())?;
+            let m = match self.next_message(self.buf.clone()).await? {
+                Some(m) => m,
+                None => {
+                    let mut out = [0_u8; 4096];
+                    let start = std::time::Instant::now();
 
-            self.buf = buf;
-
-            if let Some(i) = i {
-                s.send(i).await?;
-            } else {
-                let mut out = [0_u8; 4096];
-                let start = std::time::Instant::now();
-
-                'retry: loop {
-                    if std::time::Instant::now() - start > std::time::Duration::new(0, 100) {
-                        continue 'outer;
-                    }
-
-                    match self.io.read(&mut out).await {
-                        Ok(sz) => {
-                            if sz > 0 {
-                                let out = String::from_utf8(out[..sz].to_vec())?;
-                                let (i, buf) = read_command(self.buf.clone() + &out)?;
-                                self.buf = buf;
+                    'retry: loop {
+                        if std::time::Instant::now() - start > std::time::Duration::new(0, 100) {
+                            continue 'outer;
+                        }
 
-                                if let Some(i) = i {
-                                    s.send(i).await?;
-                                    break 'retry;
+                        match self.io.read(&mut out).await {
+                            Ok(sz) => {
+                                if sz > 0 {
+                                    let out = String::from_utf8(out[..sz].to_vec())?;
+                                    if let Some(m) =
+                                        self.next_message(self.buf.clone() + &out).await?
+                                    {
+                                        break 'retry m;
+                                    }
                                 }
                             }
+                            Err(e) => match e.kind() {
+                                std::io::ErrorKind::WouldBlock => {
+                                    continue 'retry;
+                                }
+                                _ => return Err(anyhow!("could not read: {:?}", e)),
+                            },
+                        }
+                    }
+                }
+            };
+
+            match m {
+                Message::Hello(hello) => {
+                    match greet(&hello, std::mem::replace(&mut self.started, true)) {
+                        Ok(response) => self.write_response(response).await?,
+                        Err(e) => {
+                            self.write_response(Response::from(&e)).await?;
+                            return Err(e);
                         }
-                        Err(e) => match e.kind() {
-                            std::io::ErrorKind::WouldBlock => {
-                                continue 'retry;
-                            }
-                            _ => return Err(anyhow!("could not read: {:?}", e)),
-                        },
                     }
                 }
+                Message::Encoding(encoding) => {
+                    self.encoding = encoding;
+                    self.write_response(negotiated(encoding)).await?;
+                }
+                Message::Instruction(i) if i.is_cancel() => {
+                    self.started = true;
+                    self.write_response(Response::ok(Payload::default()))
+                        .await?;
+                }
+                Message::Instruction(i) => {
+                    self.started = true;
+                    s.send(i).await?;
+                    self.stream(&s, &r).await?;
+                }
             }
-
-            let mut buf = r
-                .lock()
-                .await
-                .recv()
-                .await
-                .unwrap()
-                .to_string()
-                .as_bytes()
-                .to_vec();
-            buf.push(b'\n');
-
-            self.io.write_all(&buf).await?;
         }
     }
 }
@@ -109,6 +225,8 @@ impl AsyncTcpServer {
 pub struct TcpServer {
     io: TcpStream,
     buf: String,
+    encoding: Encoding,
+    started: bool,
 }
 
 impl TcpServer {
@@ -119,6 +237,71 @@ impl TcpServer {
         Self {
             io,
             buf: Default::default(),
+            encoding: Default::default(),
+            started: false,
+        }
+    }
+
+    fn write_response(&mut self, response: Response) -> Result<()> {
+        let mut buf = response.to_string().as_bytes().to_vec();
+        buf.push(b'\n');
+
+        Ok(self.io.write_all(&buf)?)
+    }
+
+    fn stream(
+        &mut self,
+        s: &SyncSender<Instruction>,
+        r: &Arc<Mutex<SyncReceiver<Response>>>,
+    ) -> Result<()> {
+        let r = r.lock().unwrap();
+        let mut out = [0_u8; 4096];
+        let mut eof = false;
+
+        loop {
+            match r.recv_timeout(std::time::Duration::from_millis(1)) {
+                Ok(response) => {
+                    let more = response.more;
+                    self.write_response(response)?;
+                    if !more {
+                        return Ok(());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            if eof {
+                continue;
+            }
+
+            match self.io.read(&mut out) {
+                Ok(0) => eof = true,
+                Ok(sz) => {
+                    self.buf += &String::from_utf8(out[..sz].to_vec())?;
+                    if let Some(buf) = take_cancel(&self.buf, self.encoding) {
+                        self.buf = buf;
+                        s.send(Instruction::cancel())?;
+                    }
+                }
+                Err(e) => match e.kind() {
+                    std::io::ErrorKind::WouldBlock => {}
+                    _ => return Err(anyhow!("could not read: {:?}", e)),
+                },
+            }
+        }
+    }
+
+    fn next_message(&mut self, input: String) -> Result<Option<Message>> {
+        let (m, buf) = read_command(input, self.encoding);
+        self.buf = buf;
+
+        match m {
+            Some(Err(e)) => {
+                self.write_response(Response::from(&e))?;
+                Ok(None)
+            }
+            m => Ok(m.transpose()?),
         }
     }
 
@@ -133,47 +316,61 @@ impl TcpServer {
                 return Ok(());
             }
 
-            let (i, buf) = read_command(self.buf.clone())?;
-
-            self.buf = buf;
+            let m = match self.next_message(self.buf.clone())? {
+                Some(m) => m,
+                None => {
+                    let mut out = [0_u8; 4096];
+                    let start = std::time::Instant::now();
 
-            if let Some(i) = i {
-                s.send(i)?;
-            } else {
-                let mut out = [0_u8; 4096];
-                let start = std::time::Instant::now();
-
-                'retry: loop {
-                    if std::time::Instant::now() - start > std::time::Duration::new(0, 100) {
-                        continue 'outer;
-                    }
-
-                    match self.io.read(&mut out) {
-                        Ok(sz) => {
-                            if sz > 0 {
-                                let out = String::from_utf8(out[..sz].to_vec())?;
-                                let (i, buf) = read_command(self.buf.clone() + &out)?;
-                                self.buf = buf;
+                    'retry: loop {
+                        if std::time::Instant::now() - start > std::time::Duration::new(0, 100) {
+                            continue 'outer;
+                        }
 
-                                if let Some(i) = i {
-                                    s.send(i)?;
-                                    break 'retry;
+                        match self.io.read(&mut out) {
+                            Ok(sz) => {
+                                if sz > 0 {
+                                    let out = String::from_utf8(out[..sz].to_vec())?;
+                                    if let Some(m) = self.next_message(self.buf.clone() + &out)? {
+                                        break 'retry m;
+                                    }
                                 }
                             }
+                            Err(e) => match e.kind() {
+                                std::io::ErrorKind::WouldBlock => {
+                                    continue 'retry;
+                                }
+                                _ => return Err(anyhow!("could not read: {:?}", e)),
+                            },
                         }
-                        Err(e) => match e.kind() {
-                            std::io::ErrorKind::WouldBlock => {
-                                continue 'retry;
-                            }
-                            _ => return Err(anyhow!("could not read: {:?}", e)),
-                        },
                     }
                 }
+            };
+
+            match m {
+                Message::Hello(hello) => {
+                    match greet(&hello, std::mem::replace(&mut self.started, true)) {
+                        Ok(response) => self.write_response(response)?,
+                        Err(e) => {
+                            self.write_response(Response::from(&e))?;
+                            return Err(e);
+                        }
+                    }
+                }
+                Message::Encoding(encoding) => {
+                    self.encoding = encoding;
+                    self.write_response(negotiated(encoding))?;
+                }
+                Message::Instruction(i) if i.is_cancel() => {
+                    self.started = true;
+                    self.write_response(Response::ok(Payload::default()))?;
+                }
+                Message::Instruction(i) => {
+                    self.started = true;
+                    s.send(i)?;
+                    self.stream(&s, &r)?;
+                }
             }
-            let mut buf = r.lock().unwrap().recv()?.to_string().as_bytes().to_vec();
-            buf.push(b'\n');
-
-            self.io.write_all(&buf)?;
         }
     }
 }
@@ -181,11 +378,13 @@ impl TcpServer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::Hello;
     use crate::testdata::*;
     use std::io::{Read, Write};
 
     #[test]
     fn test_tcp_sync() -> Result<()> {
+        use std::io::{BufRead, BufReader};
         use std::net::{TcpListener, TcpStream};
 
         let server = TcpListener::bind("localhost:0")?;
@@ -266,7 +465,7 @@ mod tests {
             let addr = server.local_addr()?;
             let (ins_s, ins_r) = std::sync::mpsc::sync_channel(1000);
             let (_, resp_r) = std::sync::mpsc::sync_channel(1000);
-            let (_, close_r) = std::sync::mpsc::sync_channel(1);
+            let (close_s, close_r) = std::sync::mpsc::sync_channel(1);
 
             let resp_r = Arc::new(Mutex::new(resp_r));
 
@@ -279,9 +478,20 @@ mod tests {
             });
 
             let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
             stream.write_all(input.as_bytes())?;
-            assert!(ins_r.recv().is_err(), "{}", annotation);
-            assert!(handle.join().is_ok());
+
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line)?;
+            assert!(
+                !serde_json::from_str::<Response>(&line)?.status,
+                "{}",
+                annotation
+            );
+
+            close_s.send(())?;
+            assert!(handle.join().unwrap().is_ok(), "{}", annotation);
+            assert!(ins_r.try_recv().is_err(), "{}", annotation);
         }
 
         Ok(())
@@ -289,6 +499,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_tcp_async() -> Result<()> {
+        use tokio::io::AsyncBufReadExt;
         use tokio::net::TcpListener as AsyncTcpListener;
 
         let server = AsyncTcpListener::bind("localhost:0").await?;
@@ -375,7 +586,7 @@ mod tests {
             let addr = server.local_addr()?;
             let (ins_s, mut ins_r) = tokio::sync::mpsc::channel(1000);
             let (_, resp_r) = tokio::sync::mpsc::channel(1000);
-            let (_, close_r) = tokio::sync::mpsc::channel(1);
+            let (_close_s, close_r) = tokio::sync::mpsc::channel(1);
 
             let resp_r = Arc::new(tokio::sync::Mutex::new(resp_r));
 
@@ -390,11 +601,420 @@ mod tests {
                     .unwrap();
             });
 
-            let mut stream = AsyncTcpStream::connect(addr).await?;
-            stream.write_all(input.as_bytes()).await?;
+            let (reader, mut writer) = AsyncTcpStream::connect(addr).await?.into_split();
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+            writer.write_all(format!("{}\n", input).as_bytes()).await?;
+
+            let response: Response = serde_json::from_str(&lines.next_line().await?.unwrap())?;
+            assert!(!response.status, "{}", annotation);
             assert!(ins_r.try_recv().is_err(), "{}", annotation);
         }
 
         Ok(())
     }
+
+    #[test]
+    fn test_tcp_sync_json() -> Result<()> {
+        use std::io::{BufRead, BufReader};
+        use std::net::{TcpListener, TcpStream};
+
+        let server = TcpListener::bind("localhost:0")?;
+        let addr = server.local_addr()?;
+        let (ins_s, ins_r) = std::sync::mpsc::sync_channel(1000);
+        let (resp_s, resp_r) = std::sync::mpsc::sync_channel(1000);
+        let (close_s, close_r) = std::sync::mpsc::sync_channel(1);
+
+        let resp_r = Arc::new(Mutex::new(resp_r));
+
+        let handle = std::thread::spawn(move || {
+            let (sock, _) = server.accept().unwrap();
+            let mut server = TcpServer::new(sock);
+            server.run(ins_s.clone(), resp_r.clone(), close_r).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        stream.write_all(format!("{}\n", Encoding::Json.negotiation()).as_bytes())?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let response: Response = serde_json::from_str(&line)?;
+        assert!(response.status);
+        assert_eq!(response.payload.get("encoding").unwrap(), "json");
+
+        for (_, input_result, input_annotation, response, response_result, response_annotation) in
+            &*GREEN_TABLE
+        {
+            stream.write_all(format!("{}\n", serde_json::to_string(input_result)?).as_bytes())?;
+            assert_eq!(ins_r.recv()?, *input_result, "{}", input_annotation);
+
+            resp_s.send(response.clone())?;
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            assert_eq!(line.trim(), *response_result, "{}", response_annotation);
+        }
+
+        stream.write_all(b"{\"command\":\"bogus\"}\n")?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        assert!(
+            !serde_json::from_str::<Response>(&line)?.status,
+            "invalid json instruction"
+        );
+
+        stream.write_all(format!("{}\n", serde_json::to_string(&GREEN_TABLE[0].1)?).as_bytes())?;
+        assert_eq!(
+            ins_r.recv()?,
+            GREEN_TABLE[0].1,
+            "instruction after an invalid one"
+        );
+        resp_s.send(GREEN_TABLE[0].3.clone())?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        assert_eq!(
+            line.trim(),
+            GREEN_TABLE[0].4,
+            "response after an invalid instruction"
+        );
+
+        close_s.send(())?;
+        assert!(handle.join().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tcp_async_json() -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+        use tokio::net::TcpListener as AsyncTcpListener;
+
+        let server = AsyncTcpListener::bind("localhost:0").await?;
+        let addr = server.local_addr()?;
+        let (ins_s, mut ins_r) = tokio::sync::mpsc::channel(1000);
+        let (resp_s, resp_r) = tokio::sync::mpsc::channel(1000);
+        let (close_s, close_r) = tokio::sync::mpsc::channel(1);
+
+        let resp_r = Arc::new(tokio::sync::Mutex::new(resp_r));
+
+        tokio::spawn(async move {
+            let (sock, _) = server.accept().await.unwrap();
+            let mut server = AsyncTcpServer::new(sock);
+            server
+                .run(ins_s.clone(), resp_r.clone(), close_r)
+                .await
+                .unwrap();
+        });
+
+        let (reader, mut writer) = AsyncTcpStream::connect(addr).await?.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(format!("{}\n", Encoding::Json.negotiation()).as_bytes())
+            .await?;
+        let response: Response = serde_json::from_str(&lines.next_line().await?.unwrap())?;
+        assert!(response.status);
+        assert_eq!(response.payload.get("encoding").unwrap(), "json");
+
+        for (_, input_result, input_annotation, response, response_result, response_annotation) in
+            &*GREEN_TABLE
+        {
+            writer
+                .write_all(format!("{}\n", serde_json::to_string(input_result)?).as_bytes())
+                .await?;
+            assert_eq!(
+                ins_r.recv().await.unwrap(),
+                *input_result,
+                "{}",
+                input_annotation
+            );
+
+            resp_s.send(response.clone()).await?;
+            assert_eq!(
+                lines.next_line().await?.unwrap(),
+                *response_result,
+                "{}",
+                response_annotation
+            );
+        }
+
+        close_s.send(()).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcp_sync_hello() -> Result<()> {
+        use crate::protocol::ErrorCode;
+        use std::io::{BufRead, BufReader};
+        use std::net::{TcpListener, TcpStream};
+
+        let table = vec![
+            (
+                vec![Hello::default().to_string()],
+                vec![true],
+                "compatible hello",
+            ),
+            (
+                vec![r#"hello version="99" capabilities="schedule""#.to_string()],
+                vec![false],
+                "incompatible hello",
+            ),
+            (
+                vec![Hello::default().to_string(), Hello::default().to_string()],
+                vec![true, false],
+                "repeated hello",
+            ),
+            (
+                vec![Encoding::Json.negotiation(), Hello::default().to_string()],
+                vec![true, true],
+                "hello after encoding negotiation",
+            ),
+            (
+                vec!["status".to_string(), Hello::default().to_string()],
+                vec![true, false],
+                "hello after an instruction",
+            ),
+        ];
+
+        for (lines, statuses, annotation) in table {
+            let server = TcpListener::bind("localhost:0")?;
+            let addr = server.local_addr()?;
+            let (ins_s, ins_r) = std::sync::mpsc::sync_channel(1000);
+            let (resp_s, resp_r) = std::sync::mpsc::sync_channel(1000);
+            let (close_s, close_r) = std::sync::mpsc::sync_channel(1);
+
+            let resp_r = Arc::new(Mutex::new(resp_r));
+
+            let handle = std::thread::spawn(move || {
+                let (sock, _) = server.accept().unwrap();
+                let mut server = TcpServer::new(sock);
+                server.run(ins_s.clone(), resp_r.clone(), close_r)
+            });
+
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+
+            for (line, status) in lines.iter().zip(statuses.iter()) {
+                stream.write_all(format!("{}\n", line).as_bytes())?;
+                if ins_r
+                    .recv_timeout(std::time::Duration::from_millis(100))
+                    .is_ok()
+                {
+                    resp_s.send(Response::ok(Payload::default()))?;
+                }
+
+                let is_hello = line.starts_with("hello");
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                let response: Response = serde_json::from_str(&line)?;
+                assert_eq!(response.status, *status, "{}", annotation);
+
+                if !is_hello {
+                    continue;
+                }
+
+                if response.status {
+                    assert_eq!(
+                        Hello::from_payload(&response.payload)?,
+                        Hello::default(),
+                        "{}",
+                        annotation
+                    );
+                } else {
+                    assert!(response.error.is_some(), "{}", annotation);
+                    assert!(
+                        matches!(
+                            response.code,
+                            Some(ErrorCode::IncompatibleVersion | ErrorCode::InvalidCommand)
+                        ),
+                        "{}: {:?}",
+                        annotation,
+                        response.code
+                    );
+                }
+            }
+
+            close_s.send(()).ok();
+            assert_eq!(
+                handle.join().unwrap().is_ok(),
+                statuses.iter().all(|x| *x),
+                "{}",
+                annotation
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tcp_async_hello() -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+        use tokio::net::TcpListener as AsyncTcpListener;
+
+        let server = AsyncTcpListener::bind("localhost:0").await?;
+        let addr = server.local_addr()?;
+        let (ins_s, _ins_r) = tokio::sync::mpsc::channel(1000);
+        let (_resp_s, resp_r) = tokio::sync::mpsc::channel(1000);
+        let (_close_s, close_r) = tokio::sync::mpsc::channel(1);
+
+        let resp_r = Arc::new(tokio::sync::Mutex::new(resp_r));
+
+        let handle = tokio::spawn(async move {
+            let (sock, _) = server.accept().await.unwrap();
+            let mut server = AsyncTcpServer::new(sock);
+            server.run(ins_s.clone(), resp_r.clone(), close_r).await
+        });
+
+        let (reader, mut writer) = AsyncTcpStream::connect(addr).await?.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"hello version=\"99\" capabilities=\"schedule\"\n")
+            .await?;
+        let response: Response = serde_json::from_str(&lines.next_line().await?.unwrap())?;
+        assert!(!response.status);
+        assert!(response
+            .error
+            .unwrap()
+            .contains("incompatible protocol version 99"));
+        assert!(handle.await?.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcp_sync_error_response() -> Result<()> {
+        use std::io::{BufRead, BufReader};
+        use std::net::{TcpListener, TcpStream};
+
+        for (input, annotation) in &*RED_TABLE {
+            let server = TcpListener::bind("localhost:0")?;
+            let addr = server.local_addr()?;
+            let (ins_s, _ins_r) = std::sync::mpsc::sync_channel(1000);
+            let (_resp_s, resp_r) = std::sync::mpsc::sync_channel(1000);
+            let (close_s, close_r) = std::sync::mpsc::sync_channel(1);
+
+            let resp_r = Arc::new(Mutex::new(resp_r));
+
+            let handle = std::thread::spawn(move || {
+                let (sock, _) = server.accept().unwrap();
+                let mut server = TcpServer::new(sock);
+                server.run(ins_s.clone(), resp_r.clone(), close_r)
+            });
+
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+            stream.write_all(format!("{}\n", input).as_bytes())?;
+
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line)?;
+            let response: Response = serde_json::from_str(&line)?;
+            assert!(!response.status, "{}", annotation);
+            assert!(
+                matches!(
+                    response.code,
+                    Some(crate::protocol::ErrorCode::Parse)
+                        | Some(crate::protocol::ErrorCode::InvalidArgument)
+                ),
+                "{}",
+                annotation
+            );
+            close_s.send(())?;
+            assert!(handle.join().unwrap().is_ok(), "{}", annotation);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_cancel() -> Result<()> {
+        let cancel = Encoding::Text.encode(&Instruction::cancel())?;
+
+        let table = vec![
+            (
+                format!("{}\n", cancel),
+                Some(String::new()),
+                "single cancel",
+            ),
+            (
+                format!("status\n{}\nstat", cancel),
+                Some("status\nstat".to_string()),
+                "cancel after another line",
+            ),
+            (
+                format!("{}\n{}\n", cancel, cancel),
+                Some(String::new()),
+                "repeated cancel",
+            ),
+            ("status\n".to_string(), None, "no cancel"),
+            (cancel.clone(), None, "incomplete line"),
+        ];
+
+        for (input, result, annotation) in table {
+            assert_eq!(
+                take_cancel(&input, Encoding::Text),
+                result,
+                "{}",
+                annotation
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcp_sync_late_cancel() -> Result<()> {
+        use crate::protocol::{Command, ErrorCode, Payload};
+        use std::io::{BufRead, BufReader};
+        use std::net::{TcpListener, TcpStream};
+
+        let server = TcpListener::bind("localhost:0")?;
+        let addr = server.local_addr()?;
+        let (ins_s, ins_r) = std::sync::mpsc::sync_channel(1000);
+        let (resp_s, resp_r) = std::sync::mpsc::sync_channel(1000);
+        let (close_s, close_r) = std::sync::mpsc::sync_channel(1);
+
+        let resp_r = Arc::new(Mutex::new(resp_r));
+
+        std::thread::spawn(move || {
+            let (sock, _) = server.accept().unwrap();
+            let mut server = TcpServer::new(sock);
+            server.run(ins_s.clone(), resp_r.clone(), close_r)
+        });
+
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        stream.write_all(b"status\n")?;
+        assert_eq!(ins_r.recv()?.command, Command::Status(None));
+        resp_s.send(Response::ok(Payload::default()))?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        assert!(serde_json::from_str::<Response>(&line)?.status);
+
+        let cancel = Encoding::Text.encode(&Instruction::cancel())?;
+        stream.write_all(format!("{}\nstatus\n", cancel).as_bytes())?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let response: Response = serde_json::from_str(&line)?;
+        assert!(response.status, "stray cancel is answered");
+        assert_eq!(response.payload, Payload::default());
+
+        assert_eq!(ins_r.recv()?.command, Command::Status(None));
+        resp_s.send(Response::error(ErrorCode::NotFound, "second"))?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let response: Response = serde_json::from_str(&line)?;
+        assert_eq!(response.error.as_deref(), Some("second"));
+        assert!(ins_r.try_recv().is_err());
+
+        close_s.send(()).ok();
+        Ok(())
+    }
 }